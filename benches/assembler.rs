@@ -0,0 +1,63 @@
+// Assembler throughput in source lines per second.
+// Run with `cargo bench --bench assembler`.
+
+mod common;
+
+use common::{measure, report};
+use iridation::assembler::base_assembler::Assembler;
+
+const RUNS: u32 = 3;
+
+// A 100k line program mixing every kind of instruction, labels and comments
+fn large_program() -> String {
+    let mut source = String::from(".data\nmessage: .asciiz 'Hello'\n.code\nload $2 @l0\n");
+    let mut line = 4;
+    let mut block = 0;
+    while line < 100_000 {
+        source.push_str(&format!(
+            "l{}: load $0 #{}\n\
+             add $0 $1 $1 ; running total\n\
+             mul $1 $0 $3\n\
+             eq $0 $1\n\
+             jneq $2\n\
+             inc $4\n\
+             prts @message\n\
+             dec $4\n",
+            block,
+            block % 60_000
+        ));
+        line += 8;
+        block += 1;
+    }
+    source.push_str("hlt\n");
+    source
+}
+
+// 100k labelled strings, each label declared once and looked up when its offset is set
+fn large_data() -> String {
+    let mut source = String::from(".data\n");
+    for i in 0..100_000 {
+        source.push_str(&format!("s{}: .asciiz 'string number {}'\n", i, i));
+    }
+    source.push_str(".code\nhlt\n");
+    source
+}
+
+fn bench(name: &str, source: &str) {
+    let lines = source.lines().count() as u64;
+    report(
+        name,
+        "lines",
+        measure(RUNS, || {
+            Assembler::new()
+                .assemble(source)
+                .expect("benchmark program");
+            lines
+        }),
+    );
+}
+
+fn main() {
+    bench("100k lines", &large_program());
+    bench("large data", &large_data());
+}
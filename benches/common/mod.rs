@@ -0,0 +1,31 @@
+// Timing helpers shared by the benchmarks. They use harness = false and plain
+// Instant timing, since the crate does not depend on a benchmarking framework.
+
+use std::time::{Duration, Instant};
+
+// Runs f once to warm up, then `runs` more times, returning the total of what f
+// counted and the time taken
+pub fn measure<F: FnMut() -> u64>(runs: u32, mut f: F) -> (u64, Duration) {
+    f();
+    let started = Instant::now();
+    let mut total = 0;
+    for _ in 0..runs {
+        total += f();
+    }
+    (total, started.elapsed())
+}
+
+// Prints one result line and returns the rate per second
+pub fn report(name: &str, unit: &str, (count, elapsed): (u64, Duration)) -> f64 {
+    let per_second = count as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:<16} {:>10} {} in {:>8.2?}  {:>8.2}M {}/s",
+        name,
+        count,
+        unit,
+        elapsed,
+        per_second / 1e6,
+        unit
+    );
+    per_second
+}
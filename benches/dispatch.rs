@@ -0,0 +1,58 @@
+// Compares running the same image with instructions decoded as they execute
+// against running it from the pre-decoded code section.
+// Run with `cargo bench --bench dispatch`.
+//
+// The byte-by-byte dispatch these replaced, which read the opcode and each operand with
+// next_8_bits and next_16_bits, no longer exists to run here. As a baseline, LOOP wrapped
+// in an outer loop of 500 (about 164M instructions) was run through the release binary
+// built before and after the change, median of five runs on the same machine:
+//   byte by byte   1.58s   about 103M instructions/s
+//   predecoded     1.11s   about 147M instructions/s
+
+mod common;
+
+use common::{measure, report};
+use iridation::assembler::base_assembler::Assembler;
+use iridation::vm::Vm;
+
+const RUNS: u32 = 20;
+
+// About 330k instructions: a counting loop with some arithmetic and a branch
+const LOOP: &str = ".data
+.code
+        load $0 #65535
+        load $1 #0
+        load $2 @loop
+        load $5 #3
+loop:   dec $0
+        add $3 $5 $3
+        mul $5 $5 $4
+        neq $0 $1
+        jeq $2
+        hlt
+";
+
+fn run(image: &[u8], predecode: bool) -> u64 {
+    let mut vm = Vm::new();
+    vm.load_image(image.to_vec());
+    if predecode {
+        vm.predecode();
+    }
+    vm.run();
+    vm.instructions_executed()
+}
+
+fn main() {
+    let image = Assembler::new().assemble(LOOP).expect("benchmark program");
+    let decoding = report(
+        "decoding",
+        "instructions",
+        measure(RUNS, || run(&image, false)),
+    );
+    let predecoded = report(
+        "predecoded",
+        "instructions",
+        measure(RUNS, || run(&image, true)),
+    );
+    println!("speedup          {:.2}x", predecoded / decoding);
+}
@@ -0,0 +1,89 @@
+// VM throughput in instructions per second on representative workloads.
+// Run with `cargo bench --bench vm`.
+
+mod common;
+
+use common::{measure, report};
+use iridation::assembler::base_assembler::Assembler;
+use iridation::vm::Vm;
+
+const RUNS: u32 = 10;
+
+// Arithmetic on every iteration of a counting loop
+const ARITHMETIC: &str = ".data
+.code
+        load $0 #65535
+        load $1 #0
+        load $2 @loop
+        load $5 #3
+        load $6 #7
+loop:   add $3 $5 $3
+        mul $5 $6 $4
+        sub $4 $5 $7
+        div $7 $6 $8
+        dec $0
+        neq $0 $1
+        jeq $2
+        hlt
+";
+
+// A comparison and a conditional jump for almost every instruction, with the
+// branch taken on alternate iterations
+const BRANCHES: &str = ".data
+.code
+        load $0 #65535
+        load $1 #0
+        load $2 @loop
+        load $3 @odd
+        load $4 @even
+        load $6 #1
+loop:   dec $0
+        eq $7 $1
+        jeq $3
+        jmp $4
+odd:    inc $7
+        gt $0 $1
+        jeq $2
+        hlt
+even:   sub $7 $6 $7
+        gtq $0 $1
+        jeq $2
+        hlt
+";
+
+// A read-only section close to the 64KiB that 16 bit addresses reach, so loading
+// the image copies a large section before a short program runs
+fn large_data() -> String {
+    let mut source = String::from(".data\n");
+    for i in 0..1_200 {
+        source.push_str(&format!(
+            "s{}: .asciiz 'string number {} in the data section'\n",
+            i, i
+        ));
+    }
+    source.push_str(
+        ".code\nload $0 #1000\nload $1 #0\nload $2 @loop\nloop: dec $0\nneq $0 $1\njeq $2\nhlt\n",
+    );
+    source
+}
+
+fn run(image: &[u8]) -> u64 {
+    let mut vm = Vm::new();
+    vm.load_image(image.to_vec());
+    vm.predecode();
+    vm.run();
+    vm.instructions_executed()
+}
+
+fn bench(name: &str, source: &str) {
+    let image = Assembler::new()
+        .assemble(source)
+        .expect("benchmark program");
+    report(name, "instructions", measure(RUNS, || run(&image)));
+}
+
+fn main() {
+    bench("arithmetic", ARITHMETIC);
+    bench("branches", BRANCHES);
+    bench("large data", &large_data());
+}
@@ -1,4 +1,12 @@
+use crate::linker::{Linker, LinkerError};
+use debug_info::{DebugInfo, LineEntry};
 use instruction_parser::AssemblerInstruction;
+use listing::Listing;
+use object::{ObjectFile, ObjectSection, ObjectSymbol, Relocation, SymbolBinding};
+use optimizer::OptimizationReport;
+use preprocessor::{Preprocessor, SourceLine, SourceLocation};
+use std::fmt;
+use std::path::Path;
 
 use super::*;
 
@@ -17,7 +25,17 @@ pub enum AssemblerSection {
     Unknown,
 }
 
-impl<'a> From<&'a str> for AssemblerSection {
+impl fmt::Display for AssemblerSection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerSection::Data { .. } => write!(f, "data"),
+            AssemblerSection::Code { .. } => write!(f, "code"),
+            AssemblerSection::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl From<&str> for AssemblerSection {
     fn from(name: &str) -> AssemblerSection {
         match name {
             "data" => AssemblerSection::Data {
@@ -31,15 +49,106 @@ impl<'a> From<&'a str> for AssemblerSection {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum AssemblerError {
-    NoSegmentDeclarationFound { instruction: u32 },
-    StringConstantDeclaredWithoutLabel { instruction: u32 },
-    SymbolAlreadyDeclared,
-    UnknownDirectiveFound { directive: String },
+    NoSegmentDeclarationFound {
+        instruction: u32,
+    },
+    StringConstantDeclaredWithoutLabel {
+        instruction: u32,
+    },
+    SymbolAlreadyDeclared {
+        name: String,
+        location: SourceLocation,
+    },
+    UnknownDirectiveFound {
+        directive: String,
+    },
     NonOpcodeInOpcodeField,
     InsufficientSections,
-    ParseError { error: String },
+    ParseError {
+        error: String,
+    },
+    UnparsedInput {
+        location: SourceLocation,
+        content: String,
+    },
+    MacroError {
+        message: String,
+        location: SourceLocation,
+    },
+    IncludeError {
+        message: String,
+        location: SourceLocation,
+    },
+    ConditionalError {
+        message: String,
+        location: SourceLocation,
+    },
+    UnknownLabel {
+        name: String,
+        location: SourceLocation,
+    },
+    GlobalNotDefined {
+        name: String,
+        location: SourceLocation,
+    },
+    LinkError {
+        error: LinkerError,
+    },
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::NoSegmentDeclarationFound { instruction } => write!(
+                f,
+                "Instruction {} appears before any .data or .code section",
+                instruction
+            ),
+            AssemblerError::StringConstantDeclaredWithoutLabel { instruction } => write!(
+                f,
+                "Instruction {} declares a string constant without a label",
+                instruction
+            ),
+            AssemblerError::SymbolAlreadyDeclared { name, location } => {
+                write!(f, "Symbol {} was already declared ({})", name, location)
+            }
+            AssemblerError::UnknownDirectiveFound { directive } => {
+                write!(f, "Unknown directive .{}", directive)
+            }
+            AssemblerError::NonOpcodeInOpcodeField => write!(f, "Non opcode in opcode field"),
+            AssemblerError::InsufficientSections => {
+                write!(f, "Program needs both a .data and a .code section")
+            }
+            AssemblerError::ParseError { error } => write!(f, "Parse error: {}", error),
+            AssemblerError::UnparsedInput { location, content } => {
+                write!(f, "Unable to parse {}: {}", location, content)
+            }
+            AssemblerError::MacroError { message, location } => {
+                write!(f, "Macro error at {}: {}", location, message)
+            }
+            // The file handed to the assembler itself has no including line
+            AssemblerError::IncludeError { message, location } if location.line == 0 => {
+                write!(f, "Include error: {}", message)
+            }
+            AssemblerError::IncludeError { message, location } => {
+                write!(f, "Include error at {}: {}", location, message)
+            }
+            AssemblerError::ConditionalError { message, location } => {
+                write!(f, "Conditional assembly error at {}: {}", location, message)
+            }
+            AssemblerError::UnknownLabel { name, location } => {
+                write!(f, "Label {} is never declared ({})", name, location)
+            }
+            AssemblerError::GlobalNotDefined { name, location } => write!(
+                f,
+                "Symbol {} is declared .global but never defined ({})",
+                name, location
+            ),
+            AssemblerError::LinkError { error } => write!(f, "{}", error),
+        }
+    }
 }
 
 // Assembler
@@ -55,6 +164,9 @@ pub struct Assembler {
 
     ro_offset: u32,
 
+    // Offset of the next instruction from the start of the code section
+    code_offset: u32,
+
     pub sections: Vec<AssemblerSection>,
 
     current_section: Option<AssemblerSection>,
@@ -63,6 +175,30 @@ pub struct Assembler {
 
     // Collect all errors to present to the user in the end.
     errors: Vec<AssemblerError>,
+
+    // Expands macros before the source is parsed
+    preprocessor: Preprocessor,
+
+    // Source line of every parsed instruction, indexed like current_instruction
+    lines: Vec<SourceLine>,
+
+    // Filled in by the second phase when a listing was asked for
+    listing: Option<Listing>,
+
+    // Filled in by the second phase when debug info was asked for
+    debug_info: Option<DebugInfo>,
+
+    // Filled in after parsing when the peephole optimizer is enabled
+    optimization_report: Option<OptimizationReport>,
+
+    // Names exported with .global, and where they were declared
+    globals: Vec<(String, SourceLocation)>,
+
+    // Operands that refer to labels, patched by the linker
+    relocations: Vec<Relocation>,
+
+    // call_host operands, given import indices by the linker
+    host_calls: Vec<Relocation>,
 }
 
 impl Default for Assembler {
@@ -78,22 +214,55 @@ impl Assembler {
             ro: vec![],
             bytecode: vec![],
             ro_offset: 0,
+            code_offset: 0,
             sections: vec![],
             current_section: None,
             current_instruction: 0,
             errors: vec![],
+            preprocessor: Preprocessor::new(),
+            lines: vec![],
+            listing: None,
+            debug_info: None,
+            optimization_report: None,
+            globals: vec![],
+            relocations: vec![],
+            host_calls: vec![],
         }
     }
 
+    // Makes the next assemble also produce a listing of addresses, bytes and source lines
+    pub fn enable_listing(&mut self) {
+        self.listing = Some(Listing::new());
+    }
+
+    pub fn listing(&self) -> Option<&str> {
+        self.listing.as_ref().map(|l| l.as_str())
+    }
+
+    // Makes the next assemble run the peephole optimizer before labels get their offsets
+    pub fn enable_optimizer(&mut self) {
+        self.optimization_report = Some(OptimizationReport::default());
+    }
+
+    pub fn optimization_report(&self) -> Option<&OptimizationReport> {
+        self.optimization_report.as_ref()
+    }
+
+    // Makes the next assemble record the source line of every instruction in the output
+    pub fn enable_debug_info(&mut self) {
+        self.debug_info = Some(DebugInfo::new());
+    }
+
     pub fn extract_label(&mut self, p: &Program) {
         let mut c = 0;
         for i in &p.instructions {
             if i.is_label() {
                 if let Some(name) = i.get_label_name() {
-                    self.symbol_table.symbols.push(Symbol {
+                    self.symbol_table.add_symbols(Symbol {
                         name,
                         symbol_type: SymbolType::Label,
                         offset: Some(c),
+                        section: None,
                     })
                 }
             }
@@ -114,12 +283,22 @@ impl Assembler {
         };
 
         if self.symbol_table.has_symbol(&name) {
-            self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+            self.errors.push(AssemblerError::SymbolAlreadyDeclared {
+                name,
+                location: self.current_location(),
+            });
             return;
         }
 
-        self.symbol_table
-            .add_symbols(Symbol::new(name, SymbolType::Label))
+        // Labels on instructions point at the instruction itself, labels on
+        // directives get their offset when the directive is processed
+        let mut symbol = if i.is_opcode() {
+            Symbol::new_with_offset(name, SymbolType::Label, Some(self.code_offset))
+        } else {
+            Symbol::new(name, SymbolType::Label)
+        };
+        symbol.section = self.current_section.clone();
+        self.symbol_table.add_symbols(symbol)
     }
 
     pub fn process_first_phase(&mut self, p: &Program) {
@@ -138,9 +317,25 @@ impl Assembler {
             if i.is_directive() {
                 self.process_directive(i);
             }
+            if i.is_opcode() {
+                self.code_offset += 4;
+            }
 
             self.current_instruction += 1;
         }
+
+        for (name, location) in std::mem::take(&mut self.globals) {
+            let is_label = self
+                .symbol_table
+                .symbol(&name)
+                .is_some_and(|s| s.symbol_type == SymbolType::Label);
+            if is_label {
+                self.symbol_table.set_symbol_type(&name, SymbolType::Global);
+            } else {
+                self.errors
+                    .push(AssemblerError::GlobalNotDefined { name, location });
+            }
+        }
         self.phase = AssemblerPhase::Second;
     }
 
@@ -148,18 +343,133 @@ impl Assembler {
         self.current_instruction = 0;
         // Second pass
         let mut program = vec![];
+        // Where the code lands when this program is linked on its own, for the listing
+        let code_start = PIE_HEADER_LENGTH + self.ro.len() + pie_ro_padding(self.ro.len());
         for i in &p.instructions {
             if i.is_opcode() {
-                let mut bytes = i.to_bytes(&self.symbol_table);
+                if !self.record_relocations(i, program.len() as u32) {
+                    self.current_instruction += 1;
+                    continue;
+                }
+                if let Some(name) = i.host_function() {
+                    self.host_calls.push(Relocation {
+                        offset: program.len() as u32 + 1,
+                        symbol: name.to_string(),
+                    });
+                }
+                self.record_line(program.len() as u32);
+                let mut bytes = match i.to_bytes(&self.symbol_table, &self.current_location()) {
+                    Ok(bytes) => bytes,
+                    Err(error) => {
+                        self.errors.push(error);
+                        self.current_instruction += 1;
+                        continue;
+                    }
+                };
+                if self.listing.is_some() {
+                    let address = format!("{:04x}", code_start + program.len());
+                    let listed = self.relocated_for_listing(i, &bytes, code_start as u32);
+                    self.list_instruction(Some(address), &listed);
+                }
                 program.append(&mut bytes);
             }
             if i.is_directive() {
                 self.process_directive(i);
+                if self.listing.is_some() {
+                    self.list_directive(i);
+                }
             }
             self.current_instruction += 1
         }
+        if let Some(listing) = self.listing.as_mut() {
+            listing.add_symbols(&self.symbol_table);
+        }
         program
     }
+
+    // Label operands are left for the linker to fill in. Returns false if one names an unknown label.
+    fn record_relocations(&mut self, i: &AssemblerInstruction, instruction_offset: u32) -> bool {
+        let mut known = true;
+        for (offset, name) in i.label_operands() {
+            if !self.symbol_table.has_symbol(&name) {
+                self.errors.push(AssemblerError::UnknownLabel {
+                    name,
+                    location: self.current_location(),
+                });
+                known = false;
+                continue;
+            }
+            self.relocations.push(Relocation {
+                offset: instruction_offset + offset,
+                symbol: name,
+            });
+        }
+        known
+    }
+
+    fn record_line(&mut self, pc: u32) {
+        let (location, column) = match self.lines.get(self.current_instruction as usize) {
+            Some(line) => {
+                let indent = line.text.len() - line.text.trim_start().len();
+                (line.location.clone(), indent as u32 + 1)
+            }
+            None => (SourceLocation::default(), 1),
+        };
+        if let Some(debug_info) = self.debug_info.as_mut() {
+            debug_info.lines.push(LineEntry {
+                pc,
+                file: location.file,
+                line: location.line,
+                column,
+            });
+        }
+    }
+
+    // The listing shows code labels at the address they get when this program is linked on its own
+    fn relocated_for_listing(
+        &self,
+        i: &AssemblerInstruction,
+        bytes: &[u8],
+        code_start: u32,
+    ) -> Vec<u8> {
+        let mut bytes = bytes.to_vec();
+        for (offset, name) in i.label_operands() {
+            let symbol = self.symbol_table.symbol(&name);
+            if let Some(Symbol {
+                section: Some(AssemblerSection::Code { .. }),
+                offset: Some(value),
+                ..
+            }) = symbol
+            {
+                let address = code_start + value;
+                bytes[offset as usize] = (address >> 8) as u8;
+                bytes[offset as usize + 1] = address as u8;
+            }
+        }
+        bytes
+    }
+
+    fn list_instruction(&mut self, address: Option<String>, bytes: &[u8]) {
+        let line = &self.lines[self.current_instruction as usize];
+        if let Some(listing) = self.listing.as_mut() {
+            listing.add_line(address, bytes, line);
+        }
+    }
+
+    // String constants are listed with the bytes they placed in the read-only section
+    fn list_directive(&mut self, i: &AssemblerInstruction) {
+        let offset = i
+            .get_label_name()
+            .and_then(|name| self.symbol_table.symbol_value(&name));
+        match (i.get_string_constant(), offset) {
+            (Some(string), Some(offset)) => {
+                let mut bytes = string.into_bytes();
+                bytes.push(0);
+                self.list_instruction(Some(format!("ro:{:04x}", offset)), &bytes);
+            }
+            _ => self.list_instruction(None, &[]),
+        }
+    }
     fn process_directive(&mut self, i: &AssemblerInstruction) {
         let directive_name = match i.get_directive_name() {
             Some(name) => name,
@@ -173,6 +483,9 @@ impl Assembler {
                 "asciiz" => {
                     self.handle_asciiz(i);
                 }
+                "global" | "extern" => {
+                    self.handle_symbol_directive(&directive_name, i);
+                }
                 _ => {
                     self.errors.push(AssemblerError::UnknownDirectiveFound {
                         directive: directive_name.clone(),
@@ -226,43 +539,170 @@ impl Assembler {
         }
     }
 
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in PIE_HEADER_PREFIX {
-            header.push(byte);
+    fn handle_symbol_directive(&mut self, directive_name: &str, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
         }
+        let name = match &i.operand1 {
+            Some(Token::LabelUsage { name }) => name.clone(),
+            _ => return,
+        };
 
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0);
+        if directive_name == "global" {
+            self.globals.push((name, self.current_location()));
+            return;
+        }
+        if self.symbol_table.has_symbol(&name) {
+            self.errors.push(AssemblerError::SymbolAlreadyDeclared {
+                name,
+                location: self.current_location(),
+            });
+            return;
         }
-        header
+        self.symbol_table
+            .add_symbols(Symbol::new_with_offset(name, SymbolType::Extern, Some(0)));
     }
 
-    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
-        match program(CompleteStr(raw)) {
-            Ok((_, p)) => {
-                let mut result = self.write_pie_header();
+    // Symbols as the linker sees them. Labels that never got an offset cannot be referenced.
+    fn object_symbols(&self) -> Vec<ObjectSymbol> {
+        let mut symbols = vec![];
+        for symbol in self.symbol_table.symbols() {
+            let binding = match symbol.symbol_type {
+                SymbolType::Label => SymbolBinding::Local,
+                SymbolType::Global => SymbolBinding::Global,
+                SymbolType::Extern => SymbolBinding::Extern,
+            };
+            let section = match (&symbol.section, binding) {
+                (_, SymbolBinding::Extern) => ObjectSection::Undefined,
+                (Some(AssemblerSection::Code { .. }), _) => ObjectSection::Code,
+                (Some(AssemblerSection::Data { .. }), _) => ObjectSection::Ro,
+                _ => continue,
+            };
+            let offset = match (symbol.offset, section) {
+                (Some(offset), _) => offset,
+                (None, ObjectSection::Undefined) => 0,
+                (None, _) => continue,
+            };
+            symbols.push(ObjectSymbol {
+                name: symbol.name.clone(),
+                binding,
+                section,
+                offset,
+            });
+        }
+        symbols
+    }
 
-                self.process_first_phase(&p);
+    // Defines a constant for .if/.ifdef/.ifndef, as if it had been declared with .equ
+    pub fn define(&mut self, name: &str, value: i32) {
+        self.preprocessor.define(name, value);
+    }
 
-                if !self.errors.is_empty() {
-                    return Err(self.errors.clone());
+    // Parses each preprocessed line on its own so every instruction keeps its source location
+    fn parse_lines(&mut self, lines: &[SourceLine]) -> Result<Program, Vec<AssemblerError>> {
+        let mut instructions = vec![];
+        for line in lines {
+            match program(CompleteStr(&line.text)) {
+                Ok((rest, mut p)) => {
+                    // Anything the grammar could not consume is an error, not something to drop
+                    if !rest.is_empty() {
+                        self.errors.push(AssemblerError::UnparsedInput {
+                            location: line.location.clone(),
+                            content: line.text.trim().to_string(),
+                        });
+                        continue;
+                    }
+                    for _ in &p.instructions {
+                        self.lines.push(line.clone());
+                    }
+                    instructions.append(&mut p.instructions);
                 }
-                if self.sections.len() != 2 {
-                    print!("found {} sections, needed 2", self.sections.len());
-                    self.errors.push(AssemblerError::InsufficientSections);
-                    return Err(self.errors.clone());
+                Err(e) => {
+                    println!("There was an error parsing the code: {:?}", e);
+                    self.errors.push(AssemblerError::ParseError {
+                        error: e.to_string(),
+                    });
                 }
-
-                result.append(&mut self.process_second_phase(&p));
-                Ok(result)
-            }
-            Err(e) => {
-                println!("There was an error parsing the code: {:?}", e);
-                Err(vec![AssemblerError::ParseError {
-                    error: e.to_string(),
-                }])
             }
         }
+
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+        Ok(Program { instructions })
+    }
+
+    fn current_location(&self) -> SourceLocation {
+        self.lines
+            .get(self.current_instruction as usize)
+            .map(|line| line.location.clone())
+            .unwrap_or_default()
+    }
+
+    /// Assembles a complete program into a PIE image, linking it on its own
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let object = self.assemble_object(raw)?;
+        link_alone(object)
+    }
+
+    // Like assemble, but .include paths are resolved relative to the given file
+    pub fn assemble_file(&mut self, path: &Path) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let object = self.assemble_file_object(path)?;
+        link_alone(object)
+    }
+
+    // Assembles a module that may use .extern symbols, to be linked with others later
+    pub fn assemble_object(&mut self, raw: &str) -> Result<ObjectFile, Vec<AssemblerError>> {
+        let lines = self.preprocessor.process(raw)?;
+        self.assemble_lines(&lines)
     }
+
+    pub fn assemble_file_object(&mut self, path: &Path) -> Result<ObjectFile, Vec<AssemblerError>> {
+        let lines = self.preprocessor.process_file(path)?;
+        self.assemble_lines(&lines)
+    }
+
+    fn assemble_lines(&mut self, lines: &[SourceLine]) -> Result<ObjectFile, Vec<AssemblerError>> {
+        let mut p = self.parse_lines(lines)?;
+        if self.optimization_report.is_some() {
+            let report = optimizer::optimize(&mut p.instructions, &mut self.lines);
+            self.optimization_report = Some(report);
+        }
+
+        self.process_first_phase(&p);
+
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+        if self.sections.len() != 2 {
+            print!("found {} sections, needed 2", self.sections.len());
+            self.errors.push(AssemblerError::InsufficientSections);
+            return Err(self.errors.clone());
+        }
+
+        self.bytecode = self.process_second_phase(&p);
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+
+        Ok(ObjectFile {
+            code: self.bytecode.clone(),
+            ro: self.ro.clone(),
+            symbols: self.object_symbols(),
+            relocations: self.relocations.clone(),
+            host_calls: self.host_calls.clone(),
+            debug: self.debug_info.clone(),
+        })
+    }
+}
+
+fn link_alone(object: ObjectFile) -> Result<Vec<u8>, Vec<AssemblerError>> {
+    let mut linker = Linker::new();
+    linker.add_object("<program>", object);
+    linker.link().map_err(|errors| {
+        errors
+            .into_iter()
+            .map(|error| AssemblerError::LinkError { error })
+            .collect()
+    })
 }
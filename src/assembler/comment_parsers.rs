@@ -0,0 +1,81 @@
+use nom::multispace;
+use nom::types::CompleteStr;
+
+// Looks for a line comment such as ; comment or #! comment
+named!(
+    pub line_comment<CompleteStr,CompleteStr>,
+    recognize!(
+        do_parse!(
+            alt!(tag!(";") | tag!("#!")) >>
+            opt!(is_not!("\n")) >>
+            ()
+        )
+    )
+);
+
+// Looks for a block comment such as /* comment */, which may span several lines
+named!(
+    pub block_comment<CompleteStr,CompleteStr>,
+    recognize!(
+        do_parse!(
+            tag!("/*") >>
+            take_until!("*/") >>
+            tag!("*/") >>
+            ()
+        )
+    )
+);
+
+// Skips any mix of whitespace, blank lines and comments between instructions
+named!(
+    pub blank<CompleteStr,()>,
+    do_parse!(
+        many0!(
+            alt!(
+                multispace |
+                line_comment |
+                block_comment
+            )
+        ) >>
+        ()
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_comment() {
+        let result = line_comment(CompleteStr("; load $0 #1\nhlt"));
+        assert_eq!(
+            result,
+            Ok((CompleteStr("\nhlt"), CompleteStr("; load $0 #1")))
+        );
+        let result = line_comment(CompleteStr("#! comment"));
+        assert_eq!(result, Ok((CompleteStr(""), CompleteStr("#! comment"))));
+        let result = line_comment(CompleteStr(";"));
+        assert!(result.is_ok());
+        let result = line_comment(CompleteStr("#10"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_block_comment() {
+        let result = block_comment(CompleteStr("/* first\nsecond */hlt"));
+        assert_eq!(
+            result,
+            Ok((CompleteStr("hlt"), CompleteStr("/* first\nsecond */")))
+        );
+        let result = block_comment(CompleteStr("/* never closed"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_blank() {
+        let result = blank(CompleteStr("  \n; one\n\n/* two */ #! three\n\thlt"));
+        assert_eq!(result, Ok((CompleteStr("hlt"), ())));
+        let result = blank(CompleteStr("hlt"));
+        assert_eq!(result, Ok((CompleteStr("hlt"), ())));
+    }
+}
@@ -0,0 +1,243 @@
+use super::preprocessor::SourceLocation;
+use std::collections::HashMap;
+
+// One open .if/.ifdef/.ifndef block
+#[derive(Debug, PartialEq, Clone)]
+pub struct Conditional {
+    // Whether lines in the current branch are assembled
+    pub active: bool,
+    // Whether any branch of this block has been taken yet
+    pub taken: bool,
+    // Whether the enclosing block is assembling at all
+    pub parent_active: bool,
+    pub seen_else: bool,
+    pub location: SourceLocation,
+}
+
+impl Conditional {
+    pub fn new(condition: bool, parent_active: bool, location: SourceLocation) -> Conditional {
+        Conditional {
+            active: parent_active && condition,
+            taken: condition,
+            parent_active,
+            seen_else: false,
+            location,
+        }
+    }
+
+    // Switches to the .else branch, which runs only if the .if branch did not
+    pub fn flip(&mut self) {
+        self.active = self.parent_active && !self.taken;
+        self.taken = true;
+        self.seen_else = true;
+    }
+}
+
+// Parses a command line definition such as DEBUG or LEVEL=2
+pub fn parse_define(define: &str) -> Result<(String, i32), String> {
+    let (name, value) = match define.split_once('=') {
+        Some((name, value)) => (name.trim(), value.trim()),
+        None => (define.trim(), "1"),
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(format!("`{}` is not a valid name to define", name));
+    }
+    match value.parse::<i32>() {
+        Ok(value) => Ok((name.to_string(), value)),
+        Err(_) => Err(format!("`{}` is not a valid integer for {}", value, name)),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum ExprToken {
+    Number(i32),
+    Name(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 16] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "+", "-", "*", "/", "%", "(", ")",
+];
+
+fn tokenize(text: &str) -> Result<Vec<ExprToken>, String> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() || c == '#' {
+            let digits: String = rest
+                .chars()
+                .skip(if c == '#' { 1 } else { 0 })
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            let value = digits
+                .parse::<i32>()
+                .map_err(|_| format!("invalid number in `{}`", text))?;
+            rest = &rest[digits.len() + if c == '#' { 1 } else { 0 }..];
+            tokens.push(ExprToken::Number(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let name: String = rest
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .collect();
+            rest = &rest[name.len()..];
+            tokens.push(ExprToken::Name(name));
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => {
+                    rest = &rest[op.len()..];
+                    tokens.push(ExprToken::Op(op));
+                }
+                None => return Err(format!("unexpected `{}` in `{}`", c, text)),
+            }
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// Evaluates an .if expression such as `LEVEL >= 2 && defined(DEBUG)` against the known constants
+pub fn evaluate(text: &str, constants: &HashMap<String, i32>) -> Result<i32, String> {
+    let tokens = tokenize(text)?;
+    if tokens.is_empty() {
+        return Err("expected an expression".to_string());
+    }
+    let mut parser = ExprParser {
+        tokens,
+        position: 0,
+        constants,
+    };
+    let value = parser.binary(0)?;
+    match parser.tokens.get(parser.position) {
+        Some(token) => Err(format!("unexpected {:?} in `{}`", token, text)),
+        None => Ok(value),
+    }
+}
+
+struct ExprParser<'a> {
+    tokens: Vec<ExprToken>,
+    position: usize,
+    constants: &'a HashMap<String, i32>,
+}
+
+// Binary operators from loosest to tightest binding
+const PRECEDENCE: [&[&str]; 5] = [
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<", ">", "<=", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl<'a> ExprParser<'a> {
+    fn next(&mut self) -> Option<ExprToken> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(ExprToken::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(ExprToken::Op(found)) if found == op => Ok(()),
+            _ => Err(format!("expected `{}`", op)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i32, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| PRECEDENCE[level].contains(op)) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = match op {
+                "||" => (left != 0 || right != 0) as i32,
+                "&&" => (left != 0 && right != 0) as i32,
+                "==" => (left == right) as i32,
+                "!=" => (left != right) as i32,
+                "<" => (left < right) as i32,
+                ">" => (left > right) as i32,
+                "<=" => (left <= right) as i32,
+                ">=" => (left >= right) as i32,
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                "/" | "%" if right == 0 => return Err("division by zero".to_string()),
+                "/" => left.wrapping_div(right),
+                _ => left.wrapping_rem(right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i32, String> {
+        match self.next() {
+            Some(ExprToken::Number(value)) => Ok(value),
+            Some(ExprToken::Op("!")) => Ok((self.unary()? == 0) as i32),
+            Some(ExprToken::Op("-")) => Ok(self.unary()?.wrapping_neg()),
+            Some(ExprToken::Op("(")) => {
+                let value = self.binary(0)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(ExprToken::Name(name)) if name == "defined" => {
+                self.expect("(")?;
+                let defined = match self.next() {
+                    Some(ExprToken::Name(name)) => self.constants.contains_key(&name),
+                    _ => return Err("defined() expects a name".to_string()),
+                };
+                self.expect(")")?;
+                Ok(defined as i32)
+            }
+            Some(ExprToken::Name(name)) => match self.constants.get(&name) {
+                Some(value) => Ok(*value),
+                None => Err(format!("`{}` is not defined", name)),
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_define() {
+        assert_eq!(parse_define("DEBUG"), Ok(("DEBUG".to_string(), 1)));
+        assert_eq!(parse_define("LEVEL=3"), Ok(("LEVEL".to_string(), 3)));
+        assert!(parse_define("LEVEL=high").is_err());
+        assert!(parse_define("=2").is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut constants = HashMap::new();
+        constants.insert("LEVEL".to_string(), 2);
+        constants.insert("DEBUG".to_string(), 0);
+
+        assert_eq!(evaluate("LEVEL", &constants), Ok(2));
+        assert_eq!(evaluate("LEVEL >= 2 && !DEBUG", &constants), Ok(1));
+        assert_eq!(evaluate("1 + 2 * 3 == 7", &constants), Ok(1));
+        assert_eq!(evaluate("(1 + 2) * #3", &constants), Ok(9));
+        assert_eq!(
+            evaluate("defined(DEBUG) || defined(RELEASE)", &constants),
+            Ok(1)
+        );
+        assert_eq!(evaluate("defined(RELEASE)", &constants), Ok(0));
+        assert!(evaluate("RELEASE", &constants).is_err());
+        assert!(evaluate("1 +", &constants).is_err());
+        assert!(evaluate("(1", &constants).is_err());
+        assert!(evaluate("1 / 0", &constants).is_err());
+        assert!(evaluate("", &constants).is_err());
+    }
+}
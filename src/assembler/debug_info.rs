@@ -0,0 +1,253 @@
+use crate::codec::{write_bytes, write_u32, CodecError, Reader};
+use std::fmt;
+
+// Where the instruction starting at pc came from
+#[derive(Debug, PartialEq, Clone)]
+pub struct LineEntry {
+    pub pc: u32,
+    // None when the source did not come from a file
+    pub file: Option<String>,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for LineEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+            None => write!(f, "line {}, column {}", self.line, self.column),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DebugLabel {
+    pub name: String,
+    pub pc: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DebugInfoError {
+    Truncated,
+}
+
+impl fmt::Display for DebugInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugInfoError::Truncated => write!(f, "debug section is truncated"),
+        }
+    }
+}
+
+// Undecodable strings were always reported as truncation
+impl From<CodecError> for DebugInfoError {
+    fn from(_: CodecError) -> DebugInfoError {
+        DebugInfoError::Truncated
+    }
+}
+
+// Maps bytecode offsets back to the source. In an object file pcs are relative to its code
+// section; the linker rebases them and adds the code labels when it writes the image.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DebugInfo {
+    // Sorted by pc, one entry per instruction
+    pub lines: Vec<LineEntry>,
+    // Sorted by pc
+    pub labels: Vec<DebugLabel>,
+}
+
+impl DebugInfo {
+    pub fn new() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    // The entry for the instruction containing pc
+    pub fn location(&self, pc: u32) -> Option<&LineEntry> {
+        match self.lines.binary_search_by_key(&pc, |entry| entry.pc) {
+            Ok(index) => Some(&self.lines[index]),
+            Err(0) => None,
+            Err(index) => Some(&self.lines[index - 1]),
+        }
+    }
+
+    // The closest label at or before pc, with the distance from it
+    pub fn label(&self, pc: u32) -> Option<(&str, u32)> {
+        self.labels
+            .iter()
+            .rev()
+            .find(|label| label.pc <= pc)
+            .map(|label| (label.name.as_str(), pc - label.pc))
+    }
+
+    pub fn label_pc(&self, name: &str) -> Option<u32> {
+        self.labels.iter().find(|l| l.name == name).map(|l| l.pc)
+    }
+
+    // First instruction generated by a source line. The file only has to match the end
+    // of the recorded path, so `main.iasm` finds `examples/main.iasm`.
+    pub fn line_pc(&self, file: Option<&str>, line: u32) -> Option<u32> {
+        self.lines
+            .iter()
+            .find(|entry| {
+                entry.line == line
+                    && match (file, &entry.file) {
+                        (None, _) => true,
+                        (Some(file), Some(entry_file)) => entry_file.ends_with(file),
+                        (Some(_), None) => false,
+                    }
+            })
+            .map(|entry| entry.pc)
+    }
+
+    // Human readable description of pc, such as `main.iasm:4:1 (start+8)`
+    pub fn describe(&self, pc: u32) -> String {
+        let mut text = match self.location(pc) {
+            Some(entry) => entry.to_string(),
+            None => format!("pc {}", pc),
+        };
+        if let Some((name, offset)) = self.label(pc) {
+            match offset {
+                0 => text.push_str(&format!(" ({})", name)),
+                _ => text.push_str(&format!(" ({}+{})", name, offset)),
+            }
+        }
+        text
+    }
+
+    // Moves every pc forward, used when placing an object's code in an image
+    pub fn rebase(&mut self, base: u32) {
+        self.lines.iter_mut().for_each(|entry| entry.pc += base);
+        self.labels.iter_mut().for_each(|label| label.pc += base);
+    }
+
+    pub fn append(&mut self, mut other: DebugInfo) {
+        self.lines.append(&mut other.lines);
+        self.labels.append(&mut other.labels);
+        self.lines.sort_by_key(|entry| entry.pc);
+        self.labels.sort_by_key(|label| label.pc);
+    }
+
+    // File names are stored once in a table, line entries refer to them by index
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut files: Vec<&str> = vec![];
+        for entry in &self.lines {
+            if let Some(file) = &entry.file {
+                if !files.contains(&file.as_str()) {
+                    files.push(file);
+                }
+            }
+        }
+
+        let mut out = vec![];
+        write_u32(&mut out, files.len() as u32);
+        for file in &files {
+            write_bytes(&mut out, file.as_bytes());
+        }
+        write_u32(&mut out, self.lines.len() as u32);
+        for entry in &self.lines {
+            let file = match &entry.file {
+                Some(file) => files.iter().position(|f| f == file).unwrap() as u32,
+                None => u32::MAX,
+            };
+            write_u32(&mut out, entry.pc);
+            write_u32(&mut out, file);
+            write_u32(&mut out, entry.line);
+            write_u32(&mut out, entry.column);
+        }
+        write_u32(&mut out, self.labels.len() as u32);
+        for label in &self.labels {
+            write_u32(&mut out, label.pc);
+            write_bytes(&mut out, label.name.as_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DebugInfo, DebugInfoError> {
+        let mut reader = Reader::new(bytes);
+        let mut files = vec![];
+        for _ in 0..reader.u32()? {
+            files.push(reader.string()?);
+        }
+
+        let mut info = DebugInfo::new();
+        for _ in 0..reader.u32()? {
+            let pc = reader.u32()?;
+            let file = match reader.u32()? {
+                u32::MAX => None,
+                index => Some(
+                    files
+                        .get(index as usize)
+                        .cloned()
+                        .ok_or(DebugInfoError::Truncated)?,
+                ),
+            };
+            let line = reader.u32()?;
+            let column = reader.u32()?;
+            info.lines.push(LineEntry {
+                pc,
+                file,
+                line,
+                column,
+            });
+        }
+        for _ in 0..reader.u32()? {
+            let pc = reader.u32()?;
+            let name = reader.string()?;
+            info.labels.push(DebugLabel { name, pc });
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> DebugInfo {
+        DebugInfo {
+            lines: vec![
+                LineEntry {
+                    pc: 64,
+                    file: Some("src/main.iasm".to_string()),
+                    line: 3,
+                    column: 1,
+                },
+                LineEntry {
+                    pc: 68,
+                    file: None,
+                    line: 4,
+                    column: 5,
+                },
+            ],
+            labels: vec![DebugLabel {
+                name: "start".to_string(),
+                pc: 64,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_debug_info_lookups() {
+        let info = sample();
+        assert_eq!(info.location(66).map(|e| e.line), Some(3));
+        assert_eq!(info.location(68).map(|e| e.line), Some(4));
+        assert!(info.location(10).is_none());
+        assert_eq!(info.label(70), Some(("start", 6)));
+        assert_eq!(info.describe(64), "src/main.iasm:3:1 (start)");
+        assert_eq!(info.describe(69), "line 4, column 5 (start+5)");
+        assert_eq!(info.line_pc(Some("main.iasm"), 3), Some(64));
+        assert_eq!(info.line_pc(None, 4), Some(68));
+        assert_eq!(info.label_pc("start"), Some(64));
+    }
+
+    #[test]
+    fn test_debug_info_round_trip() {
+        let info = sample();
+        let bytes = info.to_bytes();
+        assert_eq!(DebugInfo::from_bytes(&bytes), Ok(info));
+        assert_eq!(
+            DebugInfo::from_bytes(&bytes[..bytes.len() - 2]),
+            Err(DebugInfoError::Truncated)
+        );
+    }
+}
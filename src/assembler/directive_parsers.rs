@@ -1,9 +1,9 @@
 use super::instruction_parser::AssemblerInstruction;
-use super::label_parsers::label_declaration;
+use super::label_parsers::{label_declaration, symbol_name};
 use super::operand_parser::operand;
 use super::Token;
-use nom::alpha1;
 use nom::types::CompleteStr;
+use nom::{alpha1, multispace, space1};
 
 named!(directive_declaration<CompleteStr,Token>,
     do_parse!(
@@ -37,9 +37,33 @@ named!(directive_combined<CompleteStr,AssemblerInstruction>,
     )
 );
 
+// Looks for a directive naming a symbol such as .global main or .extern print.
+// The symbol has to be on the same line as the directive.
+named!(symbol_directive<CompleteStr,AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".") >>
+        name: alt!(tag!("global") | tag!("extern")) >>
+        space1 >>
+        symbol: symbol_name >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction{
+                opcode: None,
+                directive: Some(Token::Directive { name: name.to_string() }),
+                label: None,
+                operand1: Some(Token::LabelUsage { name: symbol.to_string() }),
+                operand2: None,
+                operand3: None,
+            }
+        )
+    )
+);
+
 named!(pub directive<CompleteStr,AssemblerInstruction>,
     do_parse!(
         ins: alt!(
+            symbol_directive |
             directive_combined
         ) >>
         (
@@ -76,4 +100,22 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_symbol_directive() {
+        let result = directive(CompleteStr(".global main\n"));
+        let (rest, directive) = result.unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(directive.get_directive_name(), Some("global".to_string()));
+        assert_eq!(
+            directive.operand1,
+            Some(Token::LabelUsage {
+                name: "main".to_string()
+            })
+        );
+
+        // Without a name on the same line it is just an unknown section header
+        let result = symbol_directive(CompleteStr(".extern\nmain"));
+        assert!(result.is_err());
+    }
 }
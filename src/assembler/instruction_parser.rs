@@ -1,10 +1,13 @@
+use super::base_assembler::AssemblerError;
 use super::directive_parsers::*;
 use super::label_parsers::*;
 use super::opcode_parser::*;
 use super::operand_parser::*;
+use super::preprocessor::SourceLocation;
 use super::register_parser::*;
 use super::SymbolTable;
 use super::Token;
+use crate::instruction::{Opcode, HLT_STATUS_FROM_REGISTER};
 use nom::multispace;
 use nom::types::CompleteStr;
 
@@ -19,7 +22,12 @@ pub struct AssemblerInstruction {
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbol_table: &SymbolTable) -> Vec<u8> {
+    // Fails with UnknownLabel, placed at location, when a label operand has no known offset
+    pub fn to_bytes(
+        &self,
+        symbol_table: &SymbolTable,
+        location: &SourceLocation,
+    ) -> Result<Vec<u8>, AssemblerError> {
         let mut result = vec![];
         match &self.opcode {
             Some(Token::Op { code }) => {
@@ -32,13 +40,28 @@ impl AssemblerInstruction {
             }
         }
 
-        for i in [&self.operand1, &self.operand2, &self.operand3] {
-            // println!("Operand: {:?}",i);
-            if let Some(t) = i {
-                AssemblerInstruction::extract_operand(t, &mut result)
+        if self.host_function().is_some() {
+            // The import index is filled in by the linker
+            result.extend_from_slice(&[0, 0]);
+        } else {
+            for t in [&self.operand1, &self.operand2, &self.operand3]
+                .into_iter()
+                .flatten()
+            {
+                AssemblerInstruction::extract_operand(t, &mut result, symbol_table).map_err(
+                    |name| AssemblerError::UnknownLabel {
+                        name,
+                        location: location.clone(),
+                    },
+                )?;
             }
         }
 
+        // `hlt $r` marks its third byte so the VM can tell it from a plain `hlt`
+        if self.opcode == Some(Token::Op { code: Opcode::HLT }) && self.operand1.is_some() {
+            result.push(HLT_STATUS_FROM_REGISTER);
+        }
+
         // Incase the result is not an array witha  length of 4
         while result.len() < 4 {
             result.push(0);
@@ -49,10 +72,15 @@ impl AssemblerInstruction {
         // result.iter().for_each(|x| println!("{}", x));
 
         // ToDo Doesnt work with CAPITAL OPCODE for some reason
-        result
+        Ok(result)
     }
 
-    fn extract_operand(t: &Token, result: &mut Vec<u8>) {
+    // Appends the operand's bytes, failing with the name of a label that has no known offset
+    fn extract_operand(
+        t: &Token,
+        result: &mut Vec<u8>,
+        symbol_table: &SymbolTable,
+    ) -> Result<(), String> {
         match t {
             Token::Register { reg } => result.push(*reg),
             Token::IntergerOperand { val } => {
@@ -62,11 +90,56 @@ impl AssemblerInstruction {
                 result.push(byte2);
                 result.push(byte1);
             }
+            Token::LabelUsage { name } => match symbol_table.symbol_value(name) {
+                Some(value) => {
+                    let byte = value as u16;
+                    result.push((byte >> 8) as u8);
+                    result.push(byte as u8);
+                }
+                None => return Err(name.clone()),
+            },
             _ => {
                 println!("Opcode is found in operand field");
                 std::process::exit(1);
             }
         }
+        Ok(())
+    }
+
+    // The function named by `call_host @name`, which is not a label
+    pub fn host_function(&self) -> Option<&str> {
+        match (&self.opcode, &self.operand1) {
+            (
+                Some(Token::Op {
+                    code: Opcode::CALLH,
+                }),
+                Some(Token::LabelUsage { name }),
+            ) => Some(name),
+            _ => None,
+        }
+    }
+
+    // Every @label operand, with the offset of its two bytes inside the encoded instruction
+    pub fn label_operands(&self) -> Vec<(u32, String)> {
+        if self.host_function().is_some() {
+            return vec![];
+        }
+        let mut offset = 1;
+        let mut labels = vec![];
+        for t in [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+        {
+            match t {
+                Token::Register { .. } => offset += 1,
+                Token::LabelUsage { name } => {
+                    labels.push((offset, name.clone()));
+                    offset += 2;
+                }
+                _ => offset += 2,
+            }
+        }
+        labels
     }
 
     pub fn is_label(&self) -> bool {
@@ -90,21 +163,15 @@ impl AssemblerInstruction {
 
     pub fn get_label_name(&self) -> Option<String> {
         match &self.label {
-            Some(l) => match l {
-                Token::LabelDeclaration { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::LabelDeclaration { name }) => Some(name.clone()),
+            _ => None,
         }
     }
 
     pub fn get_directive_name(&self) -> Option<String> {
         match &self.directive {
-            Some(d) => match d {
-                Token::Directive { name } => return Some(name.to_string()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::Directive { name }) => Some(name.to_string()),
+            _ => None,
         }
     }
 
@@ -189,10 +256,8 @@ named!(instruction_four<CompleteStr,AssemblerInstruction>,
 named!(pub instruction_combined<CompleteStr,AssemblerInstruction>,
     do_parse!(
         ins: alt!(
-
             instruction_three |
             instruction_one |
-            instruction_two |
             instruction_four
         ) >>
         (
@@ -275,4 +340,55 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_parse_labelled_instruction() {
+        let result = instruction(CompleteStr("test: inc $1\n"));
+        let (leftover, res) = result.unwrap();
+        assert_eq!(leftover, CompleteStr(""));
+        assert_eq!(res.get_label_name(), Some("test".to_string()));
+        assert_eq!(res.opcode, Some(Token::Op { code: Opcode::INC }));
+    }
+
+    #[test]
+    fn test_parse_host_call() {
+        let (leftover, res) = instruction(CompleteStr("call_host @square")).unwrap();
+        assert_eq!(leftover, CompleteStr(""));
+        assert_eq!(res.host_function(), Some("square"));
+        assert!(res.label_operands().is_empty());
+        let location = SourceLocation::new(1);
+        assert_eq!(
+            res.to_bytes(&SymbolTable::new(), &location),
+            Ok(vec![21, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn test_parse_label_usage_operand() {
+        let result = instruction(CompleteStr("prts @hello"));
+        let (leftover, res) = result.unwrap();
+        assert_eq!(leftover, CompleteStr(""));
+        assert_eq!(
+            res.operand1,
+            Some(Token::LabelUsage {
+                name: "hello".to_string()
+            })
+        );
+        assert_eq!(
+            res.to_bytes(&SymbolTable::new(), &SourceLocation::new(4)),
+            Err(AssemblerError::UnknownLabel {
+                name: "hello".to_string(),
+                location: SourceLocation::new(4)
+            })
+        );
+    }
+
+    #[test]
+    fn test_hlt_to_bytes() {
+        let (symbols, location) = (SymbolTable::new(), SourceLocation::new(1));
+        let (_, plain) = instruction(CompleteStr("hlt")).unwrap();
+        assert_eq!(plain.to_bytes(&symbols, &location), Ok(vec![254, 0, 0, 0]));
+        let (_, status) = instruction(CompleteStr("hlt $3")).unwrap();
+        assert_eq!(status.to_bytes(&symbols, &location), Ok(vec![254, 3, 1, 0]));
+    }
 }
@@ -1,12 +1,18 @@
 use super::Token;
-use nom::{alphanumeric, multispace, types::CompleteStr};
+use nom::{multispace, types::CompleteStr};
+
+// A label or symbol name such as print_line2
+named!(
+    pub symbol_name<CompleteStr,CompleteStr>,
+    take_while1!(|c: char| c.is_alphanumeric() || c == '_')
+);
 
 // Looks for a user-defined label such as label1:
 named!(
     pub label_declaration<CompleteStr,Token>,
     ws!(
         do_parse!(
-        name: alphanumeric >>
+        name: symbol_name >>
         tag!(":") >>
         opt!(multispace) >>
             (
@@ -22,7 +28,7 @@ named!(
     ws!(
         do_parse!(
         tag!("@") >>
-        name: alphanumeric >>
+        name: symbol_name >>
         opt!(multispace) >>
             (
                 Token::LabelUsage { name: name.to_string() }
@@ -63,5 +69,12 @@ mod tests {
         );
         let result = label_usage(CompleteStr("test"));
         assert!(result.is_err());
+        let result = label_usage(CompleteStr("@tcp_send_str"));
+        assert_eq!(
+            result.unwrap().1,
+            Token::LabelUsage {
+                name: "tcp_send_str".to_string()
+            }
+        );
     }
 }
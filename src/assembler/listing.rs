@@ -0,0 +1,102 @@
+use super::preprocessor::SourceLine;
+use super::SymbolTable;
+use std::fmt::Write;
+
+// Bytes shown on one row of the listing, longer runs continue on the next rows
+pub const LISTING_BYTES_PER_ROW: usize = 8;
+
+// Human readable view of an assembled program: every source line next to the bytes it produced
+#[derive(Debug, Default)]
+pub struct Listing {
+    text: String,
+}
+
+impl Listing {
+    pub fn new() -> Listing {
+        let mut text = String::new();
+        text.push_str("; Iridation assembler listing\n");
+        let _ = writeln!(
+            text,
+            "; {:<9}{:<26}{:<32}LOCATION",
+            "ADDRESS", "BYTES", "SOURCE"
+        );
+        Listing { text }
+    }
+
+    // address is None for lines that produce no bytes, such as section headers
+    pub fn add_line(&mut self, address: Option<String>, bytes: &[u8], line: &SourceLine) {
+        let mut rows = bytes.chunks(LISTING_BYTES_PER_ROW);
+        let first = rows.next().unwrap_or_default();
+        let _ = writeln!(
+            self.text,
+            "  {:<9}{:<26}{:<32}; {}",
+            address.unwrap_or_default(),
+            hex(first),
+            line.text.trim(),
+            line.location
+        );
+        for row in rows {
+            let _ = writeln!(self.text, "  {:<9}{}", "", hex(row));
+        }
+    }
+
+    pub fn add_symbols(&mut self, symbol_table: &SymbolTable) {
+        self.text.push_str("\n; Symbols\n");
+        let _ = writeln!(
+            self.text,
+            "; {:<24}{:<10}{:<10}OFFSET",
+            "NAME", "KIND", "SECTION"
+        );
+        for symbol in symbol_table.symbols() {
+            let section = match symbol.section() {
+                Some(section) => section.to_string(),
+                None => "-".to_string(),
+            };
+            let offset = match symbol.offset() {
+                Some(offset) => format!("{:04x}", offset),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(
+                self.text,
+                "  {:<24}{:<10}{:<10}{}",
+                symbol.name(),
+                symbol.symbol_type().to_string(),
+                section,
+                offset
+            );
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::preprocessor::SourceLocation;
+    use super::*;
+
+    #[test]
+    fn test_listing_wraps_long_byte_runs() {
+        let mut listing = Listing::new();
+        let line = SourceLine {
+            text: "hello: .asciiz 'Hello!!!!'".to_string(),
+            location: SourceLocation::new(2),
+        };
+        listing.add_line(Some("ro:0000".to_string()), b"Hello!!!!\0", &line);
+        let rows: Vec<&str> = listing.as_str().lines().skip(2).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("  ro:0000  48 65 6c 6c 6f 21 21 21"));
+        assert!(rows[0].ends_with("hello: .asciiz 'Hello!!!!'      ; line 2"));
+        assert_eq!(rows[1].trim(), "21 00");
+    }
+}
@@ -1,13 +1,23 @@
 #![allow(dead_code)]
 use crate::instruction::Opcode;
+use base_assembler::AssemblerSection;
 use nom::types::CompleteStr;
 use program_parser::{program, Program};
+use std::collections::HashMap;
+use std::fmt;
 pub mod base_assembler;
+pub mod comment_parsers;
+pub mod conditional;
+pub mod debug_info;
 pub mod directive_parsers;
 pub mod instruction_parser;
 pub mod label_parsers;
+pub mod listing;
+pub mod object;
 pub mod opcode_parser;
 pub mod operand_parser;
+pub mod optimizer;
+pub mod preprocessor;
 pub mod program_parser;
 pub mod register_parser;
 
@@ -15,6 +25,95 @@ pub mod register_parser;
 pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
 pub const PIE_HEADER_LENGTH: usize = 64;
 
+// A PIE image is the header, then the read-only section, then the code section.
+// Bytes 4..8 of the header hold the length of the read-only section (little endian),
+// which is padded so the code section starts on a 4 byte boundary.
+// An optional debug section follows the code, bytes 8..12 hold its length.
+// Between the two sits an optional section naming the host functions the code calls,
+// bytes 12..16 hold its length.
+pub fn pie_header(ro_length: u32) -> Vec<u8> {
+    let mut header = PIE_HEADER_PREFIX.to_vec();
+    header.extend_from_slice(&ro_length.to_le_bytes());
+    header.resize(PIE_HEADER_LENGTH, 0);
+    header
+}
+
+pub fn pie_ro_length(image: &[u8]) -> Option<usize> {
+    if image.len() < PIE_HEADER_LENGTH || image[0..4] != PIE_HEADER_PREFIX {
+        return None;
+    }
+    let length = u32::from_le_bytes([image[4], image[5], image[6], image[7]]) as usize;
+    if PIE_HEADER_LENGTH + length > image.len() {
+        return None;
+    }
+    Some(length)
+}
+
+pub fn pie_debug_length(image: &[u8]) -> Option<usize> {
+    let ro_length = pie_ro_length(image)?;
+    let length = u32::from_le_bytes([image[8], image[9], image[10], image[11]]) as usize;
+    if PIE_HEADER_LENGTH + ro_length + length > image.len() {
+        return None;
+    }
+    Some(length)
+}
+
+// Appends a debug section to a linked image
+pub fn pie_append_debug(image: &mut Vec<u8>, debug: &[u8]) {
+    image[8..12].copy_from_slice(&(debug.len() as u32).to_le_bytes());
+    image.extend_from_slice(debug);
+}
+
+pub fn pie_imports_length(image: &[u8]) -> Option<usize> {
+    let ro_length = pie_ro_length(image)?;
+    let debug_length = pie_debug_length(image)?;
+    let length = u32::from_le_bytes([image[12], image[13], image[14], image[15]]) as usize;
+    if PIE_HEADER_LENGTH + ro_length + debug_length + length > image.len() {
+        return None;
+    }
+    Some(length)
+}
+
+// Appends the host imports section to a linked image, before any debug section.
+// Each name is stored as a little endian u32 length and its bytes, after a u32 count.
+pub fn pie_append_imports(image: &mut Vec<u8>, names: &[String]) {
+    let mut section = (names.len() as u32).to_le_bytes().to_vec();
+    for name in names {
+        section.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        section.extend_from_slice(name.as_bytes());
+    }
+    image[12..16].copy_from_slice(&(section.len() as u32).to_le_bytes());
+    image.extend_from_slice(&section);
+}
+
+// Names of the host functions an image imports, in the order call_host operands index them
+pub fn pie_imports(image: &[u8]) -> Option<Vec<String>> {
+    let length = pie_imports_length(image)?;
+    if length == 0 {
+        return Some(vec![]);
+    }
+    let end = image.len() - pie_debug_length(image)?;
+    let section = &image[end - length..end];
+    let read_u32 = |at: usize| -> Option<usize> {
+        let bytes = section.get(at..at + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+    let mut names = vec![];
+    let mut position = 4;
+    for _ in 0..read_u32(0)? {
+        let name_length = read_u32(position)?;
+        let name = section.get(position + 4..position + 4 + name_length)?;
+        names.push(String::from_utf8(name.to_vec()).ok()?);
+        position += 4 + name_length;
+    }
+    Some(names)
+}
+
+// Zero bytes needed after a read-only section of this length
+pub fn pie_ro_padding(ro_length: usize) -> usize {
+    (4 - ro_length % 4) % 4
+}
+
 // Token
 #[derive(Debug, PartialEq)]
 pub enum Token {
@@ -33,6 +132,7 @@ pub struct Symbol {
     name: String,
     symbol_type: SymbolType,
     offset: Option<u32>,
+    section: Option<AssemblerSection>,
 }
 
 impl Symbol {
@@ -41,6 +141,7 @@ impl Symbol {
             name,
             symbol_type,
             offset: None,
+            section: None,
         }
     }
 
@@ -49,20 +150,53 @@ impl Symbol {
             name,
             symbol_type,
             offset,
+            section: None,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
+
+    pub fn offset(&self) -> Option<u32> {
+        self.offset
+    }
+
+    pub fn section(&self) -> Option<&AssemblerSection> {
+        self.section.as_ref()
+    }
 }
 
 // SymbolType
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum SymbolType {
     Label,
+    // A label exported with .global
+    Global,
+    // Declared with .extern and defined in another module
+    Extern,
+}
+
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolType::Label => write!(f, "label"),
+            SymbolType::Global => write!(f, "global"),
+            SymbolType::Extern => write!(f, "extern"),
+        }
+    }
 }
 
 // SymbolTable
+// Symbols stay in declaration order, with an index by name for lookups
 #[derive(Debug)]
 pub struct SymbolTable {
     symbols: Vec<Symbol>,
+    index: HashMap<String, usize>,
 }
 
 impl Default for SymbolTable {
@@ -73,39 +207,59 @@ impl Default for SymbolTable {
 
 impl SymbolTable {
     pub fn new() -> SymbolTable {
-        SymbolTable { symbols: vec![] }
+        SymbolTable {
+            symbols: vec![],
+            index: HashMap::new(),
+        }
     }
 
+    // A name added twice keeps resolving to the first symbol
     pub fn add_symbols(&mut self, symbol: Symbol) {
+        self.index
+            .entry(symbol.name.clone())
+            .or_insert(self.symbols.len());
         self.symbols.push(symbol);
     }
 
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.index.get(name).map(|index| &self.symbols[*index])
+    }
+
+    fn symbol_mut(&mut self, name: &str) -> Option<&mut Symbol> {
+        let index = *self.index.get(name)?;
+        Some(&mut self.symbols[index])
+    }
+
     pub fn symbol_value(&self, symbol: &str) -> Option<u32> {
-        for i in &self.symbols {
-            if i.name == symbol {
-                return Some(i.offset.unwrap());
+        self.symbol(symbol)?.offset
+    }
+
+    pub fn set_symbol_type(&mut self, s: &str, symbol_type: SymbolType) -> bool {
+        match self.symbol_mut(s) {
+            Some(symbol) => {
+                symbol.symbol_type = symbol_type;
+                true
             }
+            None => false,
         }
-        None
     }
 
     pub fn set_symbol_offset(&mut self, s: &str, offset: u32) -> bool {
-        for symbol in &mut self.symbols {
-            if symbol.name == s {
+        match self.symbol_mut(s) {
+            Some(symbol) => {
                 symbol.offset = Some(offset);
-                return true;
+                true
             }
+            None => false,
         }
-        false
     }
 
     pub fn has_symbol(&self, symbol: &str) -> bool {
-        for i in &self.symbols {
-            if i.name == symbol {
-                return true;
-            }
-        }
-        false
+        self.index.contains_key(symbol)
     }
 }
 
@@ -122,6 +276,7 @@ mod tests {
             name: "test".to_string(),
             symbol_type: SymbolType::Label,
             offset: Some(13),
+            section: None,
         });
         assert_eq!(table.symbols.len(), 1);
         let symbol_val = table.symbol_value("test");
@@ -129,6 +284,15 @@ mod tests {
         assert_eq!(symbol_val.unwrap(), 13);
         let symbol_val = table.symbol_value("error");
         assert!(symbol_val.is_none());
+
+        // Lookups and updates go to the first symbol with a name
+        table.add_symbols(Symbol::new("test".to_string(), SymbolType::Extern));
+        assert!(table.set_symbol_offset("test", 20));
+        assert!(table.set_symbol_type("test", SymbolType::Global));
+        assert_eq!(table.symbol_value("test"), Some(20));
+        assert_eq!(table.symbols()[0].symbol_type, SymbolType::Global);
+        assert_eq!(table.symbols()[1].symbol_type, SymbolType::Extern);
+        assert!(!table.set_symbol_offset("error", 1));
     }
 
     #[test]
@@ -139,7 +303,185 @@ mod tests {
         let result = assembler.assemble(program);
         let mut vm: Vm = Vm::new();
         vm.add_bytes(result.unwrap());
-        // jmpe @test still doesnt increase byte lenght
-        assert_eq!(vm.program.len(), 80);
+        // Header plus five instructions, including the labelled inc and jmpe @test
+        assert_eq!(vm.program().len(), 84);
+    }
+
+    #[test]
+    fn test_assemble_program_with_comments() {
+        let program: &str = "; setup\n.data\n\n.code\n/* count\n   up */\nload $0 #10   \n\n  inc $0 ; bump\n#! done\nhlt\n";
+        let mut assembler: Assembler = Assembler::new();
+        let result = assembler.assemble(program).unwrap();
+        assert_eq!(result.len(), PIE_HEADER_LENGTH + 12);
+    }
+
+    #[test]
+    fn test_assemble_reports_unparsed_line() {
+        let program: &str = ".data\n.code\nload $0 #10\n\n%% oops\nhlt\n";
+        let mut assembler: Assembler = Assembler::new();
+        match assembler.assemble(program) {
+            Err(errors) => match &errors[0] {
+                AssemblerError::UnparsedInput { location, content } => {
+                    assert_eq!(location.line, 5);
+                    assert_eq!(content, "%% oops");
+                }
+                e => panic!("unexpected error {:?}", e),
+            },
+            Ok(_) => panic!("leftover input was silently dropped"),
+        }
+    }
+
+    #[test]
+    fn test_assemble_resolves_code_labels() {
+        let program: &str = ".data\n.code\nload $0 #1\ntest: inc $0\nload $1 @test\n";
+        let mut assembler: Assembler = Assembler::new();
+        let result = assembler.assemble(program).unwrap();
+        // Symbols are relative to their section, the linker turns them into absolute addresses
+        assert_eq!(assembler.symbol_table.symbol_value("test"), Some(4));
+        assert_eq!(result[PIE_HEADER_LENGTH + 8..], [0, 1, 0, 68]);
+    }
+
+    #[test]
+    fn test_assemble_reports_unknown_label() {
+        let program: &str = ".data
+.code
+load $0 @nowhere
+";
+        let mut assembler: Assembler = Assembler::new();
+        let errors = assembler.assemble(program).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Label nowhere is never declared (line 3)"
+        );
+    }
+
+    #[test]
+    fn test_assemble_object() {
+        let program: &str = ".data
+msg: .asciiz 'ok'
+.code
+.global main
+.extern print
+main: load $0 @print
+prts @msg
+";
+        let mut assembler: Assembler = Assembler::new();
+        let object = assembler.assemble_object(program).unwrap();
+        assert_eq!(object.ro, b"ok\0".to_vec());
+        assert_eq!(object.code.len(), 8);
+        assert_eq!(object.exports().collect::<Vec<&str>>(), vec!["main"]);
+        assert_eq!(object.imports().collect::<Vec<&str>>(), vec!["print"]);
+        assert_eq!(
+            object.relocations,
+            vec![
+                object::Relocation {
+                    offset: 2,
+                    symbol: "print".to_string()
+                },
+                object::Relocation {
+                    offset: 5,
+                    symbol: "msg".to_string()
+                },
+            ]
+        );
+
+        // A lone module cannot satisfy its own imports
+        let errors = Assembler::new().assemble(program).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Undefined symbol print referenced from <program>"
+        );
+    }
+
+    #[test]
+    fn test_assemble_global_must_be_defined() {
+        let program: &str = ".data
+.code
+.global main
+hlt
+";
+        let errors = Assembler::new().assemble_object(program).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Symbol main is declared .global but never defined (line 3)"
+        );
+    }
+
+    #[test]
+    fn test_assemble_program_with_macros() {
+        let program: &str = ".macro countdown reg, start\nload \\reg #\\start\nloop\\@: dec \\reg\n.endm\n.data\n.code\ncountdown $0, 3\ncountdown $1, 5\nhlt\n";
+        let mut assembler: Assembler = Assembler::new();
+        let result = assembler.assemble(program).unwrap();
+        assert_eq!(result.len(), PIE_HEADER_LENGTH + 20);
+        assert_eq!(assembler.symbol_table.symbol_value("loop1"), Some(4));
+        assert_eq!(assembler.symbol_table.symbol_value("loop2"), Some(12));
+    }
+
+    #[test]
+    fn test_assemble_with_defines() {
+        let program: &str = ".data\n.code\n.ifdef DEBUG\ntrace: inc $0\n.endif\nload $0 #1\n";
+        let mut assembler: Assembler = Assembler::new();
+        let release = assembler.assemble(program).unwrap();
+        assert_eq!(release.len(), PIE_HEADER_LENGTH + 4);
+        assert!(!assembler.symbol_table.has_symbol("trace"));
+
+        let mut assembler: Assembler = Assembler::new();
+        assembler.define("DEBUG", 1);
+        let debug = assembler.assemble(program).unwrap();
+        assert_eq!(debug.len(), PIE_HEADER_LENGTH + 8);
+        assert_eq!(assembler.symbol_table.symbol_value("trace"), Some(0));
+    }
+
+    #[test]
+    fn test_assemble_traces_errors_through_macros() {
+        let program: &str = ".macro twice\nagain: inc $0\n.endm\n.data\n.code\ntwice\ntwice\n";
+        let mut assembler: Assembler = Assembler::new();
+        let errors = assembler.assemble(program).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Symbol again was already declared (line 2, in macro `twice` expanded at line 7)"
+        );
+    }
+
+    #[test]
+    fn test_assemble_optimized() {
+        let program: &str = ".data\n.code\nnop\nload $1 @end\njmp $1\nend: hlt\n";
+        let mut assembler: Assembler = Assembler::new();
+        assembler.enable_optimizer();
+        let result = assembler.assemble(program).unwrap();
+        // nop and the jump are gone, the label moves up with the code
+        assert_eq!(result[PIE_HEADER_LENGTH..], [0, 1, 0, 68, 254, 0, 0, 0]);
+        assert_eq!(assembler.optimization_report().unwrap().removals.len(), 2);
+    }
+
+    #[test]
+    fn test_assemble_listing() {
+        let program: &str =
+            ".data\nhello: .asciiz 'Hi'\n.code\nstart: load $0 #10\nprts @hello\nload $1 @start\n";
+        let mut assembler: Assembler = Assembler::new();
+        assert!(assembler.listing().is_none());
+        assembler.enable_listing();
+        assembler.assemble(program).unwrap();
+        let listing = assembler.listing().unwrap();
+        let rows: Vec<&str> = listing.lines().map(|l| l.trim_end()).collect();
+
+        assert!(rows.contains(
+            &"                                     .data                           ; line 1"
+        ));
+        assert!(rows.contains(
+            &"  ro:0000  48 69 00                  hello: .asciiz 'Hi'             ; line 2"
+        ));
+        // 'Hi' is padded to four bytes, so the code starts at 0x44
+        assert!(rows.contains(
+            &"  0044     00 00 00 0a               start: load $0 #10              ; line 4"
+        ));
+        assert!(rows.contains(
+            &"  0048     14 00 00 00               prts @hello                     ; line 5"
+        ));
+        assert!(rows.contains(
+            &"  004c     00 01 00 44               load $1 @start                  ; line 6"
+        ));
+        assert!(rows.contains(&"  hello                   label     data      0000"));
+        assert!(rows.contains(&"  start                   label     code      0000"));
     }
 }
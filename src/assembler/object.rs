@@ -0,0 +1,300 @@
+use super::debug_info::DebugInfo;
+use crate::codec::{write_bytes, write_u32, CodecError, Reader};
+use std::fmt;
+
+// Constants
+pub const OBJECT_FILE_PREFIX: [u8; 4] = [73, 82, 79, 66]; // "IROB"
+pub const OBJECT_FILE_VERSION: u32 = 3;
+
+// Section an object symbol lives in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ObjectSection {
+    Code,
+    Ro,
+    // Declared with .extern, defined by some other object
+    Undefined,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SymbolBinding {
+    // Only visible inside its own object
+    Local,
+    // Exported with .global
+    Global,
+    // Imported with .extern
+    Extern,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub binding: SymbolBinding,
+    pub section: ObjectSection,
+    // Offset from the start of its section
+    pub offset: u32,
+}
+
+// A 16 bit operand in the code section that must be patched with a symbol's final address
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relocation {
+    pub offset: u32,
+    pub symbol: String,
+}
+
+// An assembled module that still has to be linked into a PIE image
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ObjectFile {
+    pub code: Vec<u8>,
+    pub ro: Vec<u8>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub relocations: Vec<Relocation>,
+    // call_host operands, patched with the index of the host function in the image's imports
+    pub host_calls: Vec<Relocation>,
+    // Line table for the code section, present when assembled with debug info
+    pub debug: Option<DebugInfo>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ObjectFileError {
+    NotAnObjectFile,
+    UnsupportedVersion { version: u32 },
+    Truncated,
+    InvalidSymbol { name: String },
+    InvalidDebugInfo,
+}
+
+impl fmt::Display for ObjectFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectFileError::NotAnObjectFile => write!(f, "not an Iridation object file"),
+            ObjectFileError::UnsupportedVersion { version } => {
+                write!(f, "unsupported object file version {}", version)
+            }
+            ObjectFileError::Truncated => write!(f, "object file is truncated"),
+            ObjectFileError::InvalidSymbol { name } => {
+                write!(f, "object file has a malformed entry for symbol {}", name)
+            }
+            ObjectFileError::InvalidDebugInfo => write!(f, "object file has malformed debug info"),
+        }
+    }
+}
+
+// Undecodable strings were always reported as truncation
+impl From<CodecError> for ObjectFileError {
+    fn from(_: CodecError) -> ObjectFileError {
+        ObjectFileError::Truncated
+    }
+}
+
+impl ObjectFile {
+    pub fn new() -> ObjectFile {
+        ObjectFile::default()
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&ObjectSymbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    // Names this object exports with .global
+    pub fn exports(&self) -> impl Iterator<Item = &str> {
+        self.symbols
+            .iter()
+            .filter(|s| s.binding == SymbolBinding::Global)
+            .map(|s| s.name.as_str())
+    }
+
+    // Names this object needs another object to define
+    pub fn imports(&self) -> impl Iterator<Item = &str> {
+        self.symbols
+            .iter()
+            .filter(|s| s.binding == SymbolBinding::Extern)
+            .map(|s| s.name.as_str())
+    }
+
+    pub fn is_object_file(bytes: &[u8]) -> bool {
+        bytes.len() >= 4 && bytes[0..4] == OBJECT_FILE_PREFIX
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = OBJECT_FILE_PREFIX.to_vec();
+        write_u32(&mut out, OBJECT_FILE_VERSION);
+        write_bytes(&mut out, &self.code);
+        write_bytes(&mut out, &self.ro);
+
+        write_u32(&mut out, self.symbols.len() as u32);
+        for symbol in &self.symbols {
+            out.push(match symbol.binding {
+                SymbolBinding::Local => 0,
+                SymbolBinding::Global => 1,
+                SymbolBinding::Extern => 2,
+            });
+            out.push(match symbol.section {
+                ObjectSection::Code => 0,
+                ObjectSection::Ro => 1,
+                ObjectSection::Undefined => 2,
+            });
+            write_u32(&mut out, symbol.offset);
+            write_bytes(&mut out, symbol.name.as_bytes());
+        }
+
+        for relocations in [&self.relocations, &self.host_calls] {
+            write_u32(&mut out, relocations.len() as u32);
+            for relocation in relocations {
+                write_u32(&mut out, relocation.offset);
+                write_bytes(&mut out, relocation.symbol.as_bytes());
+            }
+        }
+
+        match &self.debug {
+            Some(debug) => {
+                out.push(1);
+                write_bytes(&mut out, &debug.to_bytes());
+            }
+            None => out.push(0),
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, ObjectFileError> {
+        if !ObjectFile::is_object_file(bytes) {
+            return Err(ObjectFileError::NotAnObjectFile);
+        }
+        let mut reader = Reader::new(&bytes[OBJECT_FILE_PREFIX.len()..]);
+        let version = reader.u32()?;
+        if version != OBJECT_FILE_VERSION {
+            return Err(ObjectFileError::UnsupportedVersion { version });
+        }
+
+        let mut object = ObjectFile {
+            code: reader.bytes()?.to_vec(),
+            ro: reader.bytes()?.to_vec(),
+            ..ObjectFile::default()
+        };
+
+        for _ in 0..reader.u32()? {
+            let binding = reader.u8()?;
+            let section = reader.u8()?;
+            let offset = reader.u32()?;
+            let name = reader.string()?;
+            let binding = match binding {
+                0 => SymbolBinding::Local,
+                1 => SymbolBinding::Global,
+                2 => SymbolBinding::Extern,
+                _ => return Err(ObjectFileError::InvalidSymbol { name }),
+            };
+            let section = match section {
+                0 => ObjectSection::Code,
+                1 => ObjectSection::Ro,
+                2 => ObjectSection::Undefined,
+                _ => return Err(ObjectFileError::InvalidSymbol { name }),
+            };
+            object.symbols.push(ObjectSymbol {
+                name,
+                binding,
+                section,
+                offset,
+            });
+        }
+
+        for relocations in [&mut object.relocations, &mut object.host_calls] {
+            for _ in 0..reader.u32()? {
+                let offset = reader.u32()?;
+                let symbol = reader.string()?;
+                relocations.push(Relocation { offset, symbol });
+            }
+        }
+
+        if reader.u8()? == 1 {
+            let debug = DebugInfo::from_bytes(reader.bytes()?)
+                .map_err(|_| ObjectFileError::InvalidDebugInfo)?;
+            object.debug = Some(debug);
+        }
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ObjectFile {
+        ObjectFile {
+            code: vec![0, 1, 0, 0, 20, 0, 0, 0],
+            ro: b"Hi\0".to_vec(),
+            symbols: vec![
+                ObjectSymbol {
+                    name: "main".to_string(),
+                    binding: SymbolBinding::Global,
+                    section: ObjectSection::Code,
+                    offset: 0,
+                },
+                ObjectSymbol {
+                    name: "greeting".to_string(),
+                    binding: SymbolBinding::Local,
+                    section: ObjectSection::Ro,
+                    offset: 0,
+                },
+                ObjectSymbol {
+                    name: "print".to_string(),
+                    binding: SymbolBinding::Extern,
+                    section: ObjectSection::Undefined,
+                    offset: 0,
+                },
+            ],
+            relocations: vec![Relocation {
+                offset: 2,
+                symbol: "print".to_string(),
+            }],
+            host_calls: vec![Relocation {
+                offset: 5,
+                symbol: "log".to_string(),
+            }],
+            debug: None,
+        }
+    }
+
+    #[test]
+    fn test_object_file_round_trip() {
+        let mut object = sample();
+        let bytes = object.to_bytes();
+        assert!(ObjectFile::is_object_file(&bytes));
+        assert_eq!(ObjectFile::from_bytes(&bytes), Ok(object.clone()));
+
+        let mut debug = DebugInfo::new();
+        debug.lines.push(super::super::debug_info::LineEntry {
+            pc: 4,
+            file: Some("print.iasm".to_string()),
+            line: 2,
+            column: 1,
+        });
+        object.debug = Some(debug);
+        let bytes = object.to_bytes();
+        assert_eq!(ObjectFile::from_bytes(&bytes), Ok(object));
+    }
+
+    #[test]
+    fn test_object_file_imports_and_exports() {
+        let object = sample();
+        assert_eq!(object.exports().collect::<Vec<&str>>(), vec!["main"]);
+        assert_eq!(object.imports().collect::<Vec<&str>>(), vec!["print"]);
+    }
+
+    #[test]
+    fn test_invalid_object_files() {
+        assert_eq!(
+            ObjectFile::from_bytes(&[1, 2, 3, 4]),
+            Err(ObjectFileError::NotAnObjectFile)
+        );
+        let bytes = sample().to_bytes();
+        assert_eq!(
+            ObjectFile::from_bytes(&bytes[..bytes.len() - 3]),
+            Err(ObjectFileError::Truncated)
+        );
+        let mut bytes = sample().to_bytes();
+        bytes[4] = 9;
+        assert_eq!(
+            ObjectFile::from_bytes(&bytes),
+            Err(ObjectFileError::UnsupportedVersion { version: 9 })
+        );
+    }
+}
@@ -1,6 +1,5 @@
 use crate::assembler::Token;
 use crate::instruction::Opcode;
-use nom::alpha1;
 use nom::types::CompleteStr;
 
 named!(
@@ -10,9 +9,11 @@ named!(
     )
 );
 
+// A word followed by a colon is a label declaration, not an opcode
 named!(pub opcode<CompleteStr, Token>,
   do_parse!(
-      opcode: alpha1 >>
+      opcode: take_while1!(|c: char| c.is_alphabetic() || c == '_') >>
+      not!(tag!(":")) >>
       (
         {
             Token::Op{code: Opcode::from(opcode)}
@@ -51,5 +52,7 @@ mod tests {
         assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::ADD });
+        let result = opcode(CompleteStr("test: inc $0"));
+        assert!(result.is_err());
     }
 }
@@ -24,11 +24,32 @@ named!(
         content: take_until!("'") >>
         tag!("'") >>
         (
-            Token::IrString { name: content.to_string() }
+            Token::IrString { name: unescape(&content) }
         )
     )
 );
 
+// Resolves \n, \r, \t and \\ in a string constant, other backslashes are kept as written
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let escaped = match (c, chars.peek()) {
+            ('\\', Some('n')) => '\n',
+            ('\\', Some('r')) => '\r',
+            ('\\', Some('t')) => '\t',
+            ('\\', Some('\\')) => '\\',
+            _ => {
+                result.push(c);
+                continue;
+            }
+        };
+        chars.next();
+        result.push(escaped);
+    }
+    result
+}
+
 named!(
     pub operand<CompleteStr,Token>,
     alt!(
@@ -66,5 +87,16 @@ mod tests {
                 }
             ))
         );
+
+        let result = operand(CompleteStr(r"'GET / HTTP/1.1\r\n\t\\ \d'"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::IrString {
+                    name: "GET / HTTP/1.1\r\n\t\\ \\d".to_string()
+                }
+            ))
+        );
     }
 }
@@ -0,0 +1,385 @@
+use super::instruction_parser::AssemblerInstruction;
+use super::preprocessor::{SourceLine, SourceLocation};
+use super::Token;
+use crate::instruction::Opcode;
+use std::fmt;
+
+// One instruction the optimizer dropped
+#[derive(Debug, PartialEq, Clone)]
+pub struct Removal {
+    pub location: SourceLocation,
+    pub source: String,
+    pub reason: &'static str,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct OptimizationReport {
+    pub removals: Vec<Removal>,
+    // Why the program was left alone, when it was
+    pub skipped: Option<&'static str>,
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(reason) = self.skipped {
+            return write!(f, "Optimizer left the program unchanged: {}", reason);
+        }
+        write!(
+            f,
+            "Optimizer removed {} instructions ({} bytes)",
+            self.removals.len(),
+            self.removals.len() * 4
+        )?;
+        for removal in &self.removals {
+            write!(
+                f,
+                "\n  {}: {} ({})",
+                removal.location, removal.source, removal.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Peephole pass over the parsed program, run before labels are given offsets.
+// Only unlabelled instructions are removed, so every jump target survives. Removing
+// instructions moves code around, which is only safe when every jump goes through a label:
+// programs using jmpf/jmpb, or jumping to computed addresses, are left unchanged.
+// lines holds the source line of each instruction and is kept in step.
+pub fn optimize(
+    instructions: &mut Vec<AssemblerInstruction>,
+    lines: &mut Vec<SourceLine>,
+) -> OptimizationReport {
+    let mut report = OptimizationReport::default();
+    if let Some(reason) = unsafe_to_move(instructions) {
+        report.skipped = Some(reason);
+        return report;
+    }
+
+    loop {
+        let reasons = find_removals(instructions);
+        if reasons.iter().all(|r| r.is_none()) {
+            return report;
+        }
+        let mut kept_instructions = vec![];
+        let mut kept_lines = vec![];
+        for ((instruction, line), reason) in
+            instructions.drain(..).zip(lines.drain(..)).zip(reasons)
+        {
+            match reason {
+                Some(reason) => report.removals.push(Removal {
+                    location: line.location.clone(),
+                    source: line.text.trim().to_string(),
+                    reason,
+                }),
+                None => {
+                    kept_instructions.push(instruction);
+                    kept_lines.push(line);
+                }
+            }
+        }
+        *instructions = kept_instructions;
+        *lines = kept_lines;
+    }
+}
+
+fn opcode(i: &AssemblerInstruction) -> Option<Opcode> {
+    match &i.opcode {
+        Some(Token::Op { code }) => Some(*code),
+        _ => None,
+    }
+}
+
+fn register(operand: &Option<Token>) -> Option<u8> {
+    match operand {
+        Some(Token::Register { reg }) => Some(*reg),
+        _ => None,
+    }
+}
+
+// Registers an instruction reads and the registers it writes
+fn reads_and_writes(i: &AssemblerInstruction) -> (Vec<u8>, Vec<u8>) {
+    let (first, second, third) = (
+        register(&i.operand1),
+        register(&i.operand2),
+        register(&i.operand3),
+    );
+    let reads = match opcode(i) {
+        Some(Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV) => vec![first, second],
+        Some(
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTQ
+            | Opcode::LTQ
+            | Opcode::SEND,
+        ) => vec![first, second],
+        Some(Opcode::SENDB) => vec![first, second, third],
+        Some(
+            Opcode::INC
+            | Opcode::DEC
+            | Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::AlOC
+            | Opcode::HLT
+            | Opcode::JOIN
+            | Opcode::RECV
+            | Opcode::TRYRECV,
+        ) => vec![first],
+        // A host function is handed the whole VM and may read any register, and a spawned
+        // thread starts with a copy of them all
+        Some(Opcode::CALLH | Opcode::SPAWN) => (0..32).map(Some).collect(),
+        _ => vec![],
+    };
+    let writes = match opcode(i) {
+        Some(
+            Opcode::LOAD
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::SPAWN
+            | Opcode::JOIN
+            | Opcode::CHAN
+            | Opcode::READB
+            | Opcode::READI,
+        ) => vec![first],
+        Some(Opcode::READL) => vec![first, second],
+        Some(Opcode::CALLH) => vec![Some(0)],
+        Some(Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV) => vec![third],
+        Some(Opcode::RECV | Opcode::TRYRECV) => vec![second, third],
+        _ => vec![],
+    };
+    (
+        reads.into_iter().flatten().collect(),
+        writes.into_iter().flatten().collect(),
+    )
+}
+
+// Instructions that only write their registers sometimes: try_recv leaves them alone when
+// the channel is empty, and read_int at the end of input
+fn writes_conditionally(i: &AssemblerInstruction) -> bool {
+    matches!(opcode(i), Some(Opcode::TRYRECV | Opcode::READI))
+}
+
+fn is_jump(i: &AssemblerInstruction) -> bool {
+    matches!(
+        opcode(i),
+        Some(Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ | Opcode::JNEQ)
+    )
+}
+
+fn loads_label(i: &AssemblerInstruction) -> Option<&str> {
+    match (opcode(i), &i.operand2) {
+        (Some(Opcode::LOAD), Some(Token::LabelUsage { name })) => Some(name),
+        _ => None,
+    }
+}
+
+fn unsafe_to_move(instructions: &[AssemblerInstruction]) -> Option<&'static str> {
+    if instructions
+        .iter()
+        .any(|i| matches!(opcode(i), Some(Opcode::JMPF | Opcode::JMPB)))
+    {
+        return Some("jmpf and jmpb jump by a distance that removing code would change");
+    }
+
+    // Every register used as a jump target may only ever hold a label's address
+    let targets: Vec<u8> = instructions
+        .iter()
+        .filter(|i| is_jump(i))
+        .filter_map(|i| register(&i.operand1))
+        .collect();
+    let computed = instructions.iter().any(|i| {
+        let (_, writes) = reads_and_writes(i);
+        writes.iter().any(|r| targets.contains(r)) && loads_label(i).is_none()
+    });
+    if computed {
+        return Some("a jump target is computed instead of loaded from a label");
+    }
+    None
+}
+
+fn find_removals(instructions: &[AssemblerInstruction]) -> Vec<Option<&'static str>> {
+    let mut reasons = vec![None; instructions.len()];
+    // Registers known to hold zero, forgotten at labels and after jumps
+    let mut zero = [false; 32];
+
+    for (index, i) in instructions.iter().enumerate() {
+        if i.label.is_some() {
+            zero = [false; 32];
+        }
+        let next = instructions.get(index + 1).filter(|n| n.is_opcode());
+        if i.is_opcode() && i.label.is_none() {
+            reasons[index] = removal_reason(i, index, instructions, next, &zero);
+        }
+
+        let (_, writes) = reads_and_writes(i);
+        for r in writes {
+            zero[r as usize] = opcode(i) == Some(Opcode::LOAD)
+                && i.operand2 == Some(Token::IntergerOperand { val: 0 });
+        }
+        // A host function may also write any register
+        if is_jump(i) || opcode(i) == Some(Opcode::CALLH) {
+            zero = [false; 32];
+        }
+    }
+    reasons
+}
+
+fn removal_reason(
+    i: &AssemblerInstruction,
+    index: usize,
+    instructions: &[AssemblerInstruction],
+    next: Option<&AssemblerInstruction>,
+    zero: &[bool; 32],
+) -> Option<&'static str> {
+    let (first, second, third) = (
+        register(&i.operand1),
+        register(&i.operand2),
+        register(&i.operand3),
+    );
+    match opcode(i)? {
+        Opcode::NOP => Some("does nothing"),
+        Opcode::LOAD => {
+            let (reads, writes) = reads_and_writes(next?);
+            if writes.contains(&first?) && !reads.contains(&first?) && !writes_conditionally(next?)
+            {
+                Some("overwritten by the next instruction")
+            } else {
+                None
+            }
+        }
+        Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => {
+            let previous = instructions.get(index.checked_sub(1)?)?;
+            let target = loads_label(previous)?;
+            let next_label = next?.get_label_name()?;
+            if register(&previous.operand1) == first && next_label == target {
+                Some("jumps to the next instruction")
+            } else {
+                None
+            }
+        }
+        Opcode::ADD => {
+            let zero_operand = match (first, second) {
+                (Some(a), Some(b)) if third == Some(a) && zero[b as usize] => true,
+                (Some(a), Some(b)) if third == Some(b) && zero[a as usize] => true,
+                _ => false,
+            };
+            zero_operand.then_some("adds a register known to be zero")
+        }
+        Opcode::SUB => match (first, second) {
+            (Some(a), Some(b)) if third == Some(a) && zero[b as usize] => {
+                Some("subtracts a register known to be zero")
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parser::program;
+    use nom::types::CompleteStr;
+
+    fn run(source: &str) -> (Vec<String>, OptimizationReport) {
+        let mut instructions = vec![];
+        let mut lines = vec![];
+        for (number, text) in source.lines().enumerate() {
+            let (_, mut p) = program(CompleteStr(text)).unwrap();
+            for _ in &p.instructions {
+                lines.push(SourceLine {
+                    text: text.to_string(),
+                    location: SourceLocation::new(number as u32 + 1),
+                });
+            }
+            instructions.append(&mut p.instructions);
+        }
+        let report = optimize(&mut instructions, &mut lines);
+        (lines.into_iter().map(|l| l.text).collect(), report)
+    }
+
+    #[test]
+    fn test_removes_nops_and_dead_loads() {
+        let (kept, report) = run("load $0 #1\nnop\nload $0 #2\nload $1 #3\nadd $0 $1 $1\nhlt");
+        assert_eq!(
+            kept,
+            vec!["load $0 #2", "load $1 #3", "add $0 $1 $1", "hlt"]
+        );
+        assert_eq!(report.removals.len(), 2);
+        assert_eq!(
+            report.to_string(),
+            "Optimizer removed 2 instructions (8 bytes)\n  line 2: nop (does nothing)\n  line 1: load $0 #1 (overwritten by the next instruction)"
+        );
+    }
+
+    #[test]
+    fn test_keeps_loads_before_conditional_writes() {
+        // try_recv on an empty channel keeps the 7
+        let (kept, report) = run("chan $0\nload $1 #7\ntry_recv $0 $1 $2\nhlt $1");
+        assert_eq!(kept.len(), 4);
+        assert!(report.removals.is_empty());
+
+        // So does read_int at the end of input
+        let (kept, _) = run("load $0 #7\nread_int $0\nhlt $0");
+        assert_eq!(kept.len(), 3);
+
+        // read_byte always writes its register
+        let (kept, _) = run("load $0 #7\nread_byte $0\nhlt $0");
+        assert_eq!(kept, vec!["read_byte $0", "hlt $0"]);
+    }
+
+    #[test]
+    fn test_removes_jumps_to_next_instruction() {
+        let (kept, _) = run("load $1 @next\njmp $1\nnext: hlt");
+        assert_eq!(kept, vec!["load $1 @next", "next: hlt"]);
+
+        let (kept, _) = run("load $1 @other\njmp $1\nnext: hlt\nother: hlt");
+        assert_eq!(kept.len(), 4);
+    }
+
+    #[test]
+    fn test_removes_adds_of_known_zero() {
+        let (kept, _) = run("load $2 #0\nadd $0 $2 $0\nsub $0 $2 $0\nadd $0 $2 $1\nhlt");
+        assert_eq!(kept, vec!["load $2 #0", "add $0 $2 $1", "hlt"]);
+
+        // A label may be reached with $2 holding anything
+        let (kept, _) = run("load $2 #0\nback: add $0 $2 $0\nhlt");
+        assert_eq!(kept.len(), 3);
+
+        // So may a host function, after reading any register
+        let (kept, _) = run("load $2 #0\nload $7 #1\ncall_host @log\nadd $0 $2 $0\nhlt");
+        assert_eq!(kept.len(), 5);
+        let (kept, _) = run("load $7 #1\nspawn $1 @worker\nhlt\nworker: hlt $7");
+        assert_eq!(kept.len(), 4);
+
+        // recv writes both its value and its length register
+        let (kept, _) = run("load $2 #0\nload $0 #0\nrecv $0 $1 $2\nadd $3 $2 $3\nhlt");
+        assert_eq!(kept.len(), 5);
+    }
+
+    #[test]
+    fn test_keeps_labels_and_reads() {
+        let (kept, report) = run("start: nop\nload $0 #1\ninc $0\nload $0 #1\nadd $0 $0 $0\nhlt");
+        assert_eq!(kept.len(), 6);
+        assert!(report.removals.is_empty());
+    }
+
+    #[test]
+    fn test_skips_position_dependent_programs() {
+        let (kept, report) = run("nop\nload $0 #4\njmpf $0\nhlt");
+        assert_eq!(kept.len(), 4);
+        assert!(report.skipped.is_some());
+
+        let (kept, report) = run("nop\nload $0 #72\njmp $0\nhlt");
+        assert_eq!(kept.len(), 4);
+        assert_eq!(
+            report.to_string(),
+            "Optimizer left the program unchanged: a jump target is computed instead of loaded from a label"
+        );
+    }
+}
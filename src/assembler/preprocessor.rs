@@ -0,0 +1,853 @@
+use super::base_assembler::AssemblerError;
+use super::comment_parsers::{block_comment, line_comment};
+use super::conditional::{evaluate, Conditional};
+use crate::instruction::Opcode;
+use nom::types::CompleteStr;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Guards against macros that (directly or indirectly) invoke themselves forever
+pub const MAX_MACRO_DEPTH: usize = 64;
+
+// Where a macro was invoked from
+#[derive(Debug, PartialEq, Clone)]
+pub struct MacroExpansion {
+    pub name: String,
+    pub file: Option<String>,
+    pub line: u32,
+}
+
+// Where a line of preprocessed source came from
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SourceLocation {
+    // None when the source did not come from a file
+    pub file: Option<String>,
+    pub line: u32,
+    // Macro invocations that produced this line, outermost first
+    pub expansions: Vec<MacroExpansion>,
+}
+
+impl SourceLocation {
+    pub fn new(line: u32) -> SourceLocation {
+        SourceLocation {
+            file: None,
+            line,
+            expansions: vec![],
+        }
+    }
+
+    pub fn in_file(file: &str, line: u32) -> SourceLocation {
+        SourceLocation {
+            file: Some(file.to_string()),
+            line,
+            expansions: vec![],
+        }
+    }
+}
+
+fn write_position(f: &mut fmt::Formatter, file: &Option<String>, line: u32) -> fmt::Result {
+    match file {
+        Some(file) => write!(f, "{}:{}", file, line),
+        None => write!(f, "line {}", line),
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_position(f, &self.file, self.line)?;
+        for expansion in self.expansions.iter().rev() {
+            write!(f, ", in macro `{}` expanded at ", expansion.name)?;
+            write_position(f, &expansion.file, expansion.line)?;
+        }
+        Ok(())
+    }
+}
+
+// A line of source ready for the nom grammar
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: SourceLocation,
+}
+
+// Macro
+#[derive(Debug, Clone)]
+pub struct Macro {
+    name: String,
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+    location: SourceLocation,
+}
+
+// Expands `.macro name args ... .endm` definitions, `.include "file"` directives and
+// `.if`/`.ifdef`/`.ifndef`/`.else`/`.endif` blocks before the source reaches the parser
+#[derive(Debug, Default)]
+pub struct Preprocessor {
+    macros: HashMap<String, Macro>,
+
+    // Number of expansions so far, used to make `\@` unique
+    expansion_count: u32,
+
+    // Macro currently being defined, if any
+    definition: Option<Macro>,
+
+    // Files currently being read, innermost last, as (path as written, canonical path).
+    // Used to resolve relative includes and to detect include cycles
+    include_stack: Vec<(PathBuf, PathBuf)>,
+
+    // Files marked with .once that have already been read
+    included_once: HashSet<PathBuf>,
+
+    // Constants from .equ and from definitions passed to the assembler
+    constants: HashMap<String, i32>,
+
+    // Open conditional blocks, innermost last
+    conditionals: Vec<Conditional>,
+
+    errors: Vec<AssemblerError>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Preprocessor {
+        Preprocessor {
+            macros: HashMap::new(),
+            expansion_count: 0,
+            definition: None,
+            include_stack: vec![],
+            included_once: HashSet::new(),
+            constants: HashMap::new(),
+            conditionals: vec![],
+            errors: vec![],
+        }
+    }
+
+    // Defines a constant before any source is read, like `-D NAME=value` on the command line
+    pub fn define(&mut self, name: &str, value: i32) {
+        self.constants.insert(name.to_string(), value);
+    }
+
+    // Includes in a source string are resolved relative to the working directory
+    pub fn process(&mut self, raw: &str) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+        let mut output = vec![];
+        self.process_source(raw, None, &mut output);
+        self.finish(output)
+    }
+
+    // Includes in a file are resolved relative to the including file
+    pub fn process_file(&mut self, path: &Path) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+        let mut output = vec![];
+        self.include(path, SourceLocation::default(), &mut output);
+        self.finish(output)
+    }
+
+    fn finish(&mut self, output: Vec<SourceLine>) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+        if let Some(definition) = self.definition.take() {
+            self.error(
+                format!("macro `{}` is missing its .endm", definition.name),
+                definition.location,
+            );
+        }
+        for conditional in std::mem::take(&mut self.conditionals) {
+            self.conditional_error(
+                "conditional block is missing its .endif".to_string(),
+                conditional.location,
+            );
+        }
+
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+        Ok(output)
+    }
+
+    fn process_source(&mut self, raw: &str, file: Option<String>, output: &mut Vec<SourceLine>) {
+        let source = strip_comments(raw);
+        for (index, text) in source.lines().enumerate() {
+            let line = SourceLine {
+                text: text.to_string(),
+                location: SourceLocation {
+                    file: file.clone(),
+                    line: index as u32 + 1,
+                    expansions: vec![],
+                },
+            };
+            self.process_line(line, output);
+        }
+    }
+
+    fn include(&mut self, path: &Path, included_at: SourceLocation, output: &mut Vec<SourceLine>) {
+        let canonical = match fs::canonicalize(path) {
+            Ok(canonical) => canonical,
+            Err(e) => {
+                self.include_error(
+                    format!("unable to open {}: {}", path.display(), e),
+                    included_at,
+                );
+                return;
+            }
+        };
+
+        if self.include_stack.iter().any(|(_, c)| *c == canonical) {
+            let cycle: Vec<String> = self
+                .include_stack
+                .iter()
+                .map(|(p, _)| p.as_path())
+                .chain(std::iter::once(path))
+                .map(|p| p.display().to_string())
+                .collect();
+            self.include_error(
+                format!("include cycle detected: {}", cycle.join(" -> ")),
+                included_at,
+            );
+            return;
+        }
+        if self.included_once.contains(&canonical) {
+            return;
+        }
+
+        let raw = match fs::read_to_string(&canonical) {
+            Ok(raw) => raw,
+            Err(e) => {
+                self.include_error(
+                    format!("unable to read {}: {}", path.display(), e),
+                    included_at,
+                );
+                return;
+            }
+        };
+
+        self.include_stack.push((path.to_path_buf(), canonical));
+        self.process_source(&raw, Some(path.display().to_string()), output);
+        self.include_stack.pop();
+    }
+
+    // Relative paths are looked up next to the file being read
+    fn resolve_include(&self, name: &str) -> PathBuf {
+        let name = Path::new(name);
+        match self.include_stack.last().and_then(|(f, _)| f.parent()) {
+            Some(dir) if name.is_relative() => dir.join(name),
+            _ => name.to_path_buf(),
+        }
+    }
+
+    fn process_line(&mut self, line: SourceLine, output: &mut Vec<SourceLine>) {
+        let (first, rest) = split_first_word(&line.text);
+
+        if self.definition.is_some() {
+            match first {
+                ".endm" => {
+                    let definition = self.definition.take().unwrap();
+                    self.macros.insert(definition.name.clone(), definition);
+                }
+                ".macro" => self.error(
+                    "macros cannot be defined inside another macro".to_string(),
+                    line.location,
+                ),
+                _ => self.definition.as_mut().unwrap().body.push(line),
+            }
+            return;
+        }
+
+        if self.process_conditional(first, rest, &line.location) || !self.is_active() {
+            return;
+        }
+
+        match first {
+            ".macro" => self.begin_definition(rest, line.location),
+            ".equ" => self.define_constant(rest, line.location),
+            ".include" => match unquote(rest) {
+                Some(name) => {
+                    let path = self.resolve_include(name);
+                    self.include(&path, line.location, output);
+                }
+                None => self.include_error(
+                    format!(".include expects a quoted path, found `{}`", rest),
+                    line.location,
+                ),
+            },
+            ".once" => {
+                if let Some((_, canonical)) = self.include_stack.last() {
+                    self.included_once.insert(canonical.clone());
+                }
+            }
+            ".endm" => self.error(
+                ".endm found without a matching .macro".to_string(),
+                line.location,
+            ),
+            _ if self.macros.contains_key(first) => {
+                let name = first.to_string();
+                let args = split_arguments(rest);
+                self.expand(&name, args, &line.location, output);
+            }
+            _ => output.push(line),
+        }
+    }
+
+    // Lines are only kept when every enclosing conditional block is taking its branch
+    fn is_active(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+
+    // Handles .if/.ifdef/.ifndef/.else/.endif, returning true if the line was one of them
+    fn process_conditional(&mut self, first: &str, rest: &str, location: &SourceLocation) -> bool {
+        let active = self.is_active();
+        match first {
+            ".if" | ".ifdef" | ".ifndef" => {
+                // Conditions inside a disabled block are never evaluated
+                let condition = active && self.evaluate_condition(first, rest, location);
+                self.conditionals
+                    .push(Conditional::new(condition, active, location.clone()));
+            }
+            ".else" => match self.conditionals.last_mut() {
+                Some(conditional) if !conditional.seen_else => conditional.flip(),
+                Some(_) => self.conditional_error(
+                    ".else appears twice in the same block".to_string(),
+                    location.clone(),
+                ),
+                None => self.conditional_error(
+                    ".else found without a matching .if".to_string(),
+                    location.clone(),
+                ),
+            },
+            ".endif" => {
+                if self.conditionals.pop().is_none() {
+                    self.conditional_error(
+                        ".endif found without a matching .if".to_string(),
+                        location.clone(),
+                    );
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn evaluate_condition(
+        &mut self,
+        directive: &str,
+        rest: &str,
+        location: &SourceLocation,
+    ) -> bool {
+        if directive == ".if" {
+            return match evaluate(rest, &self.constants) {
+                Ok(value) => value != 0,
+                Err(message) => {
+                    self.conditional_error(message, location.clone());
+                    false
+                }
+            };
+        }
+
+        let name = rest.trim();
+        if !is_identifier(name) {
+            self.conditional_error(
+                format!("{} expects a name, found `{}`", directive, name),
+                location.clone(),
+            );
+            return false;
+        }
+        self.constants.contains_key(name) == (directive == ".ifdef")
+    }
+
+    // .equ NAME, value
+    fn define_constant(&mut self, rest: &str, location: SourceLocation) {
+        let name: String = rest
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect();
+        let expression = rest[name.len()..].trim_start().trim_start_matches(',');
+        if name.is_empty() {
+            self.conditional_error(".equ needs a name".to_string(), location);
+            return;
+        }
+        if self.constants.contains_key(&name) {
+            self.conditional_error(format!("`{}` is already defined", name), location);
+            return;
+        }
+        match evaluate(expression, &self.constants) {
+            Ok(value) => {
+                self.constants.insert(name, value);
+            }
+            Err(message) => self.conditional_error(message, location),
+        }
+    }
+
+    fn begin_definition(&mut self, rest: &str, location: SourceLocation) {
+        let mut words = split_arguments(rest).into_iter();
+        let name = match words.next() {
+            Some(name) => name,
+            None => {
+                self.error(".macro needs a name".to_string(), location);
+                return;
+            }
+        };
+
+        if !is_identifier(&name) {
+            self.error(format!("`{}` is not a valid macro name", name), location);
+            return;
+        }
+        if Opcode::from(CompleteStr(&name)) != Opcode::IGL {
+            self.error(
+                format!("macro `{}` would shadow the opcode of the same name", name),
+                location,
+            );
+            return;
+        }
+        if self.macros.contains_key(&name) {
+            self.error(format!("macro `{}` is already defined", name), location);
+            return;
+        }
+
+        let params: Vec<String> = words.collect();
+        if let Some(param) = params.iter().find(|p| !is_identifier(p)) {
+            self.error(
+                format!("`{}` is not a valid parameter name for `{}`", param, name),
+                location,
+            );
+            return;
+        }
+
+        self.definition = Some(Macro {
+            name,
+            params,
+            body: vec![],
+            location,
+        });
+    }
+
+    fn expand(
+        &mut self,
+        name: &str,
+        args: Vec<String>,
+        invoked_at: &SourceLocation,
+        output: &mut Vec<SourceLine>,
+    ) {
+        if invoked_at.expansions.len() >= MAX_MACRO_DEPTH {
+            self.error(
+                format!(
+                    "macro `{}` nests deeper than {} levels",
+                    name, MAX_MACRO_DEPTH
+                ),
+                invoked_at.clone(),
+            );
+            return;
+        }
+
+        let definition = self.macros[name].clone();
+        if args.len() != definition.params.len() {
+            self.error(
+                format!(
+                    "macro `{}` takes {} argument(s) but {} were given",
+                    name,
+                    definition.params.len(),
+                    args.len()
+                ),
+                invoked_at.clone(),
+            );
+            return;
+        }
+
+        self.expansion_count += 1;
+        let mut expansions = invoked_at.expansions.clone();
+        expansions.push(MacroExpansion {
+            name: name.to_string(),
+            file: invoked_at.file.clone(),
+            line: invoked_at.line,
+        });
+
+        for body_line in definition.body {
+            let location = SourceLocation {
+                file: body_line.location.file.clone(),
+                line: body_line.location.line,
+                expansions: expansions.clone(),
+            };
+            match substitute(
+                &body_line.text,
+                &definition.params,
+                &args,
+                self.expansion_count,
+            ) {
+                Ok(text) => self.process_line(SourceLine { text, location }, output),
+                Err(message) => self.error(message, location),
+            }
+        }
+    }
+
+    fn error(&mut self, message: String, location: SourceLocation) {
+        self.errors
+            .push(AssemblerError::MacroError { message, location });
+    }
+
+    fn conditional_error(&mut self, message: String, location: SourceLocation) {
+        self.errors
+            .push(AssemblerError::ConditionalError { message, location });
+    }
+
+    fn include_error(&mut self, message: String, location: SourceLocation) {
+        self.errors
+            .push(AssemblerError::IncludeError { message, location });
+    }
+}
+
+fn unquote(text: &str) -> Option<&str> {
+    let text = text.trim();
+    if text.len() < 2 {
+        return None;
+    }
+    let (first, last) = (text.chars().next()?, text.chars().last()?);
+    if (first == '"' || first == '\'') && first == last {
+        Some(&text[1..text.len() - 1])
+    } else {
+        None
+    }
+}
+
+// Replaces `\param` with its argument and `\@` with a number unique to this expansion
+fn substitute(
+    text: &str,
+    params: &[String],
+    args: &[String],
+    unique: u32,
+) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        if chars.peek() == Some(&'@') {
+            chars.next();
+            result.push_str(&unique.to_string());
+            continue;
+        }
+
+        let mut name = String::new();
+        while let Some(&next) = chars.peek() {
+            if !(next.is_alphanumeric() || next == '_') {
+                break;
+            }
+            name.push(next);
+            chars.next();
+        }
+        match params.iter().position(|p| *p == name) {
+            Some(index) => result.push_str(&args[index]),
+            None => return Err(format!("unknown macro parameter `\\{}`", name)),
+        }
+    }
+    Ok(result)
+}
+
+// Blanks out comments so directives inside them are ignored, keeping line numbers intact
+pub fn strip_comments(raw: &str) -> String {
+    let mut result = String::with_capacity(raw.len());
+    let mut rest = raw;
+    let mut quote = None;
+    while let Some(c) = rest.chars().next() {
+        if quote.is_none() {
+            if let Ok((after, comment)) =
+                line_comment(CompleteStr(rest)).or_else(|_| block_comment(CompleteStr(rest)))
+            {
+                comment
+                    .chars()
+                    .filter(|c| *c == '\n')
+                    .for_each(|c| result.push(c));
+                rest = after.0;
+                continue;
+            }
+        }
+        match (quote, c) {
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (_, '\n') => quote = None,
+            _ => {}
+        }
+        result.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    result
+}
+
+fn split_first_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(index) => (&text[..index], text[index..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+// Splits macro arguments on commas and whitespace, keeping quoted strings whole
+fn split_arguments(text: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut quote = None;
+    for c in text.chars() {
+        match (quote, c) {
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                current.push(c);
+            }
+            (Some(q), _) if q == c => {
+                quote = None;
+                current.push(c);
+            }
+            (None, ',') => args.push(std::mem::take(&mut current)),
+            (None, c) if c.is_whitespace() => {
+                if !current.is_empty() {
+                    args.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    args.push(current);
+    args.into_iter().filter(|a| !a.is_empty()).collect()
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[SourceLine]) -> Vec<&str> {
+        lines.iter().map(|l| l.text.trim()).collect()
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        let result = strip_comments("load $0 #1 ; one\n/* two\nthree */hlt\n.asciiz 'a;b'");
+        assert_eq!(result, "load $0 #1 \n\nhlt\n.asciiz 'a;b'");
+    }
+
+    #[test]
+    fn test_split_arguments() {
+        assert_eq!(split_arguments("$1, #10"), vec!["$1", "#10"]);
+        assert_eq!(split_arguments("$1 #10"), vec!["$1", "#10"]);
+        assert_eq!(split_arguments("'a, b' $2"), vec!["'a, b'", "$2"]);
+        assert!(split_arguments("").is_empty());
+    }
+
+    #[test]
+    fn test_expand_macro_with_parameters() {
+        let mut preprocessor = Preprocessor::new();
+        let lines = preprocessor
+            .process(".macro set reg, val\nload \\reg #\\val\n.endm\nset $1, 10\nhlt")
+            .unwrap();
+        assert_eq!(texts(&lines), vec!["load $1 #10", "hlt"]);
+        assert_eq!(lines[0].location.line, 2);
+        assert_eq!(
+            lines[0].location.expansions,
+            vec![MacroExpansion {
+                name: "set".to_string(),
+                file: None,
+                line: 4
+            }]
+        );
+        assert_eq!(lines[1].location, SourceLocation::new(5));
+    }
+
+    #[test]
+    fn test_unique_local_labels() {
+        let mut preprocessor = Preprocessor::new();
+        let lines = preprocessor
+            .process(".macro spin\nloop\\@: inc $0\n.endm\nspin\nspin")
+            .unwrap();
+        assert_eq!(texts(&lines), vec!["loop1: inc $0", "loop2: inc $0"]);
+    }
+
+    #[test]
+    fn test_nested_macros() {
+        let mut preprocessor = Preprocessor::new();
+        let lines = preprocessor
+            .process(".macro one r\ninc \\r\n.endm\n.macro two r\none \\r\none \\r\n.endm\ntwo $3")
+            .unwrap();
+        assert_eq!(texts(&lines), vec!["inc $3", "inc $3"]);
+        assert_eq!(lines[0].location.line, 2);
+        assert_eq!(
+            lines[0].location.to_string(),
+            "line 2, in macro `one` expanded at line 5, in macro `two` expanded at line 8"
+        );
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let mut preprocessor = Preprocessor::new();
+        let result = preprocessor.process(".macro set reg\nload \\reg #1\n.endm\nset $1, $2");
+        assert!(result.is_err());
+
+        let mut preprocessor = Preprocessor::new();
+        let result = preprocessor.process(".macro forever\nforever\n.endm\nforever");
+        assert!(result.is_err());
+
+        let mut preprocessor = Preprocessor::new();
+        let result = preprocessor.process(".macro load\nhlt\n.endm");
+        assert!(result.is_err());
+
+        let mut preprocessor = Preprocessor::new();
+        let result = preprocessor.process(".macro open\nhlt\n");
+        assert!(result.is_err());
+
+        let mut preprocessor = Preprocessor::new();
+        let result = preprocessor.process(".macro bad\nload \\missing #1\n.endm\nbad");
+        match result {
+            Err(errors) => match &errors[0] {
+                AssemblerError::MacroError { location, .. } => {
+                    assert_eq!(location.line, 2);
+                    assert_eq!(location.expansions.len(), 1);
+                }
+                e => panic!("unexpected error {:?}", e),
+            },
+            Ok(_) => panic!("unknown parameter was accepted"),
+        }
+    }
+
+    // Writes the given files into a fresh directory under the system temp dir
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iridation-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, content) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_include_relative_to_including_file() {
+        let dir = write_files(
+            "include",
+            &[
+                ("main.iasm", ".include \"lib/util.iasm\"\nbump $1\nhlt"),
+                (
+                    "lib/util.iasm",
+                    ".include 'inner.iasm'\n.macro bump r\ninc \\r\n.endm",
+                ),
+                ("lib/inner.iasm", "load $0 #1"),
+            ],
+        );
+        let mut preprocessor = Preprocessor::new();
+        let lines = preprocessor.process_file(&dir.join("main.iasm")).unwrap();
+        assert_eq!(texts(&lines), vec!["load $0 #1", "inc $1", "hlt"]);
+
+        let inner = lines[0].location.file.as_ref().unwrap();
+        assert!(inner.ends_with("inner.iasm"));
+        assert_eq!(lines[0].location.line, 1);
+        let expanded = &lines[1].location;
+        assert!(expanded.file.as_ref().unwrap().ends_with("util.iasm"));
+        assert_eq!(expanded.line, 3);
+        assert!(expanded.expansions[0]
+            .file
+            .as_ref()
+            .unwrap()
+            .ends_with("main.iasm"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_once() {
+        let dir = write_files(
+            "include-once",
+            &[
+                ("main.iasm", ".include \"a.iasm\"\n.include \"a.iasm\"\nhlt"),
+                ("a.iasm", ".once\ninc $0"),
+            ],
+        );
+        let mut preprocessor = Preprocessor::new();
+        let lines = preprocessor.process_file(&dir.join("main.iasm")).unwrap();
+        assert_eq!(texts(&lines), vec!["inc $0", "hlt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = write_files(
+            "include-errors",
+            &[
+                ("a.iasm", "inc $0\n.include \"b.iasm\""),
+                ("b.iasm", ".include \"a.iasm\""),
+                ("missing.iasm", "hlt\n.include \"nowhere.iasm\""),
+            ],
+        );
+        let mut preprocessor = Preprocessor::new();
+        let errors = preprocessor.process_file(&dir.join("a.iasm")).unwrap_err();
+        match &errors[0] {
+            AssemblerError::IncludeError { message, location } => {
+                assert!(message.starts_with("include cycle detected"));
+                assert!(location.file.as_ref().unwrap().ends_with("b.iasm"));
+                assert_eq!(location.line, 1);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let mut preprocessor = Preprocessor::new();
+        let errors = preprocessor
+            .process_file(&dir.join("missing.iasm"))
+            .unwrap_err();
+        match &errors[0] {
+            AssemblerError::IncludeError { location, .. } => assert_eq!(location.line, 2),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let mut preprocessor = Preprocessor::new();
+        assert!(preprocessor.process(".include nowhere.iasm").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = ".equ LEVEL, 2\n.ifdef DEBUG\nload $0 #1\n.else\nload $0 #2\n.endif\n.if LEVEL > 1\ninc $0\n.ifndef RELEASE\ninc $1\n.endif\n.else\ndec $0\n.endif";
+        let mut preprocessor = Preprocessor::new();
+        let lines = preprocessor.process(source).unwrap();
+        assert_eq!(texts(&lines), vec!["load $0 #2", "inc $0", "inc $1"]);
+
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.define("DEBUG", 1);
+        preprocessor.define("RELEASE", 1);
+        let lines = preprocessor.process(source).unwrap();
+        assert_eq!(texts(&lines), vec!["load $0 #1", "inc $0"]);
+    }
+
+    #[test]
+    fn test_disabled_blocks_are_skipped() {
+        // Nothing inside a disabled block is looked at, not even broken expressions or includes
+        let source = ".if 0\n.include \"nowhere.iasm\"\n.if UNDEFINED\n.macro nope\n.endif\n.else\nhlt\n.endif";
+        let mut preprocessor = Preprocessor::new();
+        let lines = preprocessor.process(source).unwrap();
+        assert_eq!(texts(&lines), vec!["hlt"]);
+    }
+
+    #[test]
+    fn test_conditionals_in_macros() {
+        let source = ".macro log r\n.ifdef DEBUG\nprts \\r\n.endif\n.endm\nlog @one\n.equ DEBUG, 1\nlog @two";
+        let mut preprocessor = Preprocessor::new();
+        let lines = preprocessor.process(source).unwrap();
+        assert_eq!(texts(&lines), vec!["prts @two"]);
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        for source in [
+            ".if 1\nhlt",
+            ".endif",
+            ".else",
+            ".if 1\n.else\n.else\n.endif",
+            ".if MISSING\n.endif",
+            ".ifdef\n.endif",
+            ".equ A, 1\n.equ A, 2",
+        ] {
+            let mut preprocessor = Preprocessor::new();
+            match preprocessor.process(source) {
+                Err(errors) => {
+                    assert!(matches!(errors[0], AssemblerError::ConditionalError { .. }))
+                }
+                Ok(_) => panic!("{:?} was accepted", source),
+            }
+        }
+    }
+}
@@ -1,4 +1,7 @@
+use super::base_assembler::AssemblerError;
+use super::comment_parsers::blank;
 use super::instruction_parser::{instruction, AssemblerInstruction};
+use super::preprocessor::SourceLocation;
 use super::SymbolTable;
 use nom::types::CompleteStr;
 
@@ -8,18 +11,23 @@ pub struct Program {
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    // Encodes the program without a symbol table, so any label operand is an error. Its
+    // location is the position of the instruction, counting from 1.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AssemblerError> {
         let mut program = vec![];
-        for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(&SymbolTable::new()));
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let location = SourceLocation::new(index as u32 + 1);
+            program.append(&mut instruction.to_bytes(&SymbolTable::new(), &location)?);
         }
-        program
+        Ok(program)
     }
 }
 
+// Blank lines and comments may appear before, between and after instructions
 named!(pub program<CompleteStr,Program>,
     do_parse!(
-        instructions:  many1!(instruction) >>
+        instructions: many0!(preceded!(blank, instruction)) >>
+        blank >>
         (
             Program{
                 instructions
@@ -45,9 +53,16 @@ mod tests {
         let result = program(CompleteStr("load $0 #100\n"));
         assert!(result.is_ok());
         let (_, program) = result.unwrap();
-        let bytecode = program.to_bytes();
+        let bytecode = program.to_bytes().unwrap();
         assert_eq!(bytecode.len(), 4);
         print!("{:?}", bytecode);
+
+        // Without a symbol table no label has an offset
+        let (_, labelled) = self::program(CompleteStr("load $0 #1\nload $1 @nowhere\n")).unwrap();
+        assert_eq!(
+            labelled.to_bytes().unwrap_err().to_string(),
+            "Label nowhere is never declared (line 2)"
+        );
     }
 
     #[test]
@@ -56,5 +71,26 @@ mod tests {
             ".data\nhello: .asciiz 'Hello Everyone!'\n .code\n hlt",
         ));
         assert!(result.is_ok());
+        let (leftover, p) = result.unwrap();
+        assert_eq!(leftover, CompleteStr(""));
+        assert_eq!(4, p.instructions.len());
+    }
+
+    #[test]
+    fn test_program_with_comments_and_blank_lines() {
+        let result = program(CompleteStr(
+            "; header comment\n\n.data\n/* a block\n   comment */\n.code   \n\n  load $0 #1 ; trailing\n#! shebang style\n\thlt\n\n",
+        ));
+        let (leftover, p) = result.unwrap();
+        assert_eq!(leftover, CompleteStr(""));
+        assert_eq!(4, p.instructions.len());
+    }
+
+    #[test]
+    fn test_program_stops_at_unparseable_line() {
+        let result = program(CompleteStr("load $0 #1\n\n%%% nonsense\nhlt"));
+        let (leftover, p) = result.unwrap();
+        assert_eq!(leftover, CompleteStr("%%% nonsense\nhlt"));
+        assert_eq!(1, p.instructions.len());
     }
 }
@@ -0,0 +1,124 @@
+// Little-endian encoding shared by object files, archives, debug info and snapshots. Runs of
+// bytes carry a u32 length, snapshots use the long_ variants with a u64 length.
+
+// Why a read failed, each format turns it into its own error
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum CodecError {
+    Truncated,
+    InvalidString,
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+// Length prefixed run of bytes
+pub(crate) fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+pub(crate) fn write_long_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u64(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, position: 0 }
+    }
+
+    pub(crate) fn take(&mut self, length: usize) -> Result<&'a [u8], CodecError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(CodecError::Truncated)?;
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, CodecError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, CodecError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], CodecError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    pub(crate) fn long_bytes(&mut self) -> Result<&'a [u8], CodecError> {
+        let length = usize::try_from(self.u64()?).map_err(|_| CodecError::Truncated)?;
+        self.take(length)
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, CodecError> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| CodecError::InvalidString)
+    }
+
+    pub(crate) fn long_string(&mut self) -> Result<String, CodecError> {
+        let bytes = self.long_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| CodecError::InvalidString)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut out = vec![];
+        write_u32(&mut out, 7);
+        write_u64(&mut out, u64::MAX);
+        write_bytes(&mut out, b"short");
+        write_long_bytes(&mut out, "long".as_bytes());
+        out.push(3);
+
+        let mut reader = Reader::new(&out);
+        assert_eq!(reader.u32(), Ok(7));
+        assert_eq!(reader.u64(), Ok(u64::MAX));
+        assert_eq!(reader.string(), Ok("short".to_string()));
+        assert_eq!(reader.long_string(), Ok("long".to_string()));
+        assert_eq!(reader.u8(), Ok(3));
+        assert_eq!(reader.u8(), Err(CodecError::Truncated));
+    }
+
+    #[test]
+    fn test_bad_lengths_and_strings() {
+        let mut out = vec![];
+        write_u32(&mut out, 100);
+        assert_eq!(Reader::new(&out).bytes(), Err(CodecError::Truncated));
+
+        let mut out = vec![];
+        write_u64(&mut out, u64::MAX);
+        assert_eq!(Reader::new(&out).long_bytes(), Err(CodecError::Truncated));
+
+        let mut out = vec![];
+        write_bytes(&mut out, &[0xff, 0xfe]);
+        assert_eq!(Reader::new(&out).string(), Err(CodecError::InvalidString));
+    }
+}
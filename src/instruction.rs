@@ -1,5 +1,8 @@
 use nom::types::CompleteStr;
 
+// Third byte of `hlt $r`, a plain `hlt` exits with status 0
+pub const HLT_STATUS_FROM_REGISTER: u8 = 1;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Opcode {
     HLT,
@@ -25,16 +28,97 @@ pub enum Opcode {
     INC,
     DEC,
     PTRS,
+    // Calls a function provided by the host
+    CALLH,
+    // Green threads
+    SPAWN,
+    YIELD,
+    JOIN,
+    // Channels between green threads
+    CHAN,
+    SEND,
+    SENDB,
+    RECV,
+    TRYRECV,
+    // Standard input
+    READL,
+    READB,
+    READI,
+}
+
+impl Opcode {
+    // The name used in assembly
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Opcode::HLT => "hlt",
+            Opcode::IGL => "igl",
+            Opcode::LOAD => "load",
+            Opcode::ADD => "add",
+            Opcode::SUB => "sub",
+            Opcode::MUL => "mul",
+            Opcode::DIV => "div",
+            Opcode::JMP => "jmp",
+            Opcode::JMPB => "jmpb",
+            Opcode::JMPF => "jmpf",
+            Opcode::EQ => "eq",
+            Opcode::NEQ => "neq",
+            Opcode::GT => "gt",
+            Opcode::LT => "lt",
+            Opcode::GTQ => "gtq",
+            Opcode::LTQ => "ltq",
+            Opcode::JEQ => "jeq",
+            Opcode::JNEQ => "jneq",
+            Opcode::NOP => "nop",
+            Opcode::AlOC => "aloc",
+            Opcode::INC => "inc",
+            Opcode::DEC => "dec",
+            Opcode::PTRS => "prts",
+            Opcode::CALLH => "call_host",
+            Opcode::SPAWN => "spawn",
+            Opcode::YIELD => "yield",
+            Opcode::JOIN => "join",
+            Opcode::CHAN => "chan",
+            Opcode::SEND => "send",
+            Opcode::SENDB => "send_buf",
+            Opcode::RECV => "recv",
+            Opcode::TRYRECV => "try_recv",
+            Opcode::READL => "read_line",
+            Opcode::READB => "read_byte",
+            Opcode::READI => "read_int",
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+// A decoded instruction: the opcode and the three operand bytes that follow it
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
-    opcode: Opcode,
+    pub opcode: Opcode,
+    pub operands: [u8; 3],
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode }
+        Instruction {
+            opcode,
+            operands: [0; 3],
+        }
+    }
+
+    // Decodes the four bytes of an instruction
+    pub fn decode(bytes: &[u8]) -> Instruction {
+        Instruction {
+            opcode: Opcode::from(bytes[0]),
+            operands: [bytes[1], bytes[2], bytes[3]],
+        }
+    }
+
+    pub fn register(&self, operand: usize) -> usize {
+        self.operands[operand] as usize
+    }
+
+    // The big-endian 16 bit number starting at an operand
+    pub fn number(&self, operand: usize) -> u16 {
+        ((self.operands[operand] as u16) << 8) | self.operands[operand + 1] as u16
     }
 }
 
@@ -62,6 +146,18 @@ impl From<u8> for Opcode {
             18 => Opcode::INC,
             19 => Opcode::DEC,
             20 => Opcode::PTRS,
+            21 => Opcode::CALLH,
+            22 => Opcode::SPAWN,
+            23 => Opcode::YIELD,
+            24 => Opcode::JOIN,
+            25 => Opcode::CHAN,
+            26 => Opcode::SEND,
+            27 => Opcode::SENDB,
+            28 => Opcode::RECV,
+            29 => Opcode::TRYRECV,
+            30 => Opcode::READL,
+            31 => Opcode::READB,
+            32 => Opcode::READI,
             254 => Opcode::HLT,
             _ => Opcode::IGL,
         }
@@ -92,6 +188,18 @@ impl From<Opcode> for u8 {
             Opcode::INC => 18,
             Opcode::DEC => 19,
             Opcode::PTRS => 20,
+            Opcode::CALLH => 21,
+            Opcode::SPAWN => 22,
+            Opcode::YIELD => 23,
+            Opcode::JOIN => 24,
+            Opcode::CHAN => 25,
+            Opcode::SEND => 26,
+            Opcode::SENDB => 27,
+            Opcode::RECV => 28,
+            Opcode::TRYRECV => 29,
+            Opcode::READL => 30,
+            Opcode::READB => 31,
+            Opcode::READI => 32,
             Opcode::IGL => 255, // For illegal instructions, we could use a sentinel value like 255
             Opcode::HLT => 254,
         }
@@ -125,6 +233,18 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             CompleteStr("inc") => Opcode::INC,
             CompleteStr("dec") => Opcode::DEC,
             CompleteStr("prts") => Opcode::PTRS,
+            CompleteStr("call_host") => Opcode::CALLH,
+            CompleteStr("spawn") => Opcode::SPAWN,
+            CompleteStr("yield") => Opcode::YIELD,
+            CompleteStr("join") => Opcode::JOIN,
+            CompleteStr("chan") => Opcode::CHAN,
+            CompleteStr("send") => Opcode::SEND,
+            CompleteStr("send_buf") => Opcode::SENDB,
+            CompleteStr("recv") => Opcode::RECV,
+            CompleteStr("try_recv") => Opcode::TRYRECV,
+            CompleteStr("read_line") => Opcode::READL,
+            CompleteStr("read_byte") => Opcode::READB,
+            CompleteStr("read_int") => Opcode::READI,
             _ => Opcode::IGL,
         }
     }
@@ -146,11 +266,21 @@ mod tests {
         assert_eq!(instruct.opcode, Opcode::HLT);
     }
 
+    #[test]
+    fn test_decode_instruction() {
+        let instruction = Instruction::decode(&[0, 3, 1, 244]);
+        assert_eq!(instruction.opcode, Opcode::LOAD);
+        assert_eq!(instruction.register(0), 3);
+        assert_eq!(instruction.number(1), 500);
+    }
+
     #[test]
     fn test_str_to_opcode() {
         let opcode = Opcode::from(CompleteStr("load"));
         assert_eq!(opcode, Opcode::LOAD);
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
+        let opcode = Opcode::from(CompleteStr("call_host"));
+        assert_eq!(opcode.mnemonic(), "call_host");
     }
 }
@@ -0,0 +1,67 @@
+//! Iridation: an assembler, linker and register based virtual machine.
+//!
+//! Host applications embed the interpreter through [`Vm`]:
+//!
+//! - [`Vm::from_image`] loads a linked image, verifies its bytecode and pre-decodes it.
+//!   [`Vm::from_image_with_hosts`] also binds the functions programs call with `call_host`,
+//!   registered in [`HostFunctions`]. They take `$0` to `$3` and their result goes in `$0`.
+//! - `vm.registers` holds the 32 registers, readable and writable before and after a run.
+//!   [`Vm::set_arguments`] copies arguments and environment variables into the heap, within
+//!   the heap limit.
+//! - `read_line`, `read_byte` and `read_int` read stdin, or whatever [`Vm::set_input`] gives.
+//! - [`Vm::heap`], [`Vm::read_heap`] and [`Vm::write_heap`] give access to the heap.
+//! - [`Vm::run_with_limits`] runs until the program halts, faults or reaches a
+//!   [`Limits`] bound. A program stopped by a limit can be run again.
+//! - [`Vm::error`], [`Vm::error_report`] and [`Vm::backtrace`] describe a fault.
+//! - [`VmPool`] runs many VMs at once on operating system threads. Within one VM, programs
+//!   start green threads with `spawn`, and [`Vm::time_slice`] sets how long each runs.
+//! - [`Network`] registers `tcp_` host functions for TCP connections. They block, stalling
+//!   every green thread of the VM while they wait. `lib/http.iasm` builds a minimal HTTP/1.1
+//!   client and server on them.
+//! - [`Filesystem`] registers `fs_` host functions for files under a root directory, which
+//!   may be read-only.
+//!
+//! Images come from [`Assembler::assemble`], or from the linker for programs made of
+//! several object files.
+//!
+//! ```
+//! use iridation::{Assembler, Limits, RunOutcome, Vm};
+//!
+//! let image = Assembler::new()
+//!     .assemble(".data\n.code\nload $1 #8\naloc $1\nadd $0 $1 $2\nhlt $2\n")
+//!     .unwrap();
+//! let mut vm = Vm::from_image(image).unwrap();
+//! vm.registers[0] = 34;
+//!
+//! let limits = Limits {
+//!     instructions: Some(1000),
+//!     ..Limits::default()
+//! };
+//! assert_eq!(vm.run_with_limits(limits), RunOutcome::Exited { status: 42 });
+//!
+//! vm.write_heap(0, b"iridation").unwrap_err();
+//! vm.write_heap(0, b"embedded").unwrap();
+//! assert_eq!(vm.read_heap(0, 8).unwrap(), b"embedded");
+//! assert!(vm.error().is_none());
+//! ```
+
+#[macro_use]
+extern crate nom;
+
+#[macro_use]
+extern crate log;
+
+pub mod assembler;
+mod codec;
+pub mod instruction;
+pub mod linker;
+pub mod repl;
+pub mod verifier;
+pub mod vm;
+
+pub use assembler::base_assembler::Assembler;
+pub use vm::files::Filesystem;
+pub use vm::host::HostFunctions;
+pub use vm::net::Network;
+pub use vm::pool::VmPool;
+pub use vm::{LimitExceeded, Limits, LoadError, RunOutcome, Vm, VmError};
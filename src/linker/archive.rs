@@ -0,0 +1,260 @@
+use crate::assembler::object::{ObjectFile, ObjectFileError};
+use crate::codec::{write_bytes, write_u32, CodecError, Reader};
+use std::collections::HashMap;
+use std::fmt;
+
+// Constants
+pub const ARCHIVE_PREFIX: [u8; 4] = [73, 82, 65, 82]; // "IRAR"
+pub const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ArchiveError {
+    NotAnArchive,
+    UnsupportedVersion {
+        version: u32,
+    },
+    Truncated,
+    DuplicateMember {
+        name: String,
+    },
+    // Two members export the same symbol, so the index could not tell which one to use
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    InvalidIndex {
+        name: String,
+    },
+    InvalidMember {
+        name: String,
+        error: ObjectFileError,
+    },
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::NotAnArchive => write!(f, "not an Iridation archive"),
+            ArchiveError::UnsupportedVersion { version } => {
+                write!(f, "unsupported archive version {}", version)
+            }
+            ArchiveError::Truncated => write!(f, "archive is truncated"),
+            ArchiveError::DuplicateMember { name } => {
+                write!(f, "archive already has a member called {}", name)
+            }
+            ArchiveError::DuplicateSymbol {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "symbol {} is exported by both {} and {}",
+                name, first, second
+            ),
+            ArchiveError::InvalidIndex { name } => {
+                write!(f, "archive index does not match its members for {}", name)
+            }
+            ArchiveError::InvalidMember { name, error } => {
+                write!(f, "archive member {}: {}", name, error)
+            }
+        }
+    }
+}
+
+// Undecodable strings were always reported as truncation
+impl From<CodecError> for ArchiveError {
+    fn from(_: CodecError) -> ArchiveError {
+        ArchiveError::Truncated
+    }
+}
+
+// A bundle of objects with an index from every exported symbol to the member defining it
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Archive {
+    members: Vec<(String, ObjectFile)>,
+    index: HashMap<String, usize>,
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Archive::default()
+    }
+
+    pub fn add_member(&mut self, name: &str, object: ObjectFile) -> Result<(), ArchiveError> {
+        if self.members.iter().any(|(member, _)| member == name) {
+            return Err(ArchiveError::DuplicateMember {
+                name: name.to_string(),
+            });
+        }
+        for symbol in object.exports() {
+            if let Some(owner) = self.index.get(symbol) {
+                return Err(ArchiveError::DuplicateSymbol {
+                    name: symbol.to_string(),
+                    first: self.members[*owner].0.clone(),
+                    second: name.to_string(),
+                });
+            }
+        }
+
+        let position = self.members.len();
+        for symbol in object.exports() {
+            self.index.insert(symbol.to_string(), position);
+        }
+        self.members.push((name.to_string(), object));
+        Ok(())
+    }
+
+    pub fn members(&self) -> &[(String, ObjectFile)] {
+        &self.members
+    }
+
+    // The member that exports a symbol, if any
+    pub fn find(&self, symbol: &str) -> Option<(usize, &str, &ObjectFile)> {
+        self.index.get(symbol).map(|position| {
+            let (name, object) = &self.members[*position];
+            (*position, name.as_str(), object)
+        })
+    }
+
+    pub fn is_archive(bytes: &[u8]) -> bool {
+        bytes.len() >= 4 && bytes[0..4] == ARCHIVE_PREFIX
+    }
+
+    // The symbol index is written first so a linker can look symbols up without decoding members
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = ARCHIVE_PREFIX.to_vec();
+        write_u32(&mut out, ARCHIVE_VERSION);
+
+        let mut index: Vec<(&String, &usize)> = self.index.iter().collect();
+        index.sort();
+        write_u32(&mut out, index.len() as u32);
+        for (symbol, position) in index {
+            write_bytes(&mut out, symbol.as_bytes());
+            write_u32(&mut out, *position as u32);
+        }
+
+        write_u32(&mut out, self.members.len() as u32);
+        for (name, object) in &self.members {
+            write_bytes(&mut out, name.as_bytes());
+            write_bytes(&mut out, &object.to_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Archive, ArchiveError> {
+        if !Archive::is_archive(bytes) {
+            return Err(ArchiveError::NotAnArchive);
+        }
+        let mut reader = Reader::new(&bytes[ARCHIVE_PREFIX.len()..]);
+        let version = reader.u32()?;
+        if version != ARCHIVE_VERSION {
+            return Err(ArchiveError::UnsupportedVersion { version });
+        }
+
+        let mut index = HashMap::new();
+        for _ in 0..reader.u32()? {
+            let symbol = reader.string()?;
+            let position = reader.u32()? as usize;
+            index.insert(symbol, position);
+        }
+
+        let mut archive = Archive::new();
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let object = ObjectFile::from_bytes(reader.bytes()?).map_err(|error| {
+                ArchiveError::InvalidMember {
+                    name: name.clone(),
+                    error,
+                }
+            })?;
+            archive.add_member(&name, object)?;
+        }
+
+        // The stored index must agree with what the members actually export
+        for (symbol, position) in &index {
+            if archive.index.get(symbol) != Some(position) {
+                return Err(ArchiveError::InvalidIndex {
+                    name: symbol.clone(),
+                });
+            }
+        }
+        if let Some(symbol) = archive.index.keys().find(|s| !index.contains_key(*s)) {
+            return Err(ArchiveError::InvalidIndex {
+                name: symbol.clone(),
+            });
+        }
+        Ok(archive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+
+    fn object(source: &str) -> ObjectFile {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    fn sample() -> Archive {
+        let mut archive = Archive::new();
+        archive
+            .add_member(
+                "math",
+                object(".data\n.code\n.global double\ndouble: add $0 $0 $0\n"),
+            )
+            .unwrap();
+        archive
+            .add_member(
+                "io",
+                object(".data\nhi: .asciiz 'hi'\n.code\n.global greet\ngreet: prts @hi\n"),
+            )
+            .unwrap();
+        archive
+    }
+
+    #[test]
+    fn test_archive_index() {
+        let archive = sample();
+        assert_eq!(archive.find("greet").map(|(_, name, _)| name), Some("io"));
+        assert_eq!(archive.find("double").map(|(i, _, _)| i), Some(0));
+        assert!(archive.find("hi").is_none());
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let archive = sample();
+        let bytes = archive.to_bytes();
+        assert!(Archive::is_archive(&bytes));
+        assert_eq!(Archive::from_bytes(&bytes), Ok(archive));
+        assert_eq!(
+            Archive::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ArchiveError::Truncated)
+        );
+        assert_eq!(
+            Archive::from_bytes(b"IROB"),
+            Err(ArchiveError::NotAnArchive)
+        );
+    }
+
+    #[test]
+    fn test_archive_rejects_duplicates() {
+        let mut archive = sample();
+        let again = object(".data\n.code\n.global double\ndouble: hlt\n");
+        assert_eq!(
+            archive.add_member("math", again.clone()),
+            Err(ArchiveError::DuplicateMember {
+                name: "math".to_string()
+            })
+        );
+        assert_eq!(
+            archive.add_member("fast_math", again),
+            Err(ArchiveError::DuplicateSymbol {
+                name: "double".to_string(),
+                first: "math".to_string(),
+                second: "fast_math".to_string()
+            })
+        );
+    }
+}
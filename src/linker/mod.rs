@@ -0,0 +1,461 @@
+pub mod archive;
+
+use crate::assembler::debug_info::{DebugInfo, DebugLabel};
+use crate::assembler::object::{ObjectFile, ObjectSection, ObjectSymbol, SymbolBinding};
+use crate::assembler::{pie_append_debug, pie_append_imports, pie_header, pie_ro_padding};
+use archive::Archive;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LinkerError {
+    NoObjects,
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    UndefinedSymbol {
+        name: String,
+        module: String,
+    },
+    AddressOutOfRange {
+        name: String,
+        address: u32,
+    },
+    InvalidRelocation {
+        module: String,
+        offset: u32,
+    },
+}
+
+impl fmt::Display for LinkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkerError::NoObjects => write!(f, "Nothing to link"),
+            LinkerError::DuplicateSymbol {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "Symbol {} is exported by both {} and {}",
+                name, first, second
+            ),
+            LinkerError::UndefinedSymbol { name, module } => {
+                write!(f, "Undefined symbol {} referenced from {}", name, module)
+            }
+            LinkerError::AddressOutOfRange { name, address } => write!(
+                f,
+                "Symbol {} ends up at {:#x}, which does not fit in a 16 bit operand",
+                name, address
+            ),
+            LinkerError::InvalidRelocation { module, offset } => write!(
+                f,
+                "Relocation at offset {} in {} is outside its code section",
+                offset, module
+            ),
+        }
+    }
+}
+
+// Where every object ends up in the final image
+struct Layout {
+    // Offset of each object's read-only data inside the image's ro section
+    ro_bases: Vec<u32>,
+    // Absolute pc of each object's first instruction
+    code_bases: Vec<u32>,
+}
+
+// Merges separately assembled objects into a single PIE image.
+// Execution starts at the first instruction of the first object added.
+#[derive(Debug, Default)]
+pub struct Linker {
+    objects: Vec<(String, ObjectFile)>,
+    archives: Vec<(String, Archive)>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker {
+            objects: vec![],
+            archives: vec![],
+        }
+    }
+
+    // The name is only used in error messages, usually it is the file the object came from
+    pub fn add_object(&mut self, name: &str, object: ObjectFile) {
+        self.objects.push((name.to_string(), object));
+    }
+
+    // Archive members are only linked in when they define a symbol something else imports
+    pub fn add_archive(&mut self, name: &str, archive: Archive) {
+        self.archives.push((name.to_string(), archive));
+    }
+
+    pub fn link(&self) -> Result<Vec<u8>, Vec<LinkerError>> {
+        if self.objects.is_empty() {
+            return Err(vec![LinkerError::NoObjects]);
+        }
+
+        let objects = self.with_archive_members();
+        let mut errors = vec![];
+        let layout = layout(&objects);
+        let globals = collect_globals(&objects, &mut errors);
+
+        let mut ro = vec![];
+        let mut code = vec![];
+        // Host functions called anywhere in the image, each listed once
+        let mut imports: Vec<String> = vec![];
+        for (index, (module, object)) in objects.iter().enumerate() {
+            ro.extend_from_slice(&object.ro);
+            let start = code.len();
+            code.extend_from_slice(&object.code);
+
+            for host_call in &object.host_calls {
+                if host_call.offset as usize + 2 > object.code.len() {
+                    errors.push(LinkerError::InvalidRelocation {
+                        module: module.clone(),
+                        offset: host_call.offset,
+                    });
+                    continue;
+                }
+                let import = match imports.iter().position(|name| *name == host_call.symbol) {
+                    Some(import) => import,
+                    None => {
+                        imports.push(host_call.symbol.clone());
+                        imports.len() - 1
+                    }
+                };
+                let position = start + host_call.offset as usize;
+                code[position] = (import >> 8) as u8;
+                code[position + 1] = import as u8;
+            }
+
+            for relocation in &object.relocations {
+                let position = start + relocation.offset as usize;
+                if relocation.offset as usize + 2 > object.code.len() {
+                    errors.push(LinkerError::InvalidRelocation {
+                        module: module.clone(),
+                        offset: relocation.offset,
+                    });
+                    continue;
+                }
+
+                let address = match object.symbol(&relocation.symbol) {
+                    Some(symbol) if symbol.section != ObjectSection::Undefined => {
+                        Some(address(&layout, index, symbol))
+                    }
+                    _ => globals
+                        .get(relocation.symbol.as_str())
+                        .map(|(owner, symbol)| address(&layout, *owner, symbol)),
+                };
+                match address {
+                    Some(address) if address > u16::MAX as u32 => {
+                        errors.push(LinkerError::AddressOutOfRange {
+                            name: relocation.symbol.clone(),
+                            address,
+                        })
+                    }
+                    Some(address) => {
+                        code[position] = (address >> 8) as u8;
+                        code[position + 1] = address as u8;
+                    }
+                    None => errors.push(LinkerError::UndefinedSymbol {
+                        name: relocation.symbol.clone(),
+                        module: module.clone(),
+                    }),
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        ro.resize(ro.len() + pie_ro_padding(ro.len()), 0);
+        let mut image = pie_header(ro.len() as u32);
+        image.append(&mut ro);
+        image.append(&mut code);
+        if !imports.is_empty() {
+            pie_append_imports(&mut image, &imports);
+        }
+        if let Some(debug) = debug_info(&objects, &layout) {
+            pie_append_debug(&mut image, &debug.to_bytes());
+        }
+        Ok(image)
+    }
+
+    // The objects that were added, followed by every archive member needed to resolve imports.
+    // Archives are searched in the order they were added and each member is pulled in once,
+    // members pulled in may import symbols from further members.
+    fn with_archive_members(&self) -> Vec<(String, &ObjectFile)> {
+        let mut objects: Vec<(String, &ObjectFile)> = self
+            .objects
+            .iter()
+            .map(|(name, object)| (name.clone(), object))
+            .collect();
+        let mut pulled: HashSet<(usize, usize)> = HashSet::new();
+
+        let mut next = 0;
+        while next < objects.len() {
+            let defined: HashSet<&str> = objects.iter().flat_map(|(_, o)| o.exports()).collect();
+            let wanted: Vec<&str> = objects[next]
+                .1
+                .imports()
+                .filter(|name| !defined.contains(name))
+                .collect();
+            for name in wanted {
+                for (archive_index, (file, archive)) in self.archives.iter().enumerate() {
+                    if let Some((member, member_name, object)) = archive.find(name) {
+                        if pulled.insert((archive_index, member)) {
+                            objects.push((format!("{}({})", file, member_name), object));
+                        }
+                        break;
+                    }
+                }
+            }
+            next += 1;
+        }
+        objects
+    }
+}
+
+fn layout(objects: &[(String, &ObjectFile)]) -> Layout {
+    let total_ro: usize = objects.iter().map(|(_, o)| o.ro.len()).sum();
+    let mut ro_base = 0;
+    let mut code_base = (pie_header(0).len() + total_ro + pie_ro_padding(total_ro)) as u32;
+    let mut layout = Layout {
+        ro_bases: vec![],
+        code_bases: vec![],
+    };
+    for (_, object) in objects {
+        layout.ro_bases.push(ro_base);
+        layout.code_bases.push(code_base);
+        ro_base += object.ro.len() as u32;
+        code_base += object.code.len() as u32;
+    }
+    layout
+}
+
+// Every exported symbol, with the index of the object defining it
+fn collect_globals<'a>(
+    objects: &'a [(String, &ObjectFile)],
+    errors: &mut Vec<LinkerError>,
+) -> HashMap<&'a str, (usize, &'a ObjectSymbol)> {
+    let mut globals: HashMap<&str, (usize, &ObjectSymbol)> = HashMap::new();
+    for (index, (module, object)) in objects.iter().enumerate() {
+        for symbol in &object.symbols {
+            if symbol.binding != SymbolBinding::Global {
+                continue;
+            }
+            match globals.get(symbol.name.as_str()) {
+                Some((owner, _)) => errors.push(LinkerError::DuplicateSymbol {
+                    name: symbol.name.clone(),
+                    first: objects[*owner].0.clone(),
+                    second: module.clone(),
+                }),
+                None => {
+                    globals.insert(&symbol.name, (index, symbol));
+                }
+            }
+        }
+    }
+    globals
+}
+
+// Line tables of the objects that have one, with the names of their code labels.
+// None when no object was assembled with debug info.
+fn debug_info(objects: &[(String, &ObjectFile)], layout: &Layout) -> Option<DebugInfo> {
+    let mut merged: Option<DebugInfo> = None;
+    for (index, (_, object)) in objects.iter().enumerate() {
+        let mut debug = match &object.debug {
+            Some(debug) => debug.clone(),
+            None => continue,
+        };
+        for symbol in &object.symbols {
+            if symbol.section == ObjectSection::Code {
+                debug.labels.push(DebugLabel {
+                    name: symbol.name.clone(),
+                    pc: symbol.offset,
+                });
+            }
+        }
+        debug.rebase(layout.code_bases[index]);
+        merged.get_or_insert_with(DebugInfo::new).append(debug);
+    }
+    merged
+}
+
+// Code symbols resolve to an absolute pc, read-only symbols to an offset in the ro section
+fn address(layout: &Layout, index: usize, symbol: &ObjectSymbol) -> u32 {
+    match symbol.section {
+        ObjectSection::Code => layout.code_bases[index] + symbol.offset,
+        ObjectSection::Ro => layout.ro_bases[index] + symbol.offset,
+        ObjectSection::Undefined => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::assembler::PIE_HEADER_LENGTH;
+    use crate::vm::Vm;
+
+    fn object(source: &str) -> ObjectFile {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    #[test]
+    fn test_link_resolves_cross_module_references() {
+        let main = object(".data\n.code\n.extern double\nload $0 #21\nload $1 @double\njmp $1\n");
+        let lib = object(
+            ".data\nname: .asciiz 'lib'\n.code\n.global double\ndouble: add $0 $0 $0\nhlt\n",
+        );
+
+        let mut linker = Linker::new();
+        linker.add_object("main", main);
+        linker.add_object("lib", lib);
+        let image = linker.link().unwrap();
+
+        // The ro section of lib ("lib\0") pushes the code back by four bytes
+        let code_start = PIE_HEADER_LENGTH + 4;
+        assert_eq!(&image[PIE_HEADER_LENGTH..code_start], b"lib\0");
+        let double = code_start as u16 + 12;
+        assert_eq!(
+            image[code_start + 4..code_start + 8],
+            [0, 1, (double >> 8) as u8, double as u8]
+        );
+
+        let mut vm = Vm::new();
+        assert!(vm.load_image(image));
+        vm.run();
+        assert_eq!(vm.registers[0], 42);
+    }
+
+    #[test]
+    fn test_link_numbers_host_imports() {
+        let main = object(".data\n.code\ncall_host @log\ncall_host @now\ncall_host @log\nhlt\n");
+        let lib = object(".data\n.code\n.global helper\nhelper: call_host @now\nhlt\n");
+        let mut linker = Linker::new();
+        linker.add_object("main", main);
+        linker.add_object("lib", lib);
+        let image = linker.link().unwrap();
+
+        let code = &image[PIE_HEADER_LENGTH..];
+        assert_eq!(code[0..12], [21, 0, 0, 0, 21, 0, 1, 0, 21, 0, 0, 0]);
+        assert_eq!(code[16..20], [21, 0, 1, 0]);
+        assert_eq!(
+            crate::assembler::pie_imports(&image),
+            Some(vec!["log".to_string(), "now".to_string()])
+        );
+        assert_eq!(crate::verifier::verify(&image), Ok(()));
+    }
+
+    #[test]
+    fn test_link_reports_undefined_and_duplicate_symbols() {
+        let main = object(".data\n.code\n.extern missing\nload $0 @missing\n");
+        let mut linker = Linker::new();
+        linker.add_object("main", main);
+        assert_eq!(
+            linker.link(),
+            Err(vec![LinkerError::UndefinedSymbol {
+                name: "missing".to_string(),
+                module: "main".to_string()
+            }])
+        );
+
+        let mut linker = Linker::new();
+        linker.add_object("a", object(".data\n.code\n.global f\nf: hlt\n"));
+        linker.add_object("b", object(".data\n.code\n.global f\nf: hlt\n"));
+        assert!(matches!(
+            linker.link().unwrap_err()[0],
+            LinkerError::DuplicateSymbol { .. }
+        ));
+
+        assert_eq!(Linker::new().link(), Err(vec![LinkerError::NoObjects]));
+    }
+
+    #[test]
+    fn test_link_merges_debug_info() {
+        let mut assembler = Assembler::new();
+        assembler.enable_debug_info();
+        let lib = assembler
+            .assemble_object(".data\n.code\n.global f\nf: inc $0\n  hlt\n")
+            .unwrap();
+        let mut linker = Linker::new();
+        // main has no debug info, only lib's lines and labels end up in the image
+        linker.add_object("main", object(".data\n.code\nload $0 #1\n"));
+        linker.add_object("lib", lib);
+        let image = linker.link().unwrap();
+
+        let mut vm = Vm::new();
+        assert!(vm.load_image(image));
+        let debug_info = vm.debug_info().unwrap();
+        assert_eq!(debug_info.label_pc("f"), Some(68));
+        assert_eq!(debug_info.describe(72), "line 5, column 3 (f+4)");
+        assert!(debug_info.location(64).is_none());
+    }
+
+    #[test]
+    fn test_local_symbols_stay_local() {
+        // Both modules use a local label called loop, each must resolve to its own
+        let a = object(".data\n.code\nloop: load $0 @loop\n");
+        let b = object(".data\n.code\nloop: load $1 @loop\n");
+        let mut linker = Linker::new();
+        linker.add_object("a", a);
+        linker.add_object("b", b);
+        let image = linker.link().unwrap();
+        assert_eq!(image[PIE_HEADER_LENGTH..], [0, 0, 0, 64, 0, 1, 0, 68]);
+    }
+
+    #[test]
+    fn test_link_pulls_only_needed_archive_members() {
+        let mut archive = Archive::new();
+        let members = [
+            ("again", ".data\n.code\n.global again\nagain: add $0 $0 $0\nhlt\n"),
+            ("unused", ".data\nbig: .asciiz 'never linked'\n.code\n.global unused\nunused: hlt\n"),
+            // Pulled in by main, and pulls in again itself
+            ("double", ".data\n.code\n.global double\n.extern again\ndouble: add $0 $0 $0\nload $2 @again\njmp $2\n"),
+        ];
+        for (name, source) in members {
+            archive.add_member(name, object(source)).unwrap();
+        }
+
+        let main = object(".data\n.code\n.extern double\nload $0 #7\nload $1 @double\njmp $1\n");
+        let mut linker = Linker::new();
+        linker.add_object("main", main);
+        linker.add_archive("std.ira", archive);
+        let image = linker.link().unwrap();
+
+        // main, double and again are linked, unused (and its ro data) is not
+        assert_eq!(image.len(), PIE_HEADER_LENGTH + 12 + 12 + 8);
+
+        let mut vm = Vm::new();
+        assert!(vm.load_image(image));
+        vm.run();
+        assert_eq!(vm.registers[0], 7 * 4);
+    }
+
+    #[test]
+    fn test_link_reports_symbols_missing_from_archives() {
+        let mut archive = Archive::new();
+        archive
+            .add_member(
+                "f",
+                object(".data\n.code\n.global f\n.extern g\nf: load $0 @g\n"),
+            )
+            .unwrap();
+        let mut linker = Linker::new();
+        linker.add_object("main", object(".data\n.code\n.extern f\nload $0 @f\n"));
+        linker.add_archive("lib.ira", archive);
+        assert_eq!(
+            linker.link(),
+            Err(vec![LinkerError::UndefinedSymbol {
+                name: "g".to_string(),
+                module: "lib.ira(f)".to_string()
+            }])
+        );
+    }
+}
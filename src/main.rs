@@ -1,62 +1,336 @@
-#[macro_use]
-extern crate nom;
-
 #[macro_use]
 extern crate clap;
 
-use assembler::base_assembler::Assembler;
-use clap::App;
-use std::{fs::File, io::Read, path::Path};
-use vm::Vm;
-
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
+use clap::{App, ArgMatches};
+use iridation::assembler::base_assembler::Assembler;
+use iridation::assembler::conditional::parse_define;
+use iridation::assembler::object::ObjectFile;
+use iridation::assembler::PIE_HEADER_PREFIX;
+use iridation::linker::archive::Archive;
+use iridation::linker::Linker;
+use iridation::repl;
+use iridation::vm::files::Filesystem;
+use iridation::vm::host::{register_heap_functions, HostFunctions};
+use iridation::vm::net::Network;
+use iridation::vm::profile::Profile;
+use iridation::vm::trace::{parse_opcodes, parse_range, TraceFilter, TRACE_TARGET};
+use iridation::vm::{Limits, RunOutcome, Vm, LIMIT_EXIT_STATUS};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
 
 fn start_repl() {
     let mut user_repl = repl::Repl::new();
     user_repl.run();
 }
 
-fn read_file(tmp: &str) -> String {
-    let filename = Path::new(tmp);
-    let fl = File::open(filename);
-    match fl {
-        Ok(mut f) => {
-            let mut file_content = String::new();
-            match f.read_to_string(&mut file_content) {
-                Ok(_) => file_content,
-                Err(e) => {
-                    println!("{:?}", e);
-                    std::process::exit(1);
-                }
-            }
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    std::process::exit(1);
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => fail(&format!("Unable to read {}: {}", path, e)),
+    }
+}
+
+fn write_file(path: &str, bytes: &[u8]) {
+    if let Err(e) = std::fs::write(path, bytes) {
+        fail(&format!("Unable to write {}: {}", path, e));
+    }
+}
+
+fn new_assembler(matches: &ArgMatches) -> Assembler {
+    let mut asm = Assembler::new();
+    for define in matches.values_of("DEFINE").unwrap_or_default() {
+        match parse_define(define) {
+            Ok((name, value)) => asm.define(&name, value),
+            Err(e) => fail(&e),
         }
-        Err(_) => {
-            println!("Failed to read file");
+    }
+    if matches.is_present("LISTING") {
+        asm.enable_listing();
+    }
+    if matches.is_present("DEBUG_INFO") {
+        asm.enable_debug_info();
+    }
+    if matches.is_present("OPTIMIZE") {
+        asm.enable_optimizer();
+    }
+    asm
+}
+
+// Object files are used as they are, anything else is assembled
+fn load_object(matches: &ArgMatches, path: &str) -> ObjectFile {
+    let bytes = read_file(path);
+    if ObjectFile::is_object_file(&bytes) {
+        return match ObjectFile::from_bytes(&bytes) {
+            Ok(object) => object,
+            Err(e) => fail(&format!("{}: {}", path, e)),
+        };
+    }
+
+    let mut asm = new_assembler(matches);
+    let object = asm.assemble_file_object(Path::new(path));
+    if let (Some(listing), Some(text), true) =
+        (matches.value_of("LISTING"), asm.listing(), object.is_ok())
+    {
+        write_file(listing, text.as_bytes());
+    }
+    // The report goes to stderr so it never mixes with the program's own output
+    if let Some(report) = asm.optimization_report() {
+        eprintln!("{}: {}", path, report);
+    }
+    match object {
+        Ok(object) => object,
+        Err(errors) => {
+            errors.iter().for_each(|e| println!("{}", e));
             std::process::exit(1);
         }
     }
 }
 
+fn parse_limit<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    let value = matches.value_of(name)?;
+    match value.parse::<T>() {
+        Ok(limit) => Some(limit),
+        Err(_) => fail(&format!("`{}` is not a valid limit", value)),
+    }
+}
+
+fn limits(matches: &ArgMatches) -> Limits {
+    Limits {
+        instructions: parse_limit(matches, "MAX_INSTRUCTIONS"),
+        time: parse_limit(matches, "TIMEOUT").map(Duration::from_millis),
+        heap: parse_limit(matches, "MAX_HEAP"),
+        threads: parse_limit(matches, "MAX_THREADS"),
+    }
+}
+
+// Writes the trace to a file, env_logger only writes to the terminal
+struct TraceFile {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl log::Log for TraceFile {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == TRACE_TARGET
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let mut writer = self.writer.lock().unwrap();
+            let _ = writeln!(writer, "{}", record.args());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.writer.lock().unwrap().flush();
+    }
+}
+
+fn trace_filter(matches: &ArgMatches) -> Option<TraceFilter> {
+    if !matches.is_present("TRACE") {
+        return None;
+    }
+    let mut filter = TraceFilter::new();
+    if let Some(range) = matches.value_of("TRACE_RANGE") {
+        filter.range = Some(parse_range(range).unwrap_or_else(|e| fail(&e)));
+    }
+    if let Some(opcodes) = matches.value_of("TRACE_OPCODES") {
+        filter.opcodes = parse_opcodes(opcodes).unwrap_or_else(|e| fail(&e));
+    }
+
+    match matches.value_of("TRACE_FILE") {
+        Some(path) => {
+            let file = File::create(path)
+                .unwrap_or_else(|e| fail(&format!("Unable to write {}: {}", path, e)));
+            let logger = TraceFile {
+                writer: Mutex::new(BufWriter::new(file)),
+            };
+            log::set_boxed_logger(Box::new(logger)).expect("Logger already set");
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+        None => {
+            let mut builder = env_logger::Builder::new();
+            builder.format(|buf, record| writeln!(buf, "{}", record.args()));
+            // RUST_LOG replaces the default of tracing every selected instruction
+            match std::env::var("RUST_LOG") {
+                Ok(spec) => builder.parse(&spec),
+                Err(_) => builder.filter(Some(TRACE_TARGET), log::LevelFilter::Trace),
+            };
+            builder.init();
+        }
+    }
+    Some(filter)
+}
+
+// Functions programs run from the command line can reach with call_host
+fn host_functions(matches: &ArgMatches) -> HostFunctions {
+    let mut hosts = HostFunctions::new();
+    register_heap_functions(&mut hosts);
+    if matches.is_present("NET") {
+        Network::new().register(&mut hosts);
+    }
+    if let Some(root) = matches.value_of("FS_ROOT") {
+        let read_only = matches.is_present("FS_READ_ONLY");
+        match Filesystem::new(Path::new(root), read_only) {
+            Ok(files) => files.register(&mut hosts),
+            Err(e) => fail(&format!("Cannot use {} as the file root: {}", root, e)),
+        }
+    }
+    hosts
+}
+
+fn run_image(image: Vec<u8>, matches: &ArgMatches) -> ! {
+    let mut vm = Vm::new();
+    if !vm.load_image(image) {
+        fail("Not a valid Iridation image");
+    }
+    let args: Vec<String> = matches
+        .values_of("PROGRAM_ARGS")
+        .map(|args| args.map(String::from).collect())
+        .unwrap_or_default();
+    // Variables that are not set are left out
+    let env: Vec<(String, String)> = matches
+        .values_of("ENV")
+        .into_iter()
+        .flatten()
+        .filter_map(|name| Some((name.to_string(), std::env::var(name).ok()?)))
+        .collect();
+    // Limits first, so the arguments count toward the heap limit
+    vm.limits = limits(matches);
+    if let Err(limit) = vm.set_arguments(&args, &env) {
+        println!("{}, copying the program arguments", limit);
+        std::process::exit(LIMIT_EXIT_STATUS);
+    }
+    run_vm(vm, matches);
+}
+
+fn run_vm(mut vm: Vm, matches: &ArgMatches) -> ! {
+    let verified = match matches.is_present("NO_VERIFY") {
+        true => Ok(()),
+        false => vm.verify(),
+    };
+    if let Err(violations) = verified {
+        violations.iter().for_each(|v| println!("{}", v));
+        fail("Refusing to run an image that failed verification");
+    }
+    if let Err(e) = vm.bind_host_functions(&host_functions(matches)) {
+        fail(&e.to_string());
+    }
+    vm.predecode();
+    vm.limits = limits(matches);
+    if let Some(slice) = parse_limit(matches, "TIME_SLICE") {
+        vm.time_slice = Some(slice).filter(|slice| *slice > 0);
+    }
+    vm.trace = trace_filter(matches);
+    if matches.is_present("PROFILE") || matches.is_present("PROFILE_FOLDED") {
+        vm.profile = Some(Profile::new());
+    }
+    let outcome = vm.run();
+    log::logger().flush();
+    if let Some(profile) = &vm.profile {
+        if matches.is_present("PROFILE") {
+            eprint!("{}", profile.report(vm.debug_info()));
+        }
+        if let Some(path) = matches.value_of("PROFILE_FOLDED") {
+            write_file(path, profile.folded(vm.debug_info()).as_bytes());
+        }
+    }
+    if let Some(report) = vm.error_report() {
+        fail(&report);
+    }
+    if let RunOutcome::LimitExceeded { limit } = &outcome {
+        println!("{} at {}", limit, vm.describe_pc(vm.pc()));
+    }
+    std::process::exit(outcome.status());
+}
+
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
-    let target_file = matches.value_of("INPUT_FILE");
-    match target_file {
-        Some(fl) => {
-            let program = read_file(fl);
-            let mut vm = Vm::new();
-            let mut asm = Assembler::new();
-            let program = asm.assemble(&program);
-
-            if let Ok(p) = program {
-                vm.add_bytes(p);
-                vm.run();
-                std::process::exit(0);
+    if let Some(path) = matches.value_of("RESUME") {
+        match Vm::restore(&read_file(path)) {
+            Ok(vm) => run_vm(vm, &matches),
+            Err(e) => fail(&format!("{}: {}", path, e)),
+        }
+    }
+    let inputs: Vec<&str> = match matches.values_of("INPUT_FILE") {
+        Some(inputs) => inputs.collect(),
+        None => return start_repl(),
+    };
+    let output = matches.value_of("OUTPUT");
+    if inputs.len() > 1 && matches.is_present("LISTING") {
+        fail("--listing can only be used with a single input file");
+    }
+
+    if let Some(path) = matches.value_of("ARCHIVE") {
+        let mut archive = Archive::new();
+        for input in &inputs {
+            let name = Path::new(input)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| input.to_string());
+            if let Err(e) = archive.add_member(&name, load_object(&matches, input)) {
+                fail(&format!("{}: {}", path, e));
             }
         }
-        None => start_repl(),
+        write_file(path, &archive.to_bytes());
+        std::process::exit(0);
+    }
+
+    if matches.is_present("COMPILE") {
+        if inputs.len() > 1 && output.is_some() {
+            fail("-o can only be used with a single input file when compiling");
+        }
+        for input in &inputs {
+            let object = load_object(&matches, input);
+            let path = match output {
+                Some(path) => path.to_string(),
+                None => Path::new(input)
+                    .with_extension("iro")
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            write_file(&path, &object.to_bytes());
+        }
+        std::process::exit(0);
+    }
+
+    // An already linked image runs as it is
+    if inputs.len() == 1 && output.is_none() {
+        let bytes = read_file(inputs[0]);
+        if bytes.len() >= 4 && bytes[0..4] == PIE_HEADER_PREFIX {
+            run_image(bytes, &matches);
+        }
+    }
+
+    let mut linker = Linker::new();
+    for input in &inputs {
+        let bytes = read_file(input);
+        if Archive::is_archive(&bytes) {
+            match Archive::from_bytes(&bytes) {
+                Ok(archive) => linker.add_archive(input, archive),
+                Err(e) => fail(&format!("{}: {}", input, e)),
+            }
+        } else {
+            linker.add_object(input, load_object(&matches, input));
+        }
+    }
+    let image = match linker.link() {
+        Ok(image) => image,
+        Err(errors) => {
+            errors.iter().for_each(|e| println!("{}", e));
+            std::process::exit(1);
+        }
+    };
+    match output {
+        Some(path) => write_file(path, &image),
+        None => run_image(image, &matches),
     }
 }
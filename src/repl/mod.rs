@@ -1,17 +1,20 @@
 #![allow(dead_code)]
 
+use crate::assembler::base_assembler::Assembler;
+use crate::assembler::preprocessor::Preprocessor;
 use crate::assembler::program_parser::program;
 use crate::vm::Vm;
 use nom::types::CompleteStr;
-use std::fs::File;
 use std::io::Write;
-use std::io::{self, Read};
+use std::io::{self};
 use std::num::ParseIntError;
 use std::path::Path;
 
 pub struct Repl {
     vm: Vm,
     command_buffer: Vec<String>,
+    // pcs the debugger stops at
+    breakpoints: Vec<usize>,
 }
 
 impl Default for Repl {
@@ -25,6 +28,7 @@ impl Repl {
         Repl {
             vm: Vm::new(),
             command_buffer: vec![],
+            breakpoints: vec![],
         }
     }
     fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
@@ -65,7 +69,7 @@ impl Repl {
                 }
                 ".program" => {
                     println!("Intructions currently in VM program vector");
-                    self.vm.program.iter().for_each(|x| println!("{x}"));
+                    self.vm.program().iter().for_each(|x| println!("{x}"));
                     println!("End of program listing")
                 }
                 ".registers" => {
@@ -74,7 +78,7 @@ impl Repl {
                     println!("End of registers listing");
                 }
                 ".clear" => {
-                    self.vm.program = vec![];
+                    self.vm.set_program(vec![]);
                     println!("VM program vector is cleared!!!");
                 }
                 ".load_file" => {
@@ -83,34 +87,81 @@ impl Repl {
                     let mut tmp = String::new();
                     stdin.read_line(&mut tmp).expect("Unable to read the line");
                     let tmp = tmp.trim();
-                    let filename = Path::new(&tmp);
-                    let mut f;
-                    match File::open(Path::new(&filename)){
-                        Ok(file) => f = file,
-                        Err(_e) => {println!("This is not a path to a .iasm file"); continue;}
-                    }
-                    let mut contents = String::new();
-                    match f.read_to_string(&mut contents){
-                        Err(_) => println!("Error while reading the file"),
-                        Ok(b) => println!("{} byte was appended",b)
-                    }
+                    // Macros and .include are expanded just like a file passed on the command line
+                    let lines = match Preprocessor::new().process_file(Path::new(tmp)) {
+                        Ok(lines) => lines,
+                        Err(errors) => {
+                            errors.iter().for_each(|e| println!("{}", e));
+                            continue;
+                        }
+                    };
+                    let contents = lines
+                        .iter()
+                        .map(|l| l.text.as_str())
+                        .collect::<Vec<&str>>()
+                        .join("\n");
                     let program = match program(CompleteStr(&contents)) {
+                        Ok((rest, _)) if !rest.is_empty() => {
+                            println!(
+                                "Unable to parse input starting at: {}",
+                                rest.lines().next().unwrap_or_default()
+                            );
+                            continue;
+                        }
                         Ok((_, program)) => program,
                         Err(e) => {
                             println!("Unable to parse input {:?}", e);
                             continue;
                         }
                     };
-                    self.vm.program.append(&mut program.to_bytes());
+                    match program.to_bytes() {
+                        Ok(bytes) => self.vm.add_bytes(bytes),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                ".step" => {
+                    let stopped = self.vm.run_once();
+                    self.report_stop(stopped);
+                }
+                ".continue" => {
+                    let stopped = self.continue_to_breakpoint();
+                    self.report_stop(stopped);
+                }
+                ".where" => {
+                    self.vm.backtrace().iter().for_each(|f| println!("{}", f));
+                }
+                command if command.starts_with(".debug ") => {
+                    self.debug_file(command[".debug ".len()..].trim());
+                }
+                command if command.starts_with(".break ") => {
+                    match self.resolve_breakpoint(command[".break ".len()..].trim()) {
+                        Ok(pc) => {
+                            self.breakpoints.push(pc);
+                            println!("Breakpoint at {}", self.vm.describe_pc(pc));
+                        }
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                command if command.starts_with(".save ") => {
+                    self.save_snapshot(command[".save ".len()..].trim());
+                }
+                command if command.starts_with(".restore ") => {
+                    self.restore_snapshot(command[".restore ".len()..].trim());
                 }
                 _ => {
                     let parsed_program = program(CompleteStr(buffer));
-                    if parsed_program.is_err() {
+                    if !matches!(parsed_program, Ok((rest, _)) if rest.is_empty()) {
                         println!("Unable to parse input");
                         continue;
                     }
                     let (_, result) = parsed_program.unwrap();
-                    let bytecode = result.to_bytes();
+                    let bytecode = match result.to_bytes() {
+                        Ok(bytecode) => bytecode,
+                        Err(e) => {
+                            println!("{}", e);
+                            continue;
+                        }
+                    };
                     for byte in bytecode {
                         self.vm.add_byte(byte);
                     }
@@ -119,4 +170,120 @@ impl Repl {
             }
         }
     }
+
+    // Assembles a file with debug info and loads it, ready to be stepped through
+    fn debug_file(&mut self, path: &str) {
+        let mut assembler = Assembler::new();
+        assembler.enable_debug_info();
+        let image = match assembler.assemble_file(Path::new(path)) {
+            Ok(image) => image,
+            Err(errors) => {
+                errors.iter().for_each(|e| println!("{}", e));
+                return;
+            }
+        };
+        self.vm = Vm::new();
+        self.vm.load_image(image);
+        self.breakpoints.clear();
+        println!(
+            "Loaded {}, stopped at {}",
+            path,
+            self.vm.describe_pc(self.vm.pc())
+        );
+    }
+
+    fn save_snapshot(&self, path: &str) {
+        match std::fs::write(path, self.vm.snapshot()) {
+            Ok(()) => println!("Saved the VM to {}", path),
+            Err(e) => println!("Unable to write {}: {}", path, e),
+        }
+    }
+
+    // Replaces the VM, breakpoints are kept since they usually belong to the same program
+    fn restore_snapshot(&mut self, path: &str) {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => return println!("Unable to read {}: {}", path, e),
+        };
+        match Vm::restore(&bytes) {
+            Ok(vm) => {
+                self.vm = vm;
+                println!(
+                    "Restored {}, stopped at {}",
+                    path,
+                    self.vm.describe_pc(self.vm.pc())
+                );
+            }
+            Err(e) => println!("{}: {}", path, e),
+        }
+    }
+
+    // A breakpoint is a label, a line number or file:line
+    fn resolve_breakpoint(&self, target: &str) -> Result<usize, String> {
+        let debug_info = match self.vm.debug_info() {
+            Some(debug_info) => debug_info,
+            None => return Err("No debug info loaded, use .debug <file> first".to_string()),
+        };
+        let (file, line) = match target.rsplit_once(':') {
+            Some((file, line)) => (Some(file), line),
+            None => (None, target),
+        };
+        let pc = match line.parse::<u32>() {
+            Ok(line) => debug_info.line_pc(file, line),
+            Err(_) => debug_info.label_pc(target),
+        };
+        pc.map(|pc| pc as usize)
+            .ok_or(format!("No instruction found for {}", target))
+    }
+
+    // Runs until the VM stops or reaches a breakpoint, returns true if it stopped
+    fn continue_to_breakpoint(&mut self) -> bool {
+        loop {
+            if self.vm.run_once() {
+                return true;
+            }
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return false;
+            }
+        }
+    }
+
+    fn report_stop(&self, stopped: bool) {
+        if let Some(report) = self.vm.error_report() {
+            println!("{}", report);
+        } else if self.vm.halted() {
+            println!("Program halted with status {}", self.vm.status());
+        } else if stopped {
+            println!("Program stopped");
+        } else {
+            println!("Stopped at {}", self.vm.describe_pc(self.vm.pc()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_breakpoint() {
+        let mut repl = Repl::new();
+        assert!(repl.resolve_breakpoint("loop").is_err());
+
+        let mut assembler = Assembler::new();
+        assembler.enable_debug_info();
+        let image = assembler
+            .assemble(".data\n.code\nload $0 #3\nloop: dec $0\nhlt\n")
+            .unwrap();
+        repl.vm.load_image(image);
+        assert_eq!(repl.resolve_breakpoint("loop"), Ok(68));
+        assert_eq!(repl.resolve_breakpoint("5"), Ok(72));
+        assert!(repl.resolve_breakpoint("main.iasm:5").is_err());
+        assert!(repl.resolve_breakpoint("9").is_err());
+
+        repl.breakpoints.push(68);
+        assert!(!repl.continue_to_breakpoint());
+        assert_eq!(repl.vm.pc(), 68);
+        assert_eq!(repl.vm.registers[0], 3);
+    }
 }
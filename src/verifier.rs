@@ -0,0 +1,505 @@
+use crate::assembler::{
+    pie_debug_length, pie_imports, pie_imports_length, pie_ro_length, PIE_HEADER_LENGTH,
+};
+use crate::instruction::{Opcode, HLT_STATUS_FROM_REGISTER};
+use std::collections::VecDeque;
+use std::fmt;
+
+// Everything wrong with an image, pc is where the offending instruction starts
+#[derive(Debug, PartialEq, Clone)]
+pub enum Violation {
+    InvalidHeader,
+    MisalignedCode { length: usize },
+    UndefinedOpcode { pc: usize, opcode: u8 },
+    InvalidRegister { pc: usize, register: u8 },
+    InvalidJumpTarget { pc: usize, target: i64 },
+    UnknownJumpTarget { pc: usize },
+    InvalidString { pc: usize, offset: usize },
+    UnknownHostFunction { pc: usize, index: usize },
+    InvalidThreadEntry { pc: usize, target: usize },
+    NoReachableHalt,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::InvalidHeader => write!(f, "Image does not start with a valid PIE header"),
+            Violation::MisalignedCode { length } => write!(
+                f,
+                "Code section is {} bytes long, which is not a whole number of instructions",
+                length
+            ),
+            Violation::UndefinedOpcode { pc, opcode } => {
+                write!(f, "Undefined opcode {} at pc {}", opcode, pc)
+            }
+            Violation::InvalidRegister { pc, register } => {
+                write!(f, "Register {} does not exist, used at pc {}", register, pc)
+            }
+            Violation::InvalidJumpTarget { pc, target } => write!(
+                f,
+                "Jump at pc {} goes to {}, which is not an instruction in the code section",
+                pc, target
+            ),
+            Violation::UnknownJumpTarget { pc } => write!(
+                f,
+                "Jump target at pc {} cannot be worked out before running",
+                pc
+            ),
+            Violation::InvalidString { pc, offset } => write!(
+                f,
+                "prts at pc {} prints from offset {}, where the read-only section has no string",
+                pc, offset
+            ),
+            Violation::UnknownHostFunction { pc, index } => write!(
+                f,
+                "call_host at pc {} uses import {}, which the image does not have",
+                pc, index
+            ),
+            Violation::InvalidThreadEntry { pc, target } => write!(
+                f,
+                "spawn at pc {} starts a thread at {}, which is not an instruction in the code section",
+                pc, target
+            ),
+            Violation::NoReachableHalt => write!(f, "No hlt instruction can be reached"),
+        }
+    }
+}
+
+// What is known about a register at some point of the program
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Value {
+    Known(i32),
+    Unknown,
+}
+
+type Registers = [Value; 32];
+
+fn join(into: &mut Registers, from: &Registers) -> bool {
+    let mut changed = false;
+    for (a, b) in into.iter_mut().zip(from.iter()) {
+        if *a != *b && *a != Value::Unknown {
+            *a = Value::Unknown;
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Checks an image before it runs: every instruction must be defined and use existing
+// registers, prts must print strings from the read-only section, and every reachable jump
+// must land on an instruction. Jump targets live in registers, so they are followed by
+// tracking the values loaded into registers along every path; a jump whose target depends on
+// something only known while running is rejected. At least one hlt must be reachable.
+pub fn verify(image: &[u8]) -> Result<(), Vec<Violation>> {
+    match pie_imports(image) {
+        Some(imports) => verify_with_imports(image, imports.len()),
+        None => Err(vec![Violation::InvalidHeader]),
+    }
+}
+
+// As verify, for an image whose imports section was taken out when it was loaded.
+// call_host operands must be below the given number of imports.
+pub fn verify_with_imports(image: &[u8], imports: usize) -> Result<(), Vec<Violation>> {
+    let (ro_length, debug_length, imports_length) = match (
+        pie_ro_length(image),
+        pie_debug_length(image),
+        pie_imports_length(image),
+    ) {
+        (Some(ro), Some(debug), Some(imports_length)) => (ro, debug, imports_length),
+        _ => return Err(vec![Violation::InvalidHeader]),
+    };
+    let code = Code {
+        image,
+        ro: &image[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + ro_length],
+        start: PIE_HEADER_LENGTH + ro_length,
+        end: image.len() - debug_length - imports_length,
+        imports,
+    };
+    if !(code.end - code.start).is_multiple_of(4) {
+        return Err(vec![Violation::MisalignedCode {
+            length: code.end - code.start,
+        }]);
+    }
+
+    let mut violations = vec![];
+    let mut pc = code.start;
+    while pc < code.end {
+        code.check_instruction(pc, &mut violations);
+        pc += 4;
+    }
+    if !violations.is_empty() {
+        return Err(violations);
+    }
+
+    let states = code.propagate();
+    let mut halts = false;
+    for (index, state) in states.iter().enumerate() {
+        let state = match state {
+            Some(state) => state,
+            None => continue,
+        };
+        let pc = code.start + index * 4;
+        halts |= code.opcode(pc) == Opcode::HLT;
+        if let Some(target) = code.jump_target(pc, state) {
+            match target {
+                Value::Known(target) if code.is_instruction(target as i64) => {}
+                Value::Known(target) => violations.push(Violation::InvalidJumpTarget {
+                    pc,
+                    target: target as i64,
+                }),
+                Value::Unknown => violations.push(Violation::UnknownJumpTarget { pc }),
+            }
+        }
+    }
+    if !halts {
+        violations.push(Violation::NoReachableHalt);
+    }
+
+    match violations.is_empty() {
+        true => Ok(()),
+        false => Err(violations),
+    }
+}
+
+struct Code<'a> {
+    image: &'a [u8],
+    ro: &'a [u8],
+    start: usize,
+    end: usize,
+    // Number of host functions the image imports
+    imports: usize,
+}
+
+impl<'a> Code<'a> {
+    fn opcode(&self, pc: usize) -> Opcode {
+        Opcode::from(self.image[pc])
+    }
+
+    fn is_instruction(&self, target: i64) -> bool {
+        target >= self.start as i64
+            && target < self.end as i64
+            && (target - self.start as i64) % 4 == 0
+    }
+
+    // Positions of the register operands of an instruction
+    fn registers(opcode: Opcode) -> &'static [usize] {
+        match opcode {
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::SENDB
+            | Opcode::RECV
+            | Opcode::TRYRECV => &[1, 2, 3],
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTQ
+            | Opcode::LTQ
+            | Opcode::SEND
+            | Opcode::READL => &[1, 2],
+            Opcode::LOAD
+            | Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::AlOC
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::SPAWN
+            | Opcode::JOIN
+            | Opcode::CHAN
+            | Opcode::READB
+            | Opcode::READI => &[1],
+            _ => &[],
+        }
+    }
+
+    fn check_instruction(&self, pc: usize, violations: &mut Vec<Violation>) {
+        let opcode = self.opcode(pc);
+        if opcode == Opcode::IGL {
+            violations.push(Violation::UndefinedOpcode {
+                pc,
+                opcode: self.image[pc],
+            });
+            return;
+        }
+        for position in Code::registers(opcode) {
+            let register = self.image[pc + position];
+            if register >= 32 {
+                violations.push(Violation::InvalidRegister { pc, register });
+            }
+        }
+        let status_register = self.image[pc + 2] == HLT_STATUS_FROM_REGISTER;
+        if opcode == Opcode::HLT && status_register && self.image[pc + 1] >= 32 {
+            violations.push(Violation::InvalidRegister {
+                pc,
+                register: self.image[pc + 1],
+            });
+        }
+        if opcode == Opcode::PTRS {
+            // The offset follows the opcode directly
+            let offset = (((self.image[pc + 1] as u16) << 8) | self.image[pc + 2] as u16) as usize;
+            if offset >= self.ro.len() || !self.ro[offset..].contains(&0) {
+                violations.push(Violation::InvalidString { pc, offset });
+            }
+        }
+        if opcode == Opcode::CALLH {
+            let index = (((self.image[pc + 1] as u16) << 8) | self.image[pc + 2] as u16) as usize;
+            if index >= self.imports {
+                violations.push(Violation::UnknownHostFunction { pc, index });
+            }
+        }
+        if opcode == Opcode::SPAWN {
+            let target = self.operand16(pc) as usize;
+            if !self.is_instruction(target as i64) {
+                violations.push(Violation::InvalidThreadEntry { pc, target });
+            }
+        }
+    }
+
+    fn operand16(&self, pc: usize) -> u16 {
+        ((self.image[pc + 2] as u16) << 8) | self.image[pc + 3] as u16
+    }
+
+    fn register(&self, pc: usize, position: usize) -> usize {
+        self.image[pc + position] as usize
+    }
+
+    // Where a jump instruction goes with the given registers, None for other instructions
+    fn jump_target(&self, pc: usize, registers: &Registers) -> Option<Value> {
+        let opcode = self.opcode(pc);
+        if !matches!(
+            opcode,
+            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ | Opcode::JMPF | Opcode::JMPB
+        ) {
+            return None;
+        }
+        let value = registers[self.register(pc, 1)];
+        match opcode {
+            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => Some(value),
+            // Relative jumps count from just after the register operand
+            Opcode::JMPF => Some(match value {
+                Value::Known(v) => Value::Known((pc as i32 + 2).wrapping_add(v)),
+                Value::Unknown => Value::Unknown,
+            }),
+            Opcode::JMPB => Some(match value {
+                Value::Known(v) => Value::Known((pc as i32 + 2).wrapping_sub(v)),
+                Value::Unknown => Value::Unknown,
+            }),
+            _ => None,
+        }
+    }
+
+    fn execute(&self, pc: usize, registers: &mut Registers) {
+        // A host function may change any register
+        if self.opcode(pc) == Opcode::CALLH {
+            *registers = [Value::Unknown; 32];
+            return;
+        }
+        let known = |r: &Registers, position: usize| r[self.register(pc, position)];
+        let result = match self.opcode(pc) {
+            Opcode::LOAD => Some((1, Value::Known(self.operand16(pc) as i32))),
+            // A thread id, the exit status of the joined thread, a channel handle and input
+            Opcode::SPAWN | Opcode::JOIN | Opcode::CHAN | Opcode::READB | Opcode::READI => {
+                Some((1, Value::Unknown))
+            }
+            // The line's offset and length
+            Opcode::READL => {
+                registers[self.register(pc, 2)] = Value::Unknown;
+                Some((1, Value::Unknown))
+            }
+            // The received value and its length
+            Opcode::RECV | Opcode::TRYRECV => {
+                registers[self.register(pc, 3)] = Value::Unknown;
+                Some((2, Value::Unknown))
+            }
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                let value = match (known(registers, 1), known(registers, 2)) {
+                    (Value::Known(a), Value::Known(b)) => match self.opcode(pc) {
+                        Opcode::ADD => Value::Known(a.wrapping_add(b)),
+                        Opcode::SUB => Value::Known(a.wrapping_sub(b)),
+                        Opcode::MUL => Value::Known(a.wrapping_mul(b)),
+                        _ if b != 0 => Value::Known(a.wrapping_div(b)),
+                        _ => Value::Unknown,
+                    },
+                    _ => Value::Unknown,
+                };
+                Some((3, value))
+            }
+            Opcode::INC | Opcode::DEC => {
+                let value = match (known(registers, 1), self.opcode(pc)) {
+                    (Value::Known(v), Opcode::INC) => Value::Known(v.wrapping_add(1)),
+                    (Value::Known(v), _) => Value::Known(v.wrapping_sub(1)),
+                    _ => Value::Unknown,
+                };
+                Some((1, value))
+            }
+            _ => None,
+        };
+        if let Some((position, value)) = result {
+            registers[self.register(pc, position)] = value;
+        }
+    }
+
+    fn successors(&self, pc: usize, registers: &Registers) -> Vec<usize> {
+        let mut next = vec![];
+        match self.opcode(pc) {
+            Opcode::HLT | Opcode::IGL => return next,
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB => {}
+            _ => next.push(pc + 4),
+        }
+        if let Some(Value::Known(target)) = self.jump_target(pc, registers) {
+            if self.is_instruction(target as i64) {
+                next.push(target as usize);
+            }
+        }
+        // The new thread starts with a copy of the registers
+        if self.opcode(pc) == Opcode::SPAWN {
+            next.push(self.operand16(pc) as usize);
+        }
+        // Running off the end of the code stops the VM
+        next.retain(|pc| *pc < self.end);
+        next
+    }
+
+    // Register values on entry to every reachable instruction, None for unreachable ones.
+    // Registers start out unknown, an embedder may have set them before running.
+    fn propagate(&self) -> Vec<Option<Registers>> {
+        let mut states: Vec<Option<Registers>> = vec![None; (self.end - self.start) / 4];
+        let mut worklist = VecDeque::new();
+        if self.start < self.end {
+            states[0] = Some([Value::Unknown; 32]);
+            worklist.push_back(self.start);
+        }
+
+        while let Some(pc) = worklist.pop_front() {
+            let mut registers = states[(pc - self.start) / 4].unwrap();
+            let successors = self.successors(pc, &registers);
+            self.execute(pc, &mut registers);
+            for next in successors {
+                let index = (next - self.start) / 4;
+                let changed = match &mut states[index] {
+                    Some(state) => join(state, &registers),
+                    state => {
+                        *state = Some(registers);
+                        true
+                    }
+                };
+                if changed {
+                    worklist.push_back(next);
+                }
+            }
+        }
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::assembler::pie_header;
+
+    fn image(ro: &[u8], code: &[u8]) -> Vec<u8> {
+        let mut image = pie_header(ro.len() as u32);
+        image.extend_from_slice(ro);
+        image.extend_from_slice(code);
+        image
+    }
+
+    #[test]
+    fn test_verify_assembled_program() {
+        let source = ".data\nhi: .asciiz 'hi'\n.code\nload $0 #3\nloop: dec $0\nprts @hi\nload $1 #0\nload $2 @loop\nneq $0 $1\njeq $2\nhlt\n";
+        let image = Assembler::new().assemble(source).unwrap();
+        assert_eq!(verify(&image), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_instructions() {
+        let code = [
+            0, 40, 0, 1, // load into a register that does not exist
+            99, 0, 0, 0, // undefined opcode
+            20, 0, 7, 0, // prts past the end of the read-only section
+            254, 32, 1, 0, // exit status from a register that does not exist
+            21, 0, 0, 0, // call to a host function the image does not import
+            22, 1, 0, 90, // thread starting in the middle of an instruction
+        ];
+        assert_eq!(
+            verify(&image(b"hi\0\0", &code)),
+            Err(vec![
+                Violation::InvalidRegister {
+                    pc: 68,
+                    register: 40
+                },
+                Violation::UndefinedOpcode { pc: 72, opcode: 99 },
+                Violation::InvalidString { pc: 76, offset: 7 },
+                Violation::InvalidRegister {
+                    pc: 80,
+                    register: 32
+                },
+                Violation::UnknownHostFunction { pc: 84, index: 0 },
+                Violation::InvalidThreadEntry { pc: 88, target: 90 },
+            ])
+        );
+        assert_eq!(verify(&[1, 2, 3]), Err(vec![Violation::InvalidHeader]));
+        assert_eq!(
+            verify(&image(b"", &[254, 0, 0])),
+            Err(vec![Violation::MisalignedCode { length: 3 }])
+        );
+    }
+
+    #[test]
+    fn test_verify_jump_targets() {
+        // Jumps into the middle of an instruction, and past the end of the code
+        let code = [
+            0, 0, 0, 66, 0, 1, 0, 200, 8, 2, 3, 0, 14, 0, 0, 0, 15, 1, 0, 0, 254, 0, 0, 0,
+        ];
+        assert_eq!(
+            verify(&image(b"", &code)),
+            Err(vec![
+                Violation::InvalidJumpTarget { pc: 76, target: 66 },
+                Violation::InvalidJumpTarget {
+                    pc: 80,
+                    target: 200
+                },
+            ])
+        );
+
+        // The target register is only known at run time
+        let code = [1, 3, 4, 0, 14, 0, 0, 0, 254, 0, 0, 0];
+        assert_eq!(
+            verify(&image(b"", &code)),
+            Err(vec![Violation::UnknownJumpTarget { pc: 68 }])
+        );
+
+        // Targets computed from constants are followed
+        let code = [
+            0, 0, 0, 60, 0, 1, 0, 20, 1, 0, 1, 2, 5, 2, 0, 0, 254, 0, 0, 0,
+        ];
+        assert_eq!(verify(&image(b"", &code)), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_follows_spawned_threads() {
+        // Only the spawned thread reaches its jump, whose target it cannot know
+        let source = ".data\n.code\nspawn $0 @worker\nhlt\nworker: jmp $0\n";
+        let image = Assembler::new().assemble(source).unwrap();
+        assert_eq!(
+            verify(&image),
+            Err(vec![Violation::UnknownJumpTarget { pc: 72 }])
+        );
+    }
+
+    #[test]
+    fn test_verify_requires_reachable_halt() {
+        // The hlt sits behind an unconditional jump back to the start
+        let code = [0, 0, 0, 64, 5, 0, 0, 0, 254, 0, 0, 0];
+        assert_eq!(
+            verify(&image(b"", &code)),
+            Err(vec![Violation::NoReachableHalt])
+        );
+    }
+}
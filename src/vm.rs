@@ -1,18 +1,307 @@
 use crate::{
-    assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX},
-    instruction::Opcode,
+    assembler::{
+        debug_info::DebugInfo, pie_debug_length, pie_imports, pie_imports_length, pie_ro_length,
+        PIE_HEADER_LENGTH, PIE_HEADER_PREFIX,
+    },
+    instruction::{Instruction, Opcode, HLT_STATUS_FROM_REGISTER},
+    verifier::{verify_with_imports, Violation},
 };
+use channel::Message;
+use host::{Bindings, HostFunctions};
+use input::Input;
+use profile::Profile;
+use scheduler::{Threads, Wait, TIME_SLICE};
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use trace::TraceFilter;
 
-// Emulate cpu
+pub mod args;
+pub mod channel;
+pub mod files;
+pub mod host;
+pub mod input;
+pub mod net;
+pub mod pool;
+pub mod profile;
+pub mod scheduler;
+pub mod snapshot;
+pub mod trace;
+
+// Jumps remembered for backtraces
+pub const JUMP_HISTORY_LENGTH: usize = 16;
+// Exit status of a program that faulted
+pub const FAULT_EXIT_STATUS: i32 = 1;
+// Exit status of a program stopped by one of its limits
+pub const LIMIT_EXIT_STATUS: i32 = 2;
+// Instructions run between two looks at the clock
+pub const TIME_CHECK_INTERVAL: u64 = 1024;
+
+/// Bounds for running untrusted programs, None means unlimited. The instruction and time
+/// limits apply to each call of run, so a stopped program can be given more of both.
+/// Stack depth is not limited: there is no call instruction, so nothing grows a stack.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Limits {
+    pub instructions: Option<u64>,
+    pub time: Option<Duration>,
+    /// Bytes the heaps of all green threads and the messages queued on channels may add up to
+    pub heap: Option<usize>,
+    /// Green threads that may be alive at once, the main thread included
+    pub threads: Option<usize>,
+}
+
+/// Which of the [`Limits`] stopped a run
+#[derive(Debug, PartialEq, Clone)]
+pub enum LimitExceeded {
+    Instructions { limit: u64 },
+    Time { limit: Duration },
+    Heap { limit: usize, requested: usize },
+    Threads { limit: usize },
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitExceeded::Instructions { limit } => {
+                write!(f, "Stopped after executing {} instructions", limit)
+            }
+            LimitExceeded::Time { limit } => {
+                write!(f, "Stopped after running for {:?}", limit)
+            }
+            LimitExceeded::Heap { limit, requested } => write!(
+                f,
+                "Stopped because the heap would grow to {} bytes, the limit is {}",
+                requested, limit
+            ),
+            LimitExceeded::Threads { limit } => {
+                write!(
+                    f,
+                    "Stopped because there would be more than {} threads",
+                    limit
+                )
+            }
+        }
+    }
+}
+
+/// How a call to run ended
+#[derive(Debug, PartialEq, Clone)]
+pub enum RunOutcome {
+    /// hlt ran, or the program ran off its end
+    Exited {
+        status: i32,
+    },
+    Faulted {
+        error: VmError,
+    },
+    /// The VM stopped before the instruction at pc and can be run again
+    LimitExceeded {
+        limit: LimitExceeded,
+    },
+}
+
+impl RunOutcome {
+    /// Status for the host process
+    pub fn status(&self) -> i32 {
+        match self {
+            RunOutcome::Exited { status } => *status,
+            RunOutcome::Faulted { .. } => FAULT_EXIT_STATUS,
+            RunOutcome::LimitExceeded { .. } => LIMIT_EXIT_STATUS,
+        }
+    }
+}
+
+/// Faults that stop the VM, pc is where the faulting instruction starts
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    IllegalOpcode {
+        pc: usize,
+        opcode: u8,
+    },
+    DivisionByZero {
+        pc: usize,
+    },
+    InvalidString {
+        pc: usize,
+        offset: usize,
+    },
+    HeapOutOfBounds {
+        pc: usize,
+        offset: usize,
+        length: usize,
+    },
+    /// call_host named an import no host function was bound to
+    UnboundHostFunction {
+        pc: usize,
+        name: String,
+    },
+    /// Raised by a host function
+    HostError {
+        pc: usize,
+        message: String,
+    },
+    /// join named a thread that was never spawned, or the running thread itself
+    UnknownThread {
+        pc: usize,
+        thread: i32,
+    },
+    /// A channel instruction was given a handle chan did not return
+    UnknownChannel {
+        pc: usize,
+        channel: i32,
+    },
+    /// aloc would have made the heap smaller than empty or larger than i32::MAX
+    InvalidAllocation {
+        pc: usize,
+        heap: usize,
+        change: i32,
+    },
+    /// read_int was given a line that is not an integer
+    InvalidInteger {
+        pc: usize,
+        line: String,
+    },
+    /// Every thread is blocked, waits holds each thread's id and what it waits for
+    Deadlock {
+        pc: usize,
+        waits: Vec<(usize, Wait)>,
+    },
+}
+
+impl VmError {
+    pub fn pc(&self) -> usize {
+        match self {
+            VmError::IllegalOpcode { pc, .. } => *pc,
+            VmError::DivisionByZero { pc } => *pc,
+            VmError::InvalidString { pc, .. } => *pc,
+            VmError::HeapOutOfBounds { pc, .. } => *pc,
+            VmError::UnboundHostFunction { pc, .. } => *pc,
+            VmError::HostError { pc, .. } => *pc,
+            VmError::UnknownThread { pc, .. } => *pc,
+            VmError::UnknownChannel { pc, .. } => *pc,
+            VmError::InvalidAllocation { pc, .. } => *pc,
+            VmError::InvalidInteger { pc, .. } => *pc,
+            VmError::Deadlock { pc, .. } => *pc,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::IllegalOpcode { opcode, .. } => write!(f, "Illegal opcode {}", opcode),
+            VmError::DivisionByZero { .. } => write!(f, "Division by zero"),
+            VmError::InvalidString { offset, .. } => write!(
+                f,
+                "No terminated string at offset {} of the read-only section",
+                offset
+            ),
+            VmError::HeapOutOfBounds { offset, length, .. } => write!(
+                f,
+                "Heap access of {} bytes at offset {} is out of bounds",
+                length, offset
+            ),
+            VmError::UnboundHostFunction { name, .. } => {
+                write!(f, "No host function is bound to `{}`", name)
+            }
+            VmError::HostError { message, .. } => write!(f, "{}", message),
+            VmError::UnknownThread { thread, .. } => {
+                write!(f, "There is no other thread with id {} to join", thread)
+            }
+            VmError::UnknownChannel { channel, .. } => {
+                write!(f, "There is no channel with handle {}", channel)
+            }
+            VmError::InvalidAllocation { heap, change, .. } => write!(
+                f,
+                "Cannot change the heap of {} bytes by {} bytes",
+                heap, change
+            ),
+            VmError::InvalidInteger { line, .. } => {
+                write!(f, "Input line `{}` is not an integer", line)
+            }
+            VmError::Deadlock { waits, .. } => {
+                let waits: Vec<String> = waits
+                    .iter()
+                    .map(|(thread, wait)| format!("thread {} {}", thread, wait))
+                    .collect();
+                write!(f, "Every thread is waiting: {}", waits.join(", "))
+            }
+        }
+    }
+}
+
+/// Why Vm::from_image refused an image
+#[derive(Debug, PartialEq, Clone)]
+pub enum LoadError {
+    InvalidImage,
+    FailedVerification { violations: Vec<Violation> },
+    MissingHostFunctions { names: Vec<String> },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::InvalidImage => write!(f, "Not a valid Iridation image"),
+            LoadError::FailedVerification { violations } => {
+                write!(f, "The image failed verification:")?;
+                for violation in violations {
+                    write!(f, "\n  {}", violation)?;
+                }
+                Ok(())
+            }
+            LoadError::MissingHostFunctions { names } => write!(
+                f,
+                "The image calls host functions that are not registered: {}",
+                names.join(", ")
+            ),
+        }
+    }
+}
+
+/// A register machine running one linked image, with its heap and green threads
 #[derive(Debug, PartialEq)]
 pub struct Vm {
+    /// The 32 general purpose registers
     pub registers: [i32; 32],
     pc: usize,
     heap: Vec<u8>,
-    pub program: Vec<u8>,
+    // Only changed through load_image, set_program and add_bytes, which keep decoded in step
+    program: Vec<u8>,
     remainder: u32,
     equal_flag: bool,
     ro_data: Vec<u8>,
+    // Line table loaded from the image, when it was assembled with debug info
+    debug_info: Option<DebugInfo>,
+    // Host functions the image calls, indexed by call_host operands
+    imports: Vec<String>,
+    host_bindings: Bindings,
+    // Where read_line, read_byte and read_int read from, stdin unless set_input was called
+    input: Input,
+    // Start of the instruction being executed
+    instruction_pc: usize,
+    // Most recent jumps as (from, to), newest last
+    jumps: VecDeque<(usize, usize)>,
+    error: Option<VmError>,
+    // Set once hlt runs
+    exit_status: Option<i32>,
+    /// Bounds checked by [`Vm::run`]
+    pub limits: Limits,
+    /// Instructions each green thread runs before another gets a turn, None to only switch
+    /// threads on yield and join
+    pub time_slice: Option<u64>,
+    threads: Threads,
+    /// Instructions logged as they execute, None when tracing is off
+    pub trace: Option<TraceFilter>,
+    /// Execution counts, None when profiling is off
+    pub profile: Option<Profile>,
+    // Code section decoded by predecode, starting at decoded_base. It is dropped whenever
+    // program changes.
+    decoded: Vec<Instruction>,
+    decoded_base: usize,
+    // Set by an instruction that would break a limit, it is not executed
+    limit_exceeded: Option<LimitExceeded>,
+    instructions_executed: u64,
 }
 impl Default for Vm {
     fn default() -> Self {
@@ -20,6 +309,25 @@ impl Default for Vm {
     }
 }
 impl Vm {
+    /// A VM with a linked image loaded, verified and pre-decoded, ready to run.
+    /// The image may not call host functions, see from_image_with_hosts.
+    pub fn from_image(image: Vec<u8>) -> Result<Vm, LoadError> {
+        Vm::from_image_with_hosts(image, &HostFunctions::new())
+    }
+
+    /// As from_image, with the image's call_host imports bound to the given functions
+    pub fn from_image_with_hosts(image: Vec<u8>, hosts: &HostFunctions) -> Result<Vm, LoadError> {
+        let mut vm = Vm::new();
+        if !vm.load_image(image) {
+            return Err(LoadError::InvalidImage);
+        }
+        vm.verify()
+            .map_err(|violations| LoadError::FailedVerification { violations })?;
+        vm.bind_host_functions(hosts)?;
+        vm.predecode();
+        Ok(vm)
+    }
+
     pub fn new() -> Vm {
         Vm {
             registers: [0; 32],
@@ -29,129 +337,157 @@ impl Vm {
             remainder: 0,
             equal_flag: false,
             ro_data: vec![],
+            debug_info: None,
+            imports: vec![],
+            host_bindings: Bindings::default(),
+            input: Input::default(),
+            instruction_pc: 64,
+            jumps: VecDeque::new(),
+            error: None,
+            exit_status: None,
+            limits: Limits::default(),
+            time_slice: Some(TIME_SLICE),
+            threads: Threads::new(),
+            trace: None,
+            profile: None,
+            decoded: vec![],
+            decoded_base: 0,
+            limit_exceeded: None,
+            instructions_executed: 0,
         }
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    // The instruction at pc, from the pre-decoded code when pc is in it.
+    // None when there is no whole instruction left.
+    fn fetch(&self) -> Option<Instruction> {
+        if let Some(index) = self.decoded_index() {
+            return Some(self.decoded[index]);
+        }
+        self.program
+            .get(self.pc..self.pc.checked_add(4)?)
+            .map(Instruction::decode)
+    }
+
+    // Position of pc in the pre-decoded code
+    fn decoded_index(&self) -> Option<usize> {
+        let offset = self.pc.wrapping_sub(self.decoded_base);
+        match offset.is_multiple_of(4) && offset / 4 < self.decoded.len() {
+            true => Some(offset / 4),
+            false => None,
+        }
     }
 
-    fn match_opcode(&mut self, opcode: Opcode) -> bool {
-        match opcode {
+    // Runs one instruction, pc has already been moved past it
+    fn execute(&mut self, instruction: Instruction) -> bool {
+        let registers = &mut self.registers;
+        let a = instruction.register(0);
+        let b = instruction.register(1);
+        let c = instruction.register(2);
+        match instruction.opcode {
             Opcode::LOAD => {
-                let register: usize = self.next_8_bits() as usize;
-                let number: u16 = self.next_16_bits();
-                self.registers[register] = number as i32;
+                registers[a] = instruction.number(1) as i32;
             }
+            // Arithmetic wraps around on overflow, so no program can panic the host
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                registers[c] = registers[a].wrapping_add(registers[b]);
             }
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                registers[c] = registers[a].wrapping_mul(registers[b]);
             }
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                registers[c] = registers[a].wrapping_sub(registers[b]);
             }
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
+                let (register1, register2) = (registers[a], registers[b]);
+                if register2 == 0 {
+                    return self.fault(VmError::DivisionByZero {
+                        pc: self.instruction_pc,
+                    });
+                }
+                // i32::MIN / -1 wraps back to i32::MIN, like the other arithmetic
+                registers[c] = register1.wrapping_div(register2);
+                self.remainder = register1.wrapping_rem(register2) as u32;
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                let target = registers[a];
+                self.jump(target as usize);
             }
+            // Relative jumps count from just past the register operand
             Opcode::JMPB => {
-                self.pc -= self.registers[self.next_8_bits() as usize] as usize;
+                let offset = registers[a] as usize;
+                self.jump((self.instruction_pc + 2).wrapping_sub(offset));
             }
             Opcode::JMPF => {
-                self.pc += self.registers[self.next_8_bits() as usize] as usize;
+                let offset = registers[a] as usize;
+                self.jump((self.instruction_pc + 2).wrapping_add(offset));
             }
             Opcode::EQ => {
-                let val1 = self.registers[self.next_8_bits() as usize];
-                let val2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = val1.eq(&val2);
-                self.next_8_bits();
+                self.equal_flag = registers[a] == registers[b];
             }
             Opcode::NEQ => {
-                let val1 = self.registers[self.next_8_bits() as usize];
-                let val2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = !val1.eq(&val2);
-                self.next_8_bits();
+                self.equal_flag = registers[a] != registers[b];
             }
             Opcode::GT => {
-                let val1 = self.registers[self.next_8_bits() as usize];
-                let val2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = val1 > val2;
-                self.next_8_bits();
+                self.equal_flag = registers[a] > registers[b];
             }
             Opcode::LT => {
-                let val1 = self.registers[self.next_8_bits() as usize];
-                let val2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = val1 < val2;
-                self.next_8_bits();
+                self.equal_flag = registers[a] < registers[b];
             }
             Opcode::GTQ => {
-                let val1 = self.registers[self.next_8_bits() as usize];
-                let val2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = val1 >= val2;
-                self.next_8_bits();
+                self.equal_flag = registers[a] >= registers[b];
             }
             Opcode::LTQ => {
-                let val1 = self.registers[self.next_8_bits() as usize];
-                let val2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = val1 <= val2;
-                self.next_8_bits();
+                self.equal_flag = registers[a] <= registers[b];
             }
             Opcode::JEQ => {
                 if self.equal_flag {
-                    self.pc = self.registers[self.next_8_bits() as usize] as usize;
+                    let target = registers[a];
+                    self.jump(target as usize);
                 }
             }
             Opcode::JNEQ => {
                 if !self.equal_flag {
-                    self.pc = self.registers[self.next_8_bits() as usize] as usize;
+                    let target = registers[a];
+                    self.jump(target as usize);
                 }
             }
-            Opcode::NOP => {
-                self.next_8_bits();
-                self.next_8_bits();
-                self.next_8_bits();
-            }
+            Opcode::NOP => {}
+            // Grows the heap by the register's value, or shrinks it when the value is negative.
+            // The size has to stay between 0 and i32::MAX, the largest offset a register holds.
             Opcode::AlOC => {
-                let register = self.registers[self.next_8_bits() as usize];
-                let new_heap_size = self.heap.len() as i32 + register;
-                self.heap.resize(new_heap_size as usize, 0);
+                let change = registers[a];
+                let size = i32::try_from(self.heap.len())
+                    .ok()
+                    .and_then(|size| size.checked_add(change))
+                    .filter(|size| *size >= 0);
+                let size = match size {
+                    Some(size) => size as usize,
+                    None => {
+                        return self.fault(VmError::InvalidAllocation {
+                            pc: self.instruction_pc,
+                            heap: self.heap.len(),
+                            change,
+                        })
+                    }
+                };
+                if size > self.heap.len() && self.heap_limit_exceeded(size - self.heap.len()) {
+                    return true;
+                }
+                self.heap.resize(size, 0);
             }
             Opcode::INC => {
-                self.registers[self.next_8_bits() as usize] += 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                registers[a] = registers[a].wrapping_add(1);
             }
             Opcode::DEC => {
-                self.registers[self.next_8_bits() as usize] -= 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                registers[a] = registers[a].wrapping_sub(1);
             }
             Opcode::PTRS => {
-                let start = self.next_16_bits() as usize;
-                let mut end = start;
-                let slice = self.ro_data.as_slice();
-
-                while slice[end] != 0 {
-                    end += 1
-                }
+                let bytes = match self.ro_string(instruction.number(0) as usize) {
+                    Ok(bytes) => bytes,
+                    Err(error) => return self.fault(error),
+                };
 
-                let result = std::str::from_utf8(&slice[start..end]);
+                let result = std::str::from_utf8(bytes);
                 match result {
                     Ok(s) => {
                         print!("{}", s);
@@ -161,31 +497,274 @@ impl Vm {
                     }
                 };
             }
-            _ => {
-                println!("This is not an opcode");
+            Opcode::CALLH => return self.call_host(instruction.number(0) as usize),
+            Opcode::SPAWN => return self.spawn(a, instruction.number(1) as usize),
+            Opcode::YIELD => return self.yield_thread(),
+            Opcode::JOIN => return self.join(a),
+            Opcode::CHAN => return self.open_channel(a),
+            Opcode::SEND => {
+                let message = Message::Integer(registers[b]);
+                return self.send(a, Ok(message));
+            }
+            Opcode::SENDB => {
+                let (offset, length) = (registers[b] as usize, registers[c] as usize);
+                let message = self.heap_message(offset, length);
+                return self.send(a, message);
+            }
+            Opcode::RECV => return self.receive([a, b, c], true),
+            Opcode::TRYRECV => return self.receive([a, b, c], false),
+            Opcode::READL => return self.read_line([a, b]),
+            Opcode::READB => return self.read_byte(a),
+            Opcode::READI => return self.read_int(a),
+            Opcode::IGL => {
+                // pc stays just past the illegal opcode
+                self.pc = self.instruction_pc + 1;
+                return self.fault(VmError::IllegalOpcode {
+                    pc: self.instruction_pc,
+                    opcode: self.program[self.instruction_pc],
+                });
+            }
+            Opcode::HLT => {
+                let from_register = instruction.operands[1] == HLT_STATUS_FROM_REGISTER;
+                self.exit_status = Some(match from_register {
+                    true => registers[a],
+                    false => 0,
+                });
                 return true;
             }
         }
         false
     }
 
-    pub fn run(&mut self) {
-        let mut done: bool = false;
+    fn jump(&mut self, target: usize) {
+        if self.jumps.len() == JUMP_HISTORY_LENGTH {
+            self.jumps.pop_front();
+        }
+        self.jumps.push_back((self.instruction_pc, target));
+        self.pc = target;
+    }
+
+    // Stops the VM when adding growth bytes to the heap would take every thread's heaps
+    // together past the heap limit. pc is left on the instruction so it is retried when the
+    // VM resumes.
+    fn heap_limit_exceeded(&mut self, growth: usize) -> bool {
+        let limit = match self.limits.heap {
+            Some(limit) => limit,
+            None => return false,
+        };
+        match self.heap_in_use().checked_add(growth) {
+            Some(requested) if requested <= limit => false,
+            requested => {
+                let requested = requested.unwrap_or(usize::MAX);
+                self.limit_exceeded = Some(LimitExceeded::Heap { limit, requested });
+                self.pc = self.instruction_pc;
+                true
+            }
+        }
+    }
+
+    // Records the fault and returns true so the VM stops
+    fn fault(&mut self, error: VmError) -> bool {
+        self.error = Some(error);
+        true
+    }
+
+    /// The fault that stopped the VM, if any
+    pub fn error(&self) -> Option<&VmError> {
+        self.error.as_ref()
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The NUL terminated string at an offset of the read-only section, without its NUL
+    pub fn ro_string(&self, offset: usize) -> Result<&[u8], VmError> {
+        let rest = self.ro_data.get(offset..).unwrap_or_default();
+        match rest.iter().position(|b| *b == 0) {
+            Some(end) => Ok(&rest[..end]),
+            None => Err(VmError::InvalidString {
+                pc: self.instruction_pc,
+                offset,
+            }),
+        }
+    }
+
+    /// The whole heap, as aloc has grown it
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// The bytes at offset, or [`VmError::HeapOutOfBounds`] when they are not all in the heap
+    pub fn read_heap(&self, offset: usize, length: usize) -> Result<&[u8], VmError> {
+        let range = self.heap_range(offset, length)?;
+        Ok(&self.heap[range])
+    }
+
+    /// Writes inside the heap, it never grows
+    pub fn write_heap(&mut self, offset: usize, bytes: &[u8]) -> Result<(), VmError> {
+        let range = self.heap_range(offset, bytes.len())?;
+        self.heap[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn heap_range(&self, offset: usize, length: usize) -> Result<Range<usize>, VmError> {
+        match offset.checked_add(length) {
+            Some(end) if end <= self.heap.len() => Ok(offset..end),
+            _ => Err(VmError::HeapOutOfBounds {
+                pc: self.instruction_pc,
+                offset,
+                length,
+            }),
+        }
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Source location of pc when the image has debug info, otherwise just the pc
+    pub fn describe_pc(&self, pc: usize) -> String {
+        match &self.debug_info {
+            Some(debug_info) => debug_info.describe(pc as u32),
+            None => format!("pc {}", pc),
+        }
+    }
+
+    /// Where the VM is, followed by the jumps that led there, newest first.
+    /// There are no calls yet, so the jumps are the closest thing to a call stack.
+    pub fn backtrace(&self) -> Vec<String> {
+        let pc = match &self.error {
+            Some(error) => error.pc(),
+            None => self.pc,
+        };
+        let mut frames = vec![format!("#0 {}", self.describe_pc(pc))];
+        for (from, _) in self.jumps.iter().rev() {
+            frames.push(format!(
+                "#{} jumped from {}",
+                frames.len(),
+                self.describe_pc(*from)
+            ));
+        }
+        frames
+    }
+
+    /// The fault, where it happened and how execution got there
+    pub fn error_report(&self) -> Option<String> {
+        let error = self.error.as_ref()?;
+        let mut report = format!("{} at {}\nBacktrace:", error, self.describe_pc(error.pc()));
+        for frame in self.backtrace() {
+            report.push_str("\n  ");
+            report.push_str(&frame);
+        }
+        Some(report)
+    }
+
+    /// Runs until hlt, a fault, the end of the program or one of the limits.
+    /// After a limit the VM is left before the instruction that would have gone over it,
+    /// so run can be called again once the limits are raised.
+    pub fn run(&mut self) -> RunOutcome {
+        let started = Instant::now();
+        let mut executed: u64 = 0;
+        let mut done = self.halted() || self.error.is_some();
+        // Nothing needs to see individual instructions, so pre-decoded ones run directly
+        let direct = self.trace.is_none() && self.profile.is_none();
         while !done {
-            done = self.execute_once();
+            if let Some(limit) = self.check_limits(executed, started) {
+                return RunOutcome::LimitExceeded { limit };
+            }
+            done = match (direct, self.decoded_index()) {
+                (true, Some(index)) => {
+                    self.instruction_pc = self.pc;
+                    self.pc += 4;
+                    self.execute(self.decoded[index])
+                }
+                _ => self.execute_once(),
+            };
+            if let Some(limit) = self.limit_exceeded.take() {
+                return RunOutcome::LimitExceeded { limit };
+            }
+            done = self.schedule(done);
+            executed += 1;
+            self.instructions_executed += 1;
+        }
+        match &self.error {
+            Some(error) => RunOutcome::Faulted {
+                error: error.clone(),
+            },
+            None => RunOutcome::Exited {
+                status: self.status(),
+            },
+        }
+    }
+
+    /// Replaces the VM's limits and runs, see [`Vm::run`]
+    pub fn run_with_limits(&mut self, limits: Limits) -> RunOutcome {
+        self.limits = limits;
+        self.run()
+    }
+
+    fn check_limits(&self, executed: u64, started: Instant) -> Option<LimitExceeded> {
+        if let Some(limit) = self.limits.instructions {
+            if executed >= limit {
+                return Some(LimitExceeded::Instructions { limit });
+            }
+        }
+        if let Some(limit) = self.limits.time {
+            if executed.is_multiple_of(TIME_CHECK_INTERVAL) && started.elapsed() >= limit {
+                return Some(LimitExceeded::Time { limit });
+            }
         }
+        None
+    }
+
+    /// Across every call to run
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    /// Exit status for the host process: hlt's status, or [`FAULT_EXIT_STATUS`] after a fault
+    pub fn status(&self) -> i32 {
+        match (&self.error, self.exit_status) {
+            (Some(_), _) => FAULT_EXIT_STATUS,
+            (None, Some(status)) => status,
+            (None, None) => 0,
+        }
+    }
+
+    pub fn halted(&self) -> bool {
+        self.exit_status.is_some()
     }
 
     fn execute_once(&mut self) -> bool {
-        if self.pc >= (self.program.len() - 1) {
-            return true;
+        let instruction = match self.fetch() {
+            Some(instruction) => instruction,
+            None => return true,
+        };
+        self.instruction_pc = self.pc;
+        let before = self.trace_before();
+        self.pc += 4;
+        let stopped = self.execute(instruction);
+        if let Some(before) = before {
+            self.trace_after(before);
+        }
+        if let (Some(profile), None) = (&mut self.profile, &self.limit_exceeded) {
+            profile.record(
+                self.instruction_pc,
+                self.program[self.instruction_pc],
+                self.heap.len(),
+            );
         }
-        let opcode = self.decode_opcode();
-        self.match_opcode(opcode)
+        stopped
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_once();
+    // Returns true when the VM stopped
+    pub fn run_once(&mut self) -> bool {
+        let stopped = self.execute_once();
+        match self.limit_exceeded {
+            Some(_) => stopped,
+            None => self.schedule(stopped),
+        }
     }
 
     pub fn next_8_bits(&mut self) -> u8 {
@@ -201,15 +780,86 @@ impl Vm {
         self.pc += 2;
         result
     }
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    // Replaces the bytecode, dropping anything predecode made from the old one
+    pub fn set_program(&mut self, program: Vec<u8>) {
+        self.decoded.clear();
+        self.program = program;
+    }
+
     pub fn add_byte(&mut self, v: u8) {
+        self.decoded.clear();
         self.program.push(v)
     }
 
     pub fn add_bytes(&mut self, mut v: Vec<u8>) {
+        self.decoded.clear();
         self.program.append(&mut v)
     }
 
-    fn verify_header(&self) -> bool {
+    // Decodes the code section once so running it skips decoding every instruction again.
+    // Meant for an image that passed verification; pcs outside the decoded code still run.
+    pub fn predecode(&mut self) {
+        self.decoded_base = PIE_HEADER_LENGTH + self.ro_data.len();
+        self.decoded = self
+            .program
+            .get(self.decoded_base..)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(Instruction::decode)
+            .collect();
+    }
+
+    // Loads a linked PIE image: read-only data is copied out of it and execution starts after it.
+    // Host imports are left unbound, see bind_host_functions.
+    // Returns false if the image has no valid header.
+    pub fn load_image(&mut self, mut image: Vec<u8>) -> bool {
+        let (ro_length, debug_length, imports) = match (
+            pie_ro_length(&image),
+            pie_debug_length(&image),
+            pie_imports(&image),
+        ) {
+            (Some(ro_length), Some(debug_length), Some(imports)) => {
+                (ro_length, debug_length, imports)
+            }
+            _ => return false,
+        };
+        let imports_length = pie_imports_length(&image).unwrap_or_default();
+        let debug = image.split_off(image.len() - debug_length);
+        image.truncate(image.len() - imports_length);
+        // The program no longer carries the debug or imports sections, so its header must not
+        // claim them
+        image[8..16].copy_from_slice(&[0; 8]);
+        self.host_bindings = Bindings(vec![None; imports.len()]);
+        self.imports = imports;
+        self.debug_info = match debug_length {
+            0 => None,
+            _ => match DebugInfo::from_bytes(&debug) {
+                Ok(debug_info) => Some(debug_info),
+                Err(_) => return false,
+            },
+        };
+        self.ro_data = image[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + ro_length].to_vec();
+        self.program = image;
+        self.decoded.clear();
+        self.pc = PIE_HEADER_LENGTH + ro_length;
+        self.instruction_pc = self.pc;
+        self.error = None;
+        self.exit_status = None;
+        self.jumps.clear();
+        self.threads = Threads::new();
+        true
+    }
+
+    // Statically checks the loaded program, see verifier::verify
+    pub fn verify(&self) -> Result<(), Vec<Violation>> {
+        verify_with_imports(&self.program, self.imports.len())
+    }
+
+    pub fn verify_header(&self) -> bool {
         if self.program[0..4] != PIE_HEADER_PREFIX {
             return false;
         }
@@ -235,6 +885,70 @@ pub fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_load_image() {
+        let mut image = crate::assembler::pie_header(4);
+        image.extend_from_slice(b"Hi!\0");
+        image.extend_from_slice(&[0, 0, 0, 7]);
+        let mut vm = Vm::new();
+        assert!(vm.load_image(image));
+        assert_eq!(vm.ro_data, b"Hi!\0".to_vec());
+        assert_eq!(vm.pc, 68);
+        vm.run_once();
+        assert_eq!(vm.registers[0], 7);
+
+        assert!(!Vm::new().load_image(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_prts_opcode() {
+        let mut image = crate::assembler::pie_header(4);
+        image.extend_from_slice(b"Hi!\0");
+        image.extend_from_slice(&[20, 0, 0, 0, 0, 3, 0, 9]);
+        let mut vm = Vm::new();
+        vm.load_image(image);
+        vm.run_once();
+        assert_eq!(vm.pc, 72);
+        vm.run_once();
+        assert_eq!(vm.registers[3], 9);
+    }
+
+    #[test]
+    fn test_fault_reports_source_location() {
+        let mut assembler = crate::assembler::base_assembler::Assembler::new();
+        assembler.enable_debug_info();
+        let source =
+            ".data\n.code\nstart: load $1 #0\nload $2 @fail\njmp $2\nfail:   div $0 $1 $0\n";
+        let image = assembler.assemble(source).unwrap();
+        let mut vm = Vm::new();
+        assert!(vm.load_image(image));
+        assert_eq!(vm.program.len(), PIE_HEADER_LENGTH + 16);
+        vm.run();
+        assert_eq!(vm.error(), Some(&VmError::DivisionByZero { pc: 76 }));
+        assert_eq!(
+            vm.error_report().unwrap(),
+            "Division by zero at line 6, column 1 (fail)\nBacktrace:\n  #0 line 6, column 1 (fail)\n  #1 jumped from line 5, column 1 (start+8)"
+        );
+    }
+
+    #[test]
+    fn test_fault_without_debug_info() {
+        let mut vm = Vm::new();
+        vm.program = prepend_header(vec![0, 0, 0, 1, 253, 0, 0, 0]);
+        vm.run();
+        assert_eq!(
+            vm.error(),
+            Some(&VmError::IllegalOpcode {
+                pc: 68,
+                opcode: 253
+            })
+        );
+        assert_eq!(
+            vm.error_report().unwrap(),
+            "Illegal opcode 253 at pc 68\nBacktrace:\n  #0 pc 68"
+        );
+    }
+
     #[test]
     fn check_new() {
         let x = Vm::new();
@@ -251,6 +965,219 @@ mod tests {
         assert_eq!(vm.pc, 68);
     }
 
+    #[test]
+    fn test_hlt_exit_status() {
+        let mut vm = Vm::new();
+        vm.program = prepend_header(vec![0, 2, 0, 7, 254, 2, 1, 0, 18, 2, 0, 0]);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 7 });
+        assert!(vm.halted());
+        // Nothing after hlt runs
+        assert_eq!(vm.registers[2], 7);
+
+        let mut vm = Vm::new();
+        vm.program = prepend_header(vec![0, 2, 0, 7, 254, 2, 0, 0]);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+
+        let mut vm = Vm::new();
+        vm.program = prepend_header(vec![4, 0, 1, 2]);
+        assert_eq!(vm.run().status(), FAULT_EXIT_STATUS);
+        assert!(!vm.halted());
+    }
+
+    #[test]
+    fn test_instruction_limit_is_resumable() {
+        let mut vm = Vm::new();
+        // An endless loop: load $0 #64, jmp $0
+        vm.program = prepend_header(vec![0, 0, 0, 64, 5, 0, 0, 0]);
+        vm.limits.instructions = Some(5);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::LimitExceeded {
+                limit: LimitExceeded::Instructions { limit: 5 }
+            }
+        );
+        assert_eq!(vm.pc, 68);
+        assert_eq!(vm.instructions_executed(), 5);
+
+        // Each run gets a fresh budget and carries on where the last one stopped
+        vm.limits.instructions = Some(2);
+        assert!(matches!(vm.run(), RunOutcome::LimitExceeded { .. }));
+        assert_eq!(vm.pc, 68);
+        assert_eq!(vm.instructions_executed(), 7);
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let mut vm = Vm::new();
+        vm.program = prepend_header(vec![0, 0, 0, 64, 5, 0, 0, 0]);
+        vm.limits.time = Some(Duration::from_millis(20));
+        let outcome = vm.run();
+        assert_eq!(outcome.status(), LIMIT_EXIT_STATUS);
+        assert!(vm.instructions_executed() > 0);
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut vm = Vm::new();
+        vm.registers[0] = 64;
+        vm.program = prepend_header(vec![17, 0, 0, 0, 17, 0, 0, 0, 254, 0, 0, 0]);
+        vm.limits.heap = Some(100);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::LimitExceeded {
+                limit: LimitExceeded::Heap {
+                    limit: 100,
+                    requested: 128
+                }
+            }
+        );
+        assert_eq!(vm.heap.len(), 64);
+        assert_eq!(vm.pc, 68);
+
+        vm.limits.heap = Some(128);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        assert_eq!(vm.heap.len(), 128);
+        // Running a finished program does nothing more
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        assert_eq!(vm.instructions_executed(), 3);
+    }
+
+    #[test]
+    fn test_aloc_shrinks_and_faults_below_empty() {
+        let mut vm = Vm::new();
+        vm.registers[0] = 8;
+        vm.registers[1] = -6;
+        vm.registers[2] = -3;
+        vm.program = prepend_header(vec![17, 0, 0, 0, 17, 1, 0, 0, 17, 2, 0, 0, 254, 0, 0, 0]);
+        // Shrinking is allowed under any heap limit
+        vm.limits.heap = Some(8);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Faulted {
+                error: VmError::InvalidAllocation {
+                    pc: 72,
+                    heap: 2,
+                    change: -3
+                }
+            }
+        );
+        assert_eq!(vm.heap.len(), 2);
+
+        let mut vm = Vm::new();
+        vm.registers[0] = i32::MAX;
+        vm.heap = vec![0; 1];
+        vm.program = prepend_header(vec![17, 0, 0, 0, 254, 0, 0, 0]);
+        assert_eq!(vm.run().status(), FAULT_EXIT_STATUS);
+        assert_eq!(
+            vm.error().unwrap().to_string(),
+            "Cannot change the heap of 1 bytes by 2147483647 bytes"
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_wraps_on_overflow() {
+        let mut vm = Vm::new();
+        vm.registers[0] = 65535;
+        vm.registers[4] = i32::MAX;
+        vm.registers[5] = 1;
+        // mul $0 $0 $1, mul $1 $1 $2, add $4 $5 $6, sub $6 $5 $7, inc $4, dec $6
+        vm.program = prepend_header(vec![
+            3, 0, 0, 1, 3, 1, 1, 2, 1, 4, 5, 6, 2, 6, 5, 7, 18, 4, 0, 0, 19, 6, 0, 0, 254, 0, 0, 0,
+        ]);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        assert_eq!(vm.registers[1], 65535i32.wrapping_mul(65535));
+        assert_eq!(
+            vm.registers[2],
+            vm.registers[1].wrapping_mul(vm.registers[1])
+        );
+        assert_eq!(vm.registers[6], i32::MAX);
+        assert_eq!(vm.registers[7], i32::MAX);
+        assert_eq!(vm.registers[4], i32::MIN);
+    }
+
+    #[test]
+    fn test_div_min_by_minus_one_wraps() {
+        let mut vm = Vm::new();
+        vm.registers[0] = i32::MIN;
+        vm.registers[1] = -1;
+        vm.program = prepend_header(vec![4, 0, 1, 2, 254, 0, 0, 0]);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        assert_eq!(vm.registers[2], i32::MIN);
+        assert_eq!(vm.remainder, 0);
+    }
+
+    #[test]
+    fn test_jumps_out_of_range_stop_the_vm() {
+        // jmp to -1, and jmpb and jmpf by offsets that wrap around
+        for (opcode, target) in [(5, -1), (7, 100), (6, -1)] {
+            let mut vm = Vm::new();
+            vm.registers[0] = target;
+            vm.program = prepend_header(vec![opcode, 0, 0, 0]);
+            assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        }
+    }
+
+    #[test]
+    fn test_predecoded_run_matches() {
+        let source = ".data\n.code\nload $0 #20\nload $1 #0\nload $2 @loop\nloop: dec $0\nadd $0 $3 $3\nneq $0 $1\njeq $2\nhlt $3\n";
+        let image = crate::assembler::base_assembler::Assembler::new()
+            .assemble(source)
+            .unwrap();
+        let mut decoded = Vm::new();
+        decoded.load_image(image.clone());
+        decoded.predecode();
+        assert_eq!(decoded.decoded.len(), 8);
+        let mut vm = Vm::new();
+        vm.load_image(image);
+        assert_eq!(decoded.run(), vm.run());
+        assert_eq!(decoded.registers, vm.registers);
+        assert_eq!(decoded.run(), RunOutcome::Exited { status: 190 });
+    }
+
+    #[test]
+    fn test_set_program_drops_predecoded_code() {
+        let image = crate::assembler::base_assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nhlt $0\n")
+            .unwrap();
+        let mut vm = Vm::new();
+        vm.load_image(image);
+        vm.predecode();
+        let mut program = vm.program().to_vec();
+        // load $0 #2 in place of load $0 #1
+        program[67] = 2;
+        vm.set_program(program);
+        assert!(vm.decoded.is_empty());
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 2 });
+    }
+
+    #[test]
+    fn test_from_image() {
+        let image = crate::assembler::base_assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #4\naloc $0\nhlt\n")
+            .unwrap();
+        let mut vm = Vm::from_image(image.clone()).unwrap();
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        assert_eq!(vm.heap(), &[0; 4]);
+        assert_eq!(
+            vm.write_heap(2, &[1, 2, 3]),
+            Err(VmError::HeapOutOfBounds {
+                pc: 72,
+                offset: 2,
+                length: 3
+            })
+        );
+        assert!(vm.read_heap(usize::MAX, 2).is_err());
+
+        assert_eq!(Vm::from_image(vec![1, 2]), Err(LoadError::InvalidImage));
+        let mut broken = image;
+        let last = broken.len() - 4;
+        broken[last] = 253;
+        assert!(matches!(
+            Vm::from_image(broken),
+            Err(LoadError::FailedVerification { .. })
+        ));
+    }
+
     #[test]
     fn test_opcode_igl() {
         let mut vm = Vm::new();
@@ -347,6 +1274,16 @@ mod tests {
         assert_eq!(vm.pc, 1);
     }
 
+    #[test]
+    fn test_jeq_not_taken_moves_to_next_instruction() {
+        let mut vm = Vm::new();
+        vm.registers[0] = 100;
+        vm.program = prepend_header(vec![14, 0, 0, 0, 17, 0, 0, 0, 18, 1, 0, 0]);
+        vm.run();
+        assert_eq!(vm.heap.len(), 100);
+        assert_eq!(vm.registers[1], 1);
+    }
+
     #[test]
     fn test_nop_opcode() {
         let mut vm = Vm::new();
@@ -0,0 +1,117 @@
+use super::{LimitExceeded, Vm};
+
+// Registers a program finds its arguments and environment in when it starts
+pub const ARGC_REGISTER: usize = 0;
+pub const ARGV_REGISTER: usize = 1;
+pub const ENVC_REGISTER: usize = 2;
+pub const ENVP_REGISTER: usize = 3;
+
+impl Vm {
+    /// Appends the arguments and NAME=value environment entries to the heap as NUL-terminated
+    /// strings. argv and envp are heap offsets of tables holding one 4 byte big-endian heap
+    /// offset per string, argc and envc count the strings. The heap limit applies, so set
+    /// the limits first; over it nothing is copied and the error says how large the heap
+    /// would have grown.
+    pub fn set_arguments(
+        &mut self,
+        args: &[String],
+        env: &[(String, String)],
+    ) -> Result<(), LimitExceeded> {
+        let env: Vec<String> = env
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        // Each string, its NUL and its table entry
+        let size: usize = args.iter().chain(&env).map(|s| s.len() + 5).sum();
+        if let Some(limit) = self.limits.heap {
+            let requested = self.heap_in_use().saturating_add(size);
+            if requested > limit {
+                return Err(LimitExceeded::Heap { limit, requested });
+            }
+        }
+        self.registers[ARGC_REGISTER] = args.len() as i32;
+        self.registers[ARGV_REGISTER] = self.push_strings(args);
+        self.registers[ENVC_REGISTER] = env.len() as i32;
+        self.registers[ENVP_REGISTER] = self.push_strings(&env);
+        Ok(())
+    }
+
+    // The strings followed by their table, returning the table's offset
+    fn push_strings(&mut self, strings: &[String]) -> i32 {
+        let mut offsets = Vec::with_capacity(strings.len());
+        for string in strings {
+            offsets.push(self.heap.len() as i32);
+            self.heap.extend_from_slice(string.as_bytes());
+            self.heap.push(0);
+        }
+        let table = self.heap.len() as i32;
+        for offset in offsets {
+            self.heap.extend_from_slice(&offset.to_be_bytes());
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::host::{register_heap_functions, HostFunctions};
+    use crate::vm::RunOutcome;
+
+    #[test]
+    fn test_arguments_in_heap() {
+        let mut vm = Vm::new();
+        vm.set_arguments(
+            &["a".to_string(), "bc".to_string()],
+            &[("HOME".to_string(), "/h".to_string())],
+        )
+        .unwrap();
+        assert_eq!(vm.registers[ARGC_REGISTER], 2);
+        assert_eq!(vm.registers[ARGV_REGISTER], 5);
+        assert_eq!(vm.registers[ENVC_REGISTER], 1);
+        assert_eq!(vm.registers[ENVP_REGISTER], 21);
+        assert_eq!(
+            vm.heap(),
+            b"a\0bc\0\0\0\0\0\0\0\0\x02HOME=/h\0\0\0\0\x0d".as_slice()
+        );
+    }
+
+    #[test]
+    fn test_arguments_respect_heap_limit() {
+        let args = ["abc".to_string(), "de".to_string()];
+        let mut vm = Vm::new();
+        vm.limits.heap = Some(14);
+        assert_eq!(
+            vm.set_arguments(&args, &[]),
+            Err(LimitExceeded::Heap {
+                limit: 14,
+                requested: 15
+            })
+        );
+        assert!(vm.heap().is_empty());
+        assert_eq!(vm.registers[ARGC_REGISTER], 0);
+
+        vm.limits.heap = Some(15);
+        vm.set_arguments(&args, &[]).unwrap();
+        assert_eq!(vm.heap().len(), 15);
+    }
+
+    #[test]
+    fn test_program_reads_its_arguments() {
+        // Halts with the first byte of the second argument
+        let source = ".data\n.code\nload $4 #4\nadd $1 $4 $0\ncall_host @heap_word\ncall_host @heap_byte\nhlt $0\n";
+        let mut hosts = HostFunctions::new();
+        register_heap_functions(&mut hosts);
+        let image = Assembler::new().assemble(source).unwrap();
+        let mut vm = Vm::from_image_with_hosts(image, &hosts).unwrap();
+        vm.set_arguments(&["first".to_string(), "x".to_string()], &[])
+            .unwrap();
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Exited {
+                status: b'x' as i32
+            }
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use super::scheduler::Wait;
+use super::{Vm, VmError};
+use std::collections::VecDeque;
+
+// Written to the length register when the received message is an integer
+pub const INTEGER_MESSAGE: i32 = -1;
+
+// What travels over a channel. Buffers are copied out of the sender's heap and into the
+// receiver's, threads never share memory.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Integer(i32),
+    Buffer(Vec<u8>),
+}
+
+impl Message {
+    // Bytes a queued message counts for under the heap limit
+    pub(super) fn size(&self) -> usize {
+        match self {
+            Message::Integer(_) => 4,
+            Message::Buffer(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl Vm {
+    // chan: a new channel, its handle goes in the register
+    pub(super) fn open_channel(&mut self, register: usize) -> bool {
+        self.registers[register] = self.threads.channels.len() as i32;
+        self.threads.channels.push(VecDeque::new());
+        false
+    }
+
+    // The channel whose handle is in the register
+    fn channel(&self, register: usize) -> Result<usize, VmError> {
+        let handle = self.registers[register];
+        match handle >= 0 && (handle as usize) < self.threads.channels.len() {
+            true => Ok(handle as usize),
+            false => Err(VmError::UnknownChannel {
+                pc: self.instruction_pc,
+                channel: handle,
+            }),
+        }
+    }
+
+    // send and send_buf. Channels are unbounded so sending never blocks, but queued messages
+    // count toward the heap limit until they are received.
+    pub(super) fn send(&mut self, register: usize, message: Result<Message, VmError>) -> bool {
+        let sent = self
+            .channel(register)
+            .and_then(|channel| Ok((channel, message?)));
+        match sent {
+            Ok((channel, message)) => {
+                if self.heap_limit_exceeded(message.size()) {
+                    return true;
+                }
+                self.threads.channels[channel].push_back(message);
+                false
+            }
+            Err(error) => self.fault(error),
+        }
+    }
+
+    pub(super) fn heap_message(&self, offset: usize, length: usize) -> Result<Message, VmError> {
+        self.read_heap(offset, length)
+            .map(|bytes| Message::Buffer(bytes.to_vec()))
+            .map_err(|_| VmError::HeapOutOfBounds {
+                pc: self.instruction_pc,
+                offset,
+                length,
+            })
+    }
+
+    // recv and try_recv. An integer goes in the value register with INTEGER_MESSAGE as its
+    // length, a buffer is appended to the heap with its offset in the value register.
+    // recv waits for a message, try_recv sets the equal flag when there was one.
+    pub(super) fn receive(&mut self, registers: [usize; 3], wait: bool) -> bool {
+        let [channel, value, length] = registers;
+        let channel = match self.channel(channel) {
+            Ok(channel) => channel,
+            Err(error) => return self.fault(error),
+        };
+        // A buffer moves from the channel to the heap, the heap limit counted it when it was
+        // sent
+        match self.threads.channels[channel].pop_front() {
+            Some(Message::Integer(integer)) => {
+                self.registers[value] = integer;
+                self.registers[length] = INTEGER_MESSAGE;
+            }
+            Some(Message::Buffer(bytes)) => {
+                self.registers[value] = self.heap.len() as i32;
+                self.registers[length] = bytes.len() as i32;
+                self.heap.extend_from_slice(&bytes);
+            }
+            None if wait => return self.block(Wait::Receive { channel }),
+            None => {
+                self.equal_flag = false;
+                return false;
+            }
+        }
+        self.threads.wait = None;
+        if !wait {
+            self.equal_flag = true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::{LimitExceeded, RunOutcome};
+
+    fn vm(source: &str) -> Vm {
+        let image = Assembler::new().assemble(source).unwrap();
+        Vm::from_image(image).unwrap()
+    }
+
+    #[test]
+    fn test_send_and_receive() {
+        // The worker sends 20 and its heap bytes, main waits for both
+        let source = ".data\n.code\nchan $0\nspawn $1 @worker\nrecv $0 $2 $3\nrecv $0 $4 $5\nadd $2 $5 $6\nhlt $6\nworker: load $2 #20\nsend $0 $2\nload $2 #3\naloc $2\nload $4 #0\nsend_buf $0 $4 $2\nhlt\n";
+        let mut vm = vm(source);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 23 });
+        assert_eq!(vm.registers[3], INTEGER_MESSAGE);
+        assert_eq!(vm.registers[4], 0);
+        assert_eq!(vm.heap(), &[0, 0, 0]);
+    }
+
+    #[test]
+    fn test_try_recv() {
+        let source = ".data\n.code\nchan $0\ntry_recv $0 $1 $2\nload $3 #7\nsend $0 $3\ntry_recv $0 $1 $2\nhlt $1\n";
+        let mut vm = vm(source);
+        vm.limits.instructions = Some(2);
+        vm.run();
+        assert!(!vm.equal_flag);
+        vm.limits.instructions = None;
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 7 });
+        assert!(vm.equal_flag);
+    }
+
+    #[test]
+    fn test_channel_faults() {
+        let mut vm = vm(".data\n.code\nload $0 #3\nsend $0 $0\nhlt\n");
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Faulted {
+                error: VmError::UnknownChannel { pc: 68, channel: 3 }
+            }
+        );
+
+        let mut vm = self::vm(".data\n.code\nchan $0\nload $1 #4\nsend_buf $0 $1 $1\nhlt\n");
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Faulted {
+                error: VmError::HeapOutOfBounds {
+                    pc: 72,
+                    offset: 4,
+                    length: 4
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_queued_messages_count_toward_heap_limit() {
+        // Sends its 8 byte heap over and over without receiving
+        let source = ".data\n.code\nchan $0\nload $1 #8\naloc $1\nload $2 #0\nload $3 @loop\nload $4 @done\nloop: send_buf $0 $2 $1\neq $1 $9\njeq $4\njmp $3\ndone: hlt\n";
+        let mut vm = vm(source);
+        vm.limits.heap = Some(30);
+        vm.limits.instructions = Some(100);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::LimitExceeded {
+                limit: LimitExceeded::Heap {
+                    limit: 30,
+                    requested: 32
+                }
+            }
+        );
+        assert_eq!(vm.threads.channels[0].len(), 2);
+
+        // Receiving moves a buffer into the heap without counting it twice
+        let source = ".data\n.code\nchan $0\nload $1 #8\naloc $1\nload $2 #0\nsend_buf $0 $2 $1\nrecv $0 $4 $5\nhlt $5\n";
+        let mut vm = self::vm(source);
+        vm.limits.heap = Some(16);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 8 });
+        assert_eq!(vm.heap_in_use(), 16);
+    }
+
+    #[test]
+    fn test_deadlock_reports_waits() {
+        // Both threads wait for a message nobody will send
+        let source = ".data\n.code\nchan $0\nchan $1\nspawn $2 @worker\nrecv $0 $3 $4\nhlt\nworker: recv $1 $3 $4\nhlt\n";
+        let mut vm = vm(source);
+        let error = VmError::Deadlock {
+            pc: 84,
+            waits: vec![
+                (1, Wait::Receive { channel: 1 }),
+                (0, Wait::Receive { channel: 0 }),
+            ],
+        };
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Faulted {
+                error: error.clone()
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Every thread is waiting: thread 1 receives from channel 1, thread 0 receives from channel 0"
+        );
+    }
+}
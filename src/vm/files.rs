@@ -0,0 +1,322 @@
+use super::host::HostFunctions;
+use super::{Vm, VmError};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Returned by the fs_ host functions when the operation failed
+pub const FS_ERROR: i32 = -1;
+
+// Modes fs_open takes in $1
+pub const OPEN_READ: i32 = 0;
+pub const OPEN_WRITE: i32 = 1;
+pub const OPEN_APPEND: i32 = 2;
+
+/// Files programs reach through host functions, by handle. Every path is taken relative to
+/// the root, and programs cannot name anything outside of it. Clones share the open files.
+#[derive(Debug, Clone)]
+pub struct Filesystem {
+    root: PathBuf,
+    read_only: bool,
+    files: Arc<Mutex<Vec<Option<File>>>>,
+}
+
+impl Filesystem {
+    /// Fails when the root is not an existing directory
+    pub fn new(root: &Path, read_only: bool) -> io::Result<Filesystem> {
+        let root = root.canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Filesystem {
+            root,
+            read_only,
+            files: Arc::default(),
+        })
+    }
+
+    // Where a program's path points, None when it would leave the root. Only plain names
+    // are allowed. Symbolic links in the directories are followed before checking, the file
+    // itself must not be one, which open checks once the file is open.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = Path::new(path);
+        let plain = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !plain || path.is_empty() {
+            return None;
+        }
+        let path = self.root.join(relative);
+        let resolved = path.parent()?.canonicalize().ok()?.join(path.file_name()?);
+        resolved.starts_with(&self.root).then_some(resolved)
+    }
+
+    fn open(&self, path: &str, mode: i32) -> i32 {
+        let path = match self.resolve(path) {
+            Some(path) => path,
+            None => return FS_ERROR,
+        };
+        let file = match mode {
+            OPEN_READ => OpenOptions::new().read(true).open(&path),
+            _ if self.read_only => return FS_ERROR,
+            OPEN_WRITE => open_for_writing(&path, false),
+            OPEN_APPEND => open_for_writing(&path, true),
+            _ => return FS_ERROR,
+        };
+        // Checked after opening, so the path cannot be swapped for a link in between
+        let file = match file {
+            Ok(file) if is_opened_file(&path, &file) => file,
+            _ => return FS_ERROR,
+        };
+        // Only truncated once it is known to be the file inside the root
+        if mode == OPEN_WRITE && file.set_len(0).is_err() {
+            return FS_ERROR;
+        }
+        let mut files = self.files.lock().unwrap();
+        files.push(Some(file));
+        (files.len() - 1) as i32
+    }
+
+    // Runs f on the open file, FS_ERROR when there is none or f failed
+    fn with_file<F>(&self, handle: i32, f: F) -> i32
+    where
+        F: FnOnce(&mut File) -> io::Result<i32>,
+    {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(handle as usize) {
+            Some(Some(file)) => f(file).unwrap_or(FS_ERROR),
+            _ => FS_ERROR,
+        }
+    }
+
+    fn close(&self, handle: i32) -> i32 {
+        match self.files.lock().unwrap().get_mut(handle as usize) {
+            Some(file @ Some(_)) => {
+                *file = None;
+                0
+            }
+            _ => FS_ERROR,
+        }
+    }
+
+    /// Registers the fs_ functions, each returns FS_ERROR when the operation fails:
+    ///
+    /// ```text
+    /// fs_open   $0 = read-only path relative to the root, $1 = OPEN_READ, OPEN_WRITE
+    ///           (creates or truncates) or OPEN_APPEND, returns a file handle. Only
+    ///           OPEN_READ is allowed on a read-only filesystem.
+    /// fs_read   $0 = file, $1 = heap offset, $2 = most bytes to read, returns the bytes
+    ///           read, 0 at the end of the file
+    /// fs_write  $0 = file, $1 = heap offset, $2 = length, returns the bytes written
+    /// fs_seek   $0 = file, $1 = offset, $2 = 0 from the start, 1 from the current
+    ///           position or 2 from the end, returns the new position
+    /// fs_close  $0 = file
+    /// ```
+    pub fn register(&self, hosts: &mut HostFunctions) {
+        let files = self.clone();
+        hosts.register("fs_open", move |vm, args| {
+            let path = path(vm, args[0])?;
+            Ok(files.open(&path, args[1]))
+        });
+        let files = self.clone();
+        hosts.register("fs_read", move |vm, args| {
+            // Checked first, so a bad range faults before anything is read
+            vm.read_heap(args[1] as usize, args[2] as usize)?;
+            let mut buffer = vec![0; args[2] as usize];
+            let read = files.with_file(args[0], |file| Ok(file.read(&mut buffer)? as i32));
+            if read > 0 {
+                vm.write_heap(args[1] as usize, &buffer[..read as usize])?;
+            }
+            Ok(read)
+        });
+        let files = self.clone();
+        hosts.register("fs_write", move |vm, args| {
+            let bytes = vm.read_heap(args[1] as usize, args[2] as usize)?;
+            Ok(files.with_file(args[0], |file| {
+                file.write_all(bytes)?;
+                Ok(bytes.len() as i32)
+            }))
+        });
+        let files = self.clone();
+        hosts.register("fs_seek", move |_, args| {
+            let position = match args[2] {
+                0 if args[1] >= 0 => SeekFrom::Start(args[1] as u64),
+                1 => SeekFrom::Current(args[1] as i64),
+                2 => SeekFrom::End(args[1] as i64),
+                _ => return Ok(FS_ERROR),
+            };
+            Ok(files.with_file(args[0], |file| {
+                let position = file.seek(position)?;
+                i32::try_from(position).map_err(|_| io::ErrorKind::InvalidData.into())
+            }))
+        });
+        let files = self.clone();
+        hosts.register("fs_close", move |_, args| Ok(files.close(args[0])));
+    }
+}
+
+// Opens an existing file without truncating it, or creates one. create_new never follows a
+// symbolic link, not even one pointing at a file that does not exist yet.
+fn open_for_writing(path: &Path, append: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).append(append);
+    match options.open(path) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            options.create_new(true).open(path)
+        }
+        result => result,
+    }
+}
+
+// Whether path names the opened file itself rather than a symbolic link to it
+fn is_opened_file(path: &Path, file: &File) -> bool {
+    match (fs::symlink_metadata(path), file.metadata()) {
+        (Ok(link), Ok(opened)) => !link.file_type().is_symlink() && same_file(&link, &opened),
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+// Without inode numbers only the symbolic link check applies
+#[cfg(not(unix))]
+fn same_file(_: &Metadata, _: &Metadata) -> bool {
+    true
+}
+
+fn path(vm: &Vm, offset: i32) -> Result<String, VmError> {
+    let bytes = vm.ro_string(offset as usize)?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::RunOutcome;
+    use std::fs;
+
+    // A fresh directory under the system temp dir holding the given files
+    fn root(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("iridation-fs-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    fn vm(source: &str, files: &Filesystem) -> Vm {
+        let mut hosts = HostFunctions::new();
+        files.register(&mut hosts);
+        let image = Assembler::new().assemble(source).unwrap();
+        Vm::from_image_with_hosts(image, &hosts).unwrap()
+    }
+
+    #[test]
+    fn test_copy_between_files() {
+        // Skips the first byte of input.txt, reads the rest into the heap and writes it out twice
+        let dir = root("copy", &[("input.txt", "xhello")]);
+        let source = ".data\ninput: .asciiz 'input.txt'\noutput: .asciiz 'out.txt'\n.code\nload $13 #0\nload $0 @input\nload $1 #0\ncall_host @fs_open\nadd $0 $13 $10\nload $1 #1\nload $2 #0\ncall_host @fs_seek\nload $2 #16\naloc $2\nadd $10 $13 $0\nload $1 #0\ncall_host @fs_read\nadd $0 $13 $11\nload $0 @output\nload $1 #1\ncall_host @fs_open\nadd $0 $13 $12\nload $1 #0\nadd $11 $13 $2\ncall_host @fs_write\nadd $12 $13 $0\ncall_host @fs_write\nadd $12 $13 $0\ncall_host @fs_close\nhlt $11\n";
+        let files = Filesystem::new(&dir, false).unwrap();
+        let mut vm = vm(source, &files);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 5 });
+        assert_eq!(
+            fs::read_to_string(dir.join("out.txt")).unwrap(),
+            "hellohello"
+        );
+    }
+
+    #[test]
+    fn test_read_only_and_outside_paths_fail() {
+        let dir = root("sandbox", &[("data.txt", "data")]);
+        let files = Filesystem::new(&dir, true).unwrap();
+        let source = |path: &str, mode: i32| {
+            format!(
+                ".data\npath: .asciiz '{}'\n.code\nload $0 @path\nload $1 #{}\ncall_host @fs_open\nhlt $0\n",
+                path, mode
+            )
+        };
+        let open = |path: &str, mode: i32| vm(&source(path, mode), &files).run().status();
+        assert_eq!(open("data.txt", OPEN_READ), 0);
+        assert_eq!(open("data.txt", OPEN_WRITE), FS_ERROR);
+        assert_eq!(open("new.txt", OPEN_APPEND), FS_ERROR);
+        assert_eq!(open("../data.txt", OPEN_READ), FS_ERROR);
+        assert_eq!(
+            open(&dir.join("data.txt").display().to_string(), OPEN_READ),
+            FS_ERROR
+        );
+        assert_eq!(open("missing.txt", OPEN_READ), FS_ERROR);
+        assert!(!dir.join("new.txt").exists());
+
+        let writable = Filesystem::new(&dir, false).unwrap();
+        assert_eq!(
+            vm(&source("new.txt", OPEN_APPEND), &writable)
+                .run()
+                .status(),
+            0
+        );
+        assert!(dir.join("new.txt").exists());
+        assert!(Filesystem::new(&dir.join("data.txt"), false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symbolic_links_cannot_leave_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let outside = root("outside", &[("secret.txt", "secret")]);
+        let dir = root("links", &[]);
+        symlink(outside.join("secret.txt"), dir.join("secret.txt")).unwrap();
+        symlink(outside.join("created.txt"), dir.join("dangling.txt")).unwrap();
+        let files = Filesystem::new(&dir, false).unwrap();
+        for (path, mode) in [
+            ("secret.txt", OPEN_READ),
+            ("secret.txt", OPEN_WRITE),
+            ("secret.txt", OPEN_APPEND),
+            ("dangling.txt", OPEN_WRITE),
+            ("dangling.txt", OPEN_APPEND),
+        ] {
+            assert_eq!(files.open(path, mode), FS_ERROR, "{} {}", path, mode);
+        }
+        assert_eq!(
+            fs::read_to_string(outside.join("secret.txt")).unwrap(),
+            "secret"
+        );
+        assert!(!outside.join("created.txt").exists());
+
+        // Plain files are still created, truncated and appended to
+        assert_eq!(files.open("plain.txt", OPEN_APPEND), 0);
+        fs::write(dir.join("plain.txt"), "old").unwrap();
+        assert_eq!(files.open("plain.txt", OPEN_WRITE), 1);
+        assert_eq!(fs::read_to_string(dir.join("plain.txt")).unwrap(), "");
+    }
+
+    #[test]
+    fn test_bad_handles_and_ranges() {
+        let dir = root("handles", &[]);
+        let files = Filesystem::new(&dir, false).unwrap();
+        let mut vm = vm(".data\n.code\nload $0 #3\ncall_host @fs_close\nadd $0 $2 $5\nload $1 #0\nload $2 #3\ncall_host @fs_read\nhlt\n", &files);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Faulted {
+                error: VmError::HeapOutOfBounds {
+                    pc: 84,
+                    offset: 0,
+                    length: 3
+                }
+            }
+        );
+        assert_eq!(vm.registers[5], FS_ERROR);
+    }
+}
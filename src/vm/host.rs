@@ -0,0 +1,219 @@
+use super::{LoadError, Vm, VmError};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Registers $0 to $3 are passed to a host function, its result is written to $0
+pub const HOST_ARGUMENT_REGISTERS: usize = 4;
+
+pub type HostFunction = Arc<dyn Fn(&mut Vm, &[i32]) -> Result<i32, VmError> + Send + Sync>;
+
+/// Functions a host offers to programs, under the names call_host refers to them by
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    functions: BTreeMap<String, HostFunction>,
+}
+
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+impl HostFunctions {
+    pub fn new() -> HostFunctions {
+        HostFunctions::default()
+    }
+
+    /// Replaces any function already registered under the name
+    pub fn register<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&mut Vm, &[i32]) -> Result<i32, VmError> + Send + Sync + 'static,
+    {
+        self.functions.insert(name.to_string(), Arc::new(function));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&HostFunction> {
+        self.functions.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.functions.keys().map(|name| name.as_str())
+    }
+}
+
+// The function bound to each import of the loaded image, indexed like call_host operands
+#[derive(Clone, Default)]
+pub(super) struct Bindings(pub Vec<Option<HostFunction>>);
+
+impl fmt::Debug for Bindings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bound: Vec<bool> = self.0.iter().map(|b| b.is_some()).collect();
+        f.debug_tuple("Bindings").field(&bound).finish()
+    }
+}
+
+// Functions cannot be compared, bindings are equal when the same imports are bound
+impl PartialEq for Bindings {
+    fn eq(&self, other: &Bindings) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(&other.0)
+                .all(|(a, b)| a.is_some() == b.is_some())
+    }
+}
+
+impl Vm {
+    // Names of the host functions the loaded image calls
+    pub fn imports(&self) -> &[String] {
+        &self.imports
+    }
+
+    // Binds every import of the loaded image, failing with all the names the host lacks
+    pub fn bind_host_functions(&mut self, hosts: &HostFunctions) -> Result<(), LoadError> {
+        let missing: Vec<String> = self
+            .imports
+            .iter()
+            .filter(|name| hosts.get(name).is_none())
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(LoadError::MissingHostFunctions { names: missing });
+        }
+        self.host_bindings = Bindings(
+            self.imports
+                .iter()
+                .map(|name| hosts.get(name).cloned())
+                .collect(),
+        );
+        Ok(())
+    }
+
+    // A fault raised by a host function at the call_host being executed
+    pub fn host_error(&self, message: &str) -> VmError {
+        VmError::HostError {
+            pc: self.instruction_pc,
+            message: message.to_string(),
+        }
+    }
+
+    pub(super) fn call_host(&mut self, import: usize) -> bool {
+        let function = match self.host_bindings.0.get(import).cloned().flatten() {
+            Some(function) => function,
+            None => {
+                return self.fault(VmError::UnboundHostFunction {
+                    pc: self.instruction_pc,
+                    name: self.imports.get(import).cloned().unwrap_or_default(),
+                })
+            }
+        };
+        let mut arguments = [0; HOST_ARGUMENT_REGISTERS];
+        arguments.copy_from_slice(&self.registers[..HOST_ARGUMENT_REGISTERS]);
+        match function(self, &arguments) {
+            Ok(result) => {
+                self.registers[0] = result;
+                false
+            }
+            Err(error) => self.fault(error),
+        }
+    }
+}
+
+// heap_byte reads the heap byte at offset $0, heap_set_byte writes $1 there. heap_word reads
+// the 4 byte big-endian integer at $0. Programs have no instructions for heap data, so these
+// let them parse and build it.
+pub fn register_heap_functions(hosts: &mut HostFunctions) {
+    hosts.register("heap_byte", |vm, args| {
+        Ok(vm.read_heap(args[0] as usize, 1)?[0] as i32)
+    });
+    hosts.register("heap_word", |vm, args| {
+        let bytes = vm.read_heap(args[0] as usize, 4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    });
+    hosts.register("heap_set_byte", |vm, args| {
+        vm.write_heap(args[0] as usize, &[args[1] as u8])?;
+        Ok(0)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::RunOutcome;
+
+    fn hosts() -> HostFunctions {
+        let mut hosts = HostFunctions::new();
+        hosts.register("square", |_, args| Ok(args[0] * args[0]));
+        hosts.register("store", |vm, args| {
+            vm.write_heap(args[1] as usize, &[args[0] as u8])?;
+            Ok(0)
+        });
+        hosts.register("fail", |vm, _| Err(vm.host_error("host said no")));
+        hosts
+    }
+
+    fn image(source: &str) -> Vec<u8> {
+        Assembler::new().assemble(source).unwrap()
+    }
+
+    #[test]
+    fn test_call_host() {
+        let image =
+            image(".data\n.code\nload $0 #7\ncall_host @square\ncall_host @square\nhlt $0\n");
+        let mut vm = Vm::from_image_with_hosts(image, &hosts()).unwrap();
+        assert_eq!(vm.imports(), &["square".to_string()]);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 2401 });
+    }
+
+    #[test]
+    fn test_host_faults() {
+        let source = ".data\n.code\nload $2 #4\naloc $2\nload $0 #9\nload $1 #3\ncall_host @store\ncall_host @fail\nhlt\n";
+        let mut vm = Vm::from_image_with_hosts(image(source), &hosts()).unwrap();
+        vm.run();
+        assert_eq!(vm.heap(), &[0, 0, 0, 9]);
+        assert_eq!(
+            vm.error(),
+            Some(&VmError::HostError {
+                pc: 84,
+                message: "host said no".to_string()
+            })
+        );
+
+        // Loading without binding leaves the call to fault when it runs
+        let mut vm = Vm::new();
+        vm.load_image(image(".data\n.code\ncall_host @square\nhlt\n"));
+        vm.run();
+        assert_eq!(
+            vm.error(),
+            Some(&VmError::UnboundHostFunction {
+                pc: 64,
+                name: "square".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_heap_functions() {
+        let mut hosts = HostFunctions::new();
+        register_heap_functions(&mut hosts);
+        let source = ".data\n.code\nload $0 #2\naloc $0\nload $0 #1\nload $1 #65\ncall_host @heap_set_byte\nload $0 #1\ncall_host @heap_byte\nhlt $0\n";
+        let mut vm = Vm::from_image_with_hosts(image(source), &hosts).unwrap();
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 65 });
+        assert_eq!(vm.heap(), b"\0A");
+    }
+
+    #[test]
+    fn test_missing_host_functions_fail_to_load() {
+        let image =
+            image(".data\n.code\ncall_host @square\ncall_host @cube\ncall_host @log\nhlt\n");
+        assert_eq!(
+            Vm::from_image_with_hosts(image, &hosts()),
+            Err(LoadError::MissingHostFunctions {
+                names: vec!["cube".to_string(), "log".to_string()]
+            })
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use super::{Vm, VmError};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+// Left in read_byte's register at the end of input
+pub const END_OF_INPUT: i32 = -1;
+
+// The reader behind read_line, read_byte and read_int, shared by every green thread
+#[derive(Default)]
+pub(super) struct Input {
+    // None reads the process's stdin
+    reader: Option<Box<dyn BufRead + Send>>,
+    // A line read_line could not fit in the heap, kept for when it runs again
+    pending: Option<Vec<u8>>,
+}
+
+impl Input {
+    fn new(reader: impl BufRead + Send + 'static) -> Input {
+        Input {
+            reader: Some(Box::new(reader)),
+            pending: None,
+        }
+    }
+
+    // Stdin is locked for each read rather than wrapped in a reader of its own, so every VM
+    // in the process reads through the one buffer the standard library keeps for it and
+    // none of them loses input buffered by another. Output is flushed first, prts does not
+    // end its text with a newline and a prompt has to show before the read blocks.
+    fn read<T>(&mut self, f: impl FnOnce(&mut dyn BufRead) -> T) -> T {
+        match &mut self.reader {
+            Some(reader) => f(reader),
+            None => {
+                let _ = io::stdout().flush();
+                f(&mut io::stdin().lock())
+            }
+        }
+    }
+
+    // The next line without its line ending, None at the end of input. A read error ends the
+    // input as well.
+    fn line(&mut self) -> Option<Vec<u8>> {
+        if let Some(line) = self.pending.take() {
+            return Some(line);
+        }
+        let mut line = vec![];
+        match self.read(|reader| reader.read_until(b'\n', &mut line)) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        Some(line)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.read(|reader| {
+            let byte = *reader.fill_buf().ok()?.first()?;
+            reader.consume(1);
+            Some(byte)
+        })
+    }
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Input")
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+// Readers cannot be compared, inputs are equal when they hold the same pending line
+impl PartialEq for Input {
+    fn eq(&self, other: &Input) -> bool {
+        self.pending == other.pending
+    }
+}
+
+impl Vm {
+    /// Makes read_line, read_byte and read_int read from reader instead of stdin
+    pub fn set_input(&mut self, reader: impl BufRead + Send + 'static) {
+        self.input = Input::new(reader);
+    }
+
+    // read_line: appends the next line to the heap without its line ending, its offset goes
+    // in the first register and its length in the second. The equal flag is set when there
+    // was a line, at the end of input it is cleared and the length is 0.
+    pub(super) fn read_line(&mut self, registers: [usize; 2]) -> bool {
+        let [offset, length] = registers;
+        let line = match self.input.line() {
+            Some(line) => line,
+            None => {
+                self.registers[offset] = self.heap.len() as i32;
+                self.registers[length] = 0;
+                self.equal_flag = false;
+                return false;
+            }
+        };
+        if self.heap_limit_exceeded(line.len()) {
+            self.input.pending = Some(line);
+            return true;
+        }
+        self.registers[offset] = self.heap.len() as i32;
+        self.registers[length] = line.len() as i32;
+        self.heap.extend_from_slice(&line);
+        self.equal_flag = true;
+        false
+    }
+
+    // read_byte: the next byte of input, or END_OF_INPUT with the equal flag cleared
+    pub(super) fn read_byte(&mut self, register: usize) -> bool {
+        let byte = self.input.byte();
+        self.registers[register] = byte.map_or(END_OF_INPUT, i32::from);
+        self.equal_flag = byte.is_some();
+        false
+    }
+
+    // read_int: the next line as an integer, surrounding whitespace is ignored. At the end of
+    // input the register is left alone and the equal flag cleared.
+    pub(super) fn read_int(&mut self, register: usize) -> bool {
+        let line = match self.input.line() {
+            Some(line) => String::from_utf8_lossy(&line).into_owned(),
+            None => {
+                self.equal_flag = false;
+                return false;
+            }
+        };
+        match line.trim().parse() {
+            Ok(value) => {
+                self.registers[register] = value;
+                self.equal_flag = true;
+                false
+            }
+            Err(_) => self.fault(VmError::InvalidInteger {
+                pc: self.instruction_pc,
+                line,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::{LimitExceeded, RunOutcome};
+    use std::io::Cursor;
+
+    fn vm(source: &str, input: &'static str) -> Vm {
+        let image = Assembler::new().assemble(source).unwrap();
+        let mut vm = Vm::from_image(image).unwrap();
+        vm.set_input(Cursor::new(input));
+        vm
+    }
+
+    #[test]
+    fn test_read_lines_bytes_and_ints() {
+        let source = ".data\n.code\nread_int $0\nread_line $1 $2\nread_byte $3\nread_byte $4\nread_byte $5\nhlt\n";
+        let mut vm = vm(source, "12\n hello\r\nxy");
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        assert_eq!(
+            vm.registers[..6],
+            [12, 0, 6, b'x' as i32, b'y' as i32, END_OF_INPUT]
+        );
+        assert_eq!(vm.heap(), b" hello");
+        assert!(!vm.equal_flag);
+    }
+
+    #[test]
+    fn test_sum_until_end_of_input() {
+        let source = ".data\n.code\nload $1 #0\nloop: read_int $0\nload $2 @done\njneq $2\nadd $0 $1 $1\nload $2 @loop\njmp $2\ndone: hlt $1\n";
+        assert_eq!(
+            vm(source, "1\n2\n 39\n").run(),
+            RunOutcome::Exited { status: 42 }
+        );
+        assert_eq!(vm(source, "").run(), RunOutcome::Exited { status: 0 });
+
+        let mut vm = self::vm(source, "1\ntwo\n");
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Faulted {
+                error: VmError::InvalidInteger {
+                    pc: 68,
+                    line: "two".to_string()
+                }
+            }
+        );
+        assert_eq!(
+            vm.error().unwrap().to_string(),
+            "Input line `two` is not an integer"
+        );
+    }
+
+    #[test]
+    fn test_read_line_keeps_line_over_heap_limit() {
+        let mut vm = vm(
+            ".data\n.code\nread_line $0 $1\nread_line $2 $3\nhlt\n",
+            "hello\n",
+        );
+        vm.limits.heap = Some(2);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::LimitExceeded {
+                limit: LimitExceeded::Heap {
+                    limit: 2,
+                    requested: 5
+                }
+            }
+        );
+        vm.limits.heap = None;
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        assert_eq!(vm.registers[..4], [0, 5, 5, 0]);
+        assert_eq!(vm.heap(), b"hello");
+    }
+}
@@ -0,0 +1,251 @@
+use super::host::HostFunctions;
+use super::{Vm, VmError};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+// Returned by the tcp_ host functions when the operation failed
+pub const NET_ERROR: i32 = -1;
+
+#[derive(Debug)]
+enum Socket {
+    Listener(TcpListener),
+    Stream(TcpStream),
+}
+
+/// TCP sockets programs reach through host functions, by handle. Clones share the sockets,
+/// so every VM bound to the same functions sees the same handles.
+///
+/// The tcp_ functions block. While one waits to connect, accept, send or receive, the
+/// operating system thread running the VM waits with it, so none of that VM's green threads
+/// run. A client and a server that talk to each other have to run in different VMs, for
+/// example in a [`VmPool`](super::pool::VmPool).
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    sockets: Arc<Mutex<Vec<Option<Socket>>>>,
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network::default()
+    }
+
+    /// Hands a listener the host bound itself to programs, returning its handle
+    pub fn add_listener(&self, listener: TcpListener) -> i32 {
+        self.add(Socket::Listener(listener))
+    }
+
+    fn add(&self, socket: Socket) -> i32 {
+        let mut sockets = self.sockets.lock().unwrap();
+        sockets.push(Some(socket));
+        (sockets.len() - 1) as i32
+    }
+
+    // The sockets are cloned so blocking on one does not hold up the others
+    fn listener(&self, handle: i32) -> Option<TcpListener> {
+        match self.sockets.lock().unwrap().get(handle as usize) {
+            Some(Some(Socket::Listener(listener))) => listener.try_clone().ok(),
+            _ => None,
+        }
+    }
+
+    fn stream(&self, handle: i32) -> Option<TcpStream> {
+        match self.sockets.lock().unwrap().get(handle as usize) {
+            Some(Some(Socket::Stream(stream))) => stream.try_clone().ok(),
+            _ => None,
+        }
+    }
+
+    fn close(&self, handle: i32) -> i32 {
+        match self.sockets.lock().unwrap().get_mut(handle as usize) {
+            Some(socket @ Some(_)) => {
+                *socket = None;
+                0
+            }
+            _ => NET_ERROR,
+        }
+    }
+
+    /// Registers the tcp_ functions, each returns NET_ERROR when the operation fails:
+    ///
+    /// ```text
+    /// tcp_connect   $0 = read-only string such as `127.0.0.1:8080`, returns a stream handle
+    /// tcp_listen    $0 = read-only address string, returns a listener handle
+    /// tcp_accept    $0 = listener, waits for a connection and returns its stream handle
+    /// tcp_send      $0 = stream, $1 = heap offset, $2 = length, returns the bytes sent
+    /// tcp_send_str  $0 = stream, $1 = read-only string, returns the bytes sent
+    /// tcp_recv      $0 = stream, $1 = heap offset, $2 = most bytes to read, returns the
+    ///               bytes read, 0 once the other side has closed the connection
+    /// tcp_close     $0 = stream or listener
+    /// ```
+    pub fn register(&self, hosts: &mut HostFunctions) {
+        let network = self.clone();
+        hosts.register("tcp_connect", move |vm, args| {
+            let address = address(vm, args[0])?;
+            Ok(match TcpStream::connect(address.as_str()) {
+                Ok(stream) => network.add(Socket::Stream(stream)),
+                Err(_) => NET_ERROR,
+            })
+        });
+        let network = self.clone();
+        hosts.register("tcp_listen", move |vm, args| {
+            let address = address(vm, args[0])?;
+            Ok(match TcpListener::bind(address.as_str()) {
+                Ok(listener) => network.add(Socket::Listener(listener)),
+                Err(_) => NET_ERROR,
+            })
+        });
+        let network = self.clone();
+        hosts.register("tcp_accept", move |_, args| {
+            let accepted = network.listener(args[0]).map(|l| l.accept());
+            Ok(match accepted {
+                Some(Ok((stream, _))) => network.add(Socket::Stream(stream)),
+                _ => NET_ERROR,
+            })
+        });
+        let network = self.clone();
+        hosts.register("tcp_send", move |vm, args| {
+            let bytes = vm.read_heap(args[1] as usize, args[2] as usize)?;
+            Ok(send(network.stream(args[0]), bytes))
+        });
+        let network = self.clone();
+        hosts.register("tcp_send_str", move |vm, args| {
+            let bytes = vm.ro_string(args[1] as usize)?;
+            Ok(send(network.stream(args[0]), bytes))
+        });
+        let network = self.clone();
+        hosts.register("tcp_recv", move |vm, args| {
+            // Checked before blocking, so a bad range faults straight away
+            vm.read_heap(args[1] as usize, args[2] as usize)?;
+            let mut buffer = vec![0; args[2] as usize];
+            let read = match network.stream(args[0]).map(|mut s| s.read(&mut buffer)) {
+                Some(Ok(read)) => read,
+                _ => return Ok(NET_ERROR),
+            };
+            vm.write_heap(args[1] as usize, &buffer[..read])?;
+            Ok(read as i32)
+        });
+        let network = self.clone();
+        hosts.register("tcp_close", move |_, args| Ok(network.close(args[0])));
+    }
+}
+
+fn address(vm: &Vm, offset: i32) -> Result<String, VmError> {
+    let bytes = vm.ro_string(offset as usize)?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn send(stream: Option<TcpStream>, bytes: &[u8]) -> i32 {
+    match stream.map(|mut s| s.write_all(bytes)) {
+        Some(Ok(())) => bytes.len() as i32,
+        _ => NET_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::host::register_heap_functions;
+    use crate::vm::RunOutcome;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::thread;
+
+    const HTTP_LIBRARY: &str = include_str!("../../lib/http.iasm");
+
+    fn hosts(network: &Network) -> HostFunctions {
+        let mut hosts = HostFunctions::new();
+        network.register(&mut hosts);
+        register_heap_functions(&mut hosts);
+        hosts
+    }
+
+    // The program linked with the HTTP library
+    fn http_program(source: &str, network: &Network) -> Vm {
+        let mut linker = crate::linker::Linker::new();
+        let program = Assembler::new().assemble_object(source).unwrap();
+        let library = Assembler::new().assemble_object(HTTP_LIBRARY).unwrap();
+        linker.add_object("main", program);
+        linker.add_object("http", library);
+        Vm::from_image_with_hosts(linker.link().unwrap(), &hosts(network)).unwrap()
+    }
+
+    #[test]
+    fn test_http_get_over_loopback() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = server.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                request.push(line);
+            }
+            let response = "HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\nnothing here";
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            request
+        });
+
+        // The status comes back through join, the response through the channel
+        let source = format!(
+            ".data\naddress: .asciiz '{}'\npath: .asciiz '/missing'\n.code\n.extern http_get\nload $0 @address\nload $1 @path\nchan $2\nspawn $3 @http_get\njoin $3\nrecv $2 $4 $5\nhlt $3\n",
+            address
+        );
+        let mut vm = http_program(&source, &Network::new());
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 404 });
+        let response = vm.read_heap(vm.registers[4] as usize, vm.registers[5] as usize);
+        assert!(response.unwrap().ends_with(b"\r\n\r\nnothing here"));
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                "GET /missing HTTP/1.1\r\n".to_string(),
+                format!("Host: {}\r\n", address),
+                "Connection: close\r\n".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_http_serve_over_loopback() {
+        let network = Network::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let listener = network.add_listener(listener);
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let source = ".data\nbody: .asciiz 'hello from iridation'\n.code\n.extern http_serve_once\nload $1 @body\nchan $2\nspawn $3 @http_serve_once\njoin $3\nrecv $2 $4 $5\nhlt $3\n";
+        let mut vm = http_program(source, &network);
+        vm.registers[0] = listener;
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+        assert_eq!(
+            client.join().unwrap(),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain\r\n\r\nhello from iridation"
+        );
+        let request = vm.read_heap(vm.registers[4] as usize, vm.registers[5] as usize);
+        assert!(request.unwrap().starts_with(b"GET / HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_failures_return_net_error() {
+        let network = Network::new();
+        let source = ".data\nnowhere: .asciiz 'not an address'\n.code\nload $0 @nowhere\ncall_host @tcp_connect\nadd $0 $1 $1\nload $0 #9\ncall_host @tcp_close\nadd $0 $1 $1\nhlt $1\n";
+        let image = Assembler::new().assemble(source).unwrap();
+        let mut vm = Vm::from_image_with_hosts(image, &hosts(&network)).unwrap();
+        assert_eq!(vm.run(), RunOutcome::Exited { status: -2 });
+    }
+}
@@ -0,0 +1,73 @@
+use super::{RunOutcome, Vm};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+
+/// Runs independent VMs on a fixed number of operating system threads. Each VM keeps its own
+/// limits, host functions and green threads.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmPool {
+    workers: usize,
+}
+
+impl VmPool {
+    /// At least one worker is always used
+    pub fn new(workers: usize) -> VmPool {
+        VmPool {
+            workers: workers.max(1),
+        }
+    }
+
+    /// Runs every VM to completion, returning them with their outcomes in the order given
+    pub fn run(&self, vms: Vec<Vm>) -> Vec<(Vm, RunOutcome)> {
+        let count = vms.len();
+        let queue = Mutex::new(vms.into_iter().enumerate().collect::<VecDeque<_>>());
+        let results = Mutex::new(Vec::with_capacity(count));
+        thread::scope(|scope| {
+            for _ in 0..self.workers.min(count) {
+                scope.spawn(|| loop {
+                    let next = queue.lock().unwrap().pop_front();
+                    let (index, mut vm) = match next {
+                        Some(next) => next,
+                        None => return,
+                    };
+                    let outcome = vm.run();
+                    results.lock().unwrap().push((index, vm, outcome));
+                });
+            }
+        });
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(index, _, _)| *index);
+        results
+            .into_iter()
+            .map(|(_, vm, outcome)| (vm, outcome))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+
+    #[test]
+    fn test_pool_runs_every_vm() {
+        let vms: Vec<Vm> = (0..10)
+            .map(|n| {
+                let source = format!(
+                    ".data\n.code\nload $0 #{}\nload $1 #3\nmul $0 $1 $2\nhlt $2\n",
+                    n
+                );
+                Vm::from_image(Assembler::new().assemble(&source).unwrap()).unwrap()
+            })
+            .collect();
+        let results = VmPool::new(3).run(vms);
+        let statuses: Vec<i32> = results
+            .iter()
+            .map(|(_, outcome)| outcome.status())
+            .collect();
+        assert_eq!(statuses, (0..10).map(|n| n * 3).collect::<Vec<i32>>());
+        assert_eq!(results[4].0.registers[2], 12);
+        assert!(VmPool::new(0).run(vec![]).is_empty());
+    }
+}
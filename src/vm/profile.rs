@@ -0,0 +1,142 @@
+use crate::assembler::debug_info::DebugInfo;
+use crate::instruction::Opcode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Addresses listed in the report
+pub const HOT_ADDRESSES: usize = 20;
+
+// Execution counts gathered while the VM runs with profiling enabled
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Profile {
+    // Executions of the instruction starting at each pc
+    pub counts: BTreeMap<usize, u64>,
+    // Executions of each opcode, keyed by its byte
+    pub opcodes: BTreeMap<u8, u64>,
+    pub instructions: u64,
+    pub heap_high_water: usize,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    pub fn record(&mut self, pc: usize, opcode: u8, heap: usize) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+        *self.opcodes.entry(opcode).or_insert(0) += 1;
+        self.instructions += 1;
+        self.heap_high_water = self.heap_high_water.max(heap);
+    }
+
+    // Addresses by execution count, highest first
+    pub fn hottest(&self) -> Vec<(usize, u64)> {
+        let mut hottest: Vec<(usize, u64)> = self.counts.iter().map(|(pc, n)| (*pc, *n)).collect();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hottest
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        match self.instructions {
+            0 => 0.0,
+            total => count as f64 * 100.0 / total as f64,
+        }
+    }
+
+    // The hottest addresses, named by their source line and label when there is debug info
+    pub fn report(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut report = format!(
+            "Profile: {} instructions, heap high-water mark {} bytes\n",
+            self.instructions, self.heap_high_water
+        );
+        report.push_str("Hottest addresses:\n");
+        for (pc, count) in self.hottest().into_iter().take(HOT_ADDRESSES) {
+            let _ = write!(
+                report,
+                "  {:>10} {:>6.2}%  {:04x}",
+                count,
+                self.percent(count),
+                pc
+            );
+            if let Some(debug_info) = debug_info {
+                let _ = write!(report, "  {}", debug_info.describe(pc as u32));
+            }
+            report.push('\n');
+        }
+
+        report.push_str("By opcode:\n");
+        let mut opcodes: Vec<(u8, u64)> = self.opcodes.iter().map(|(o, n)| (*o, *n)).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (opcode, count) in opcodes {
+            let name = Opcode::from(opcode).mnemonic();
+            let _ = writeln!(
+                report,
+                "  {:<9} {:>10} {:>6.2}%",
+                name,
+                count,
+                self.percent(count)
+            );
+        }
+        report
+    }
+
+    // Folded stacks, one `frames count` line per stack, as read by flamegraph tools.
+    // There are no calls yet, so every stack is the single label the instruction sits under.
+    pub fn folded(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        for (pc, count) in &self.counts {
+            let frame = match debug_info.and_then(|d| d.label(*pc as u32)) {
+                Some((name, _)) => name.to_string(),
+                None => format!("{:04x}", pc),
+            };
+            *stacks.entry(frame).or_insert(0) += count;
+        }
+        stacks
+            .iter()
+            .map(|(frame, count)| format!("{} {}\n", frame, count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::Vm;
+
+    fn profiled_run() -> Vm {
+        let mut assembler = Assembler::new();
+        assembler.enable_debug_info();
+        let source = ".data\n.code\nload $0 #3\nload $1 #0\nload $2 @loop\nload $3 #8\naloc $3\nloop: dec $0\nneq $0 $1\njeq $2\nhlt\n";
+        let mut vm = Vm::new();
+        vm.load_image(assembler.assemble(source).unwrap());
+        vm.profile = Some(Profile::new());
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn test_profile_counts() {
+        let vm = profiled_run();
+        let profile = vm.profile.as_ref().unwrap();
+        assert_eq!(profile.instructions, 15);
+        assert_eq!(profile.heap_high_water, 8);
+        assert_eq!(profile.counts[&0x54], 3);
+        assert_eq!(profile.opcodes[&u8::from(Opcode::LOAD)], 4);
+        assert_eq!(profile.hottest()[0], (0x54, 3));
+    }
+
+    #[test]
+    fn test_profile_report() {
+        let vm = profiled_run();
+        let profile = vm.profile.as_ref().unwrap();
+        let report = profile.report(vm.debug_info());
+        assert!(report.starts_with("Profile: 15 instructions, heap high-water mark 8 bytes\n"));
+        assert!(report.contains("           3  20.00%  0054  line 8, column 1 (loop)\n"));
+        assert!(report.contains("  load               4  26.67%\n"));
+        assert!(profile
+            .folded(vm.debug_info())
+            .ends_with("0050 1\nloop 10\n"));
+        assert_eq!(profile.folded(None).lines().next(), Some("0040 1"));
+    }
+}
@@ -0,0 +1,356 @@
+use super::channel::Message;
+use super::{LimitExceeded, Vm, VmError};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::mem;
+
+// Id of the thread a program starts in, the program ends when it halts
+pub const MAIN_THREAD: usize = 0;
+// Instructions a thread runs before the scheduler moves on to the next one
+pub const TIME_SLICE: u64 = 1024;
+
+// Everything a thread does not share with the others, code and read-only data are shared
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(super) struct Context {
+    pub registers: [i32; 32],
+    pub pc: usize,
+    pub instruction_pc: usize,
+    pub remainder: u32,
+    pub equal_flag: bool,
+    pub heap: Vec<u8>,
+    pub jumps: VecDeque<(usize, usize)>,
+}
+
+// What a blocked thread is waiting for
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Wait {
+    Join { thread: usize },
+    Receive { channel: usize },
+}
+
+impl fmt::Display for Wait {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Wait::Join { thread } => write!(f, "joins thread {}", thread),
+            Wait::Receive { channel } => write!(f, "receives from channel {}", channel),
+        }
+    }
+}
+
+// A thread that is not running
+#[derive(Debug, PartialEq, Clone)]
+pub(super) struct Thread {
+    pub id: usize,
+    // The thread cannot run until this is over
+    pub wait: Option<Wait>,
+    pub context: Context,
+}
+
+// Green threads of a VM. The running thread's context lives in the VM itself.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(super) struct Threads {
+    pub current: usize,
+    pub wait: Option<Wait>,
+    pub next_id: usize,
+    // Threads that are not running, in the order they get their turn
+    pub waiting: VecDeque<Thread>,
+    // Exit status of every thread that has finished
+    pub finished: BTreeMap<usize, i32>,
+    // Messages sent and not yet received, indexed by channel handle
+    pub channels: Vec<VecDeque<Message>>,
+    // Instructions the running thread has executed in its slice
+    pub slice_used: u64,
+    // Set by yield, and by a join or recv that has to wait
+    pub switch: bool,
+}
+
+impl Threads {
+    pub fn new() -> Threads {
+        Threads {
+            next_id: MAIN_THREAD + 1,
+            ..Threads::default()
+        }
+    }
+
+    fn can_run(&self, wait: Option<Wait>) -> bool {
+        match wait {
+            Some(Wait::Join { thread }) => self.finished.contains_key(&thread),
+            Some(Wait::Receive { channel }) => !self.channels[channel].is_empty(),
+            None => true,
+        }
+    }
+
+    // Every thread with what it waits for, the running one first
+    fn waits(&self) -> Vec<(usize, Wait)> {
+        let running = self.wait.map(|wait| (self.current, wait));
+        running
+            .into_iter()
+            .chain(
+                self.waiting
+                    .iter()
+                    .filter_map(|thread| thread.wait.map(|wait| (thread.id, wait))),
+            )
+            .collect()
+    }
+}
+
+impl Vm {
+    // Id of the running thread
+    pub fn thread(&self) -> usize {
+        self.threads.current
+    }
+
+    // Threads that have not finished, including the running one
+    pub fn live_threads(&self) -> usize {
+        self.threads.waiting.len() + 1
+    }
+
+    // Bytes in the heaps of every thread and in messages waiting on channels, the heap
+    // limit applies to all of them together
+    pub(super) fn heap_in_use(&self) -> usize {
+        let waiting = self.threads.waiting.iter().map(|t| t.context.heap.len());
+        let queued = self.threads.channels.iter().flatten().map(Message::size);
+        self.heap.len() + waiting.sum::<usize>() + queued.sum::<usize>()
+    }
+
+    // spawn: a new thread starting at target with a copy of the registers, both threads find
+    // its id in the register operand. Under the thread limit the VM stops before the spawn
+    // instead, so it runs again when the VM resumes.
+    pub(super) fn spawn(&mut self, register: usize, target: usize) -> bool {
+        if let Some(limit) = self.limits.threads {
+            if self.live_threads() >= limit {
+                self.limit_exceeded = Some(LimitExceeded::Threads { limit });
+                self.pc = self.instruction_pc;
+                return true;
+            }
+        }
+        let id = self.threads.next_id;
+        self.threads.next_id += 1;
+        self.registers[register] = id as i32;
+        let context = Context {
+            registers: self.registers,
+            pc: target,
+            instruction_pc: target,
+            ..Context::default()
+        };
+        self.threads.waiting.push_back(Thread {
+            id,
+            wait: None,
+            context,
+        });
+        false
+    }
+
+    // join: waits for the thread whose id is in the register, then replaces it with the
+    // thread's exit status
+    pub(super) fn join(&mut self, register: usize) -> bool {
+        let id = self.registers[register] as usize;
+        if let Some(status) = self.threads.finished.get(&id) {
+            self.registers[register] = *status;
+            self.threads.wait = None;
+            return false;
+        }
+        let known = id != self.threads.current && self.threads.waiting.iter().any(|t| t.id == id);
+        if !known {
+            return self.fault(VmError::UnknownThread {
+                pc: self.instruction_pc,
+                thread: self.registers[register],
+            });
+        }
+        // The join runs again once the thread has finished
+        self.block(Wait::Join { thread: id })
+    }
+
+    // Runs the current instruction again once the wait is over
+    pub(super) fn block(&mut self, wait: Wait) -> bool {
+        self.pc = self.instruction_pc;
+        self.threads.wait = Some(wait);
+        self.threads.switch = true;
+        false
+    }
+
+    pub(super) fn yield_thread(&mut self) -> bool {
+        self.threads.switch = true;
+        false
+    }
+
+    // Called after every instruction with whether it stopped the running thread.
+    // Returns true when the whole program has stopped.
+    #[inline]
+    pub(super) fn schedule(&mut self, stopped: bool) -> bool {
+        if stopped {
+            // A fault anywhere, or the main thread halting, ends the program
+            if self.error.is_some() || self.threads.current == MAIN_THREAD {
+                return true;
+            }
+            let status = self.exit_status.take().unwrap_or(0);
+            self.threads.finished.insert(self.threads.current, status);
+            return self.switch_thread(false);
+        }
+        if self.threads.waiting.is_empty() && !self.threads.switch {
+            return false;
+        }
+        self.threads.slice_used += 1;
+        let expired = matches!(self.time_slice, Some(slice) if self.threads.slice_used >= slice);
+        if self.threads.switch || expired {
+            self.threads.switch = false;
+            return self.switch_thread(true);
+        }
+        false
+    }
+
+    // Moves on to the next thread that can run, putting the running one at the back of the
+    // queue unless it has finished
+    fn switch_thread(&mut self, keep_current: bool) -> bool {
+        self.threads.slice_used = 0;
+        let next = self
+            .threads
+            .waiting
+            .iter()
+            .position(|thread| self.threads.can_run(thread.wait));
+        let mut thread = match next {
+            Some(index) => self.threads.waiting.remove(index).unwrap(),
+            None if keep_current && self.threads.can_run(self.threads.wait) => return false,
+            None => {
+                return self.fault(VmError::Deadlock {
+                    pc: self.instruction_pc,
+                    waits: self.threads.waits(),
+                })
+            }
+        };
+        self.swap_context(&mut thread.context);
+        mem::swap(&mut self.threads.current, &mut thread.id);
+        mem::swap(&mut self.threads.wait, &mut thread.wait);
+        if keep_current {
+            self.threads.waiting.push_back(thread);
+        }
+        false
+    }
+
+    fn swap_context(&mut self, context: &mut Context) {
+        mem::swap(&mut self.registers, &mut context.registers);
+        mem::swap(&mut self.pc, &mut context.pc);
+        mem::swap(&mut self.instruction_pc, &mut context.instruction_pc);
+        mem::swap(&mut self.remainder, &mut context.remainder);
+        mem::swap(&mut self.equal_flag, &mut context.equal_flag);
+        mem::swap(&mut self.heap, &mut context.heap);
+        mem::swap(&mut self.jumps, &mut context.jumps);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::RunOutcome;
+
+    fn vm(source: &str) -> Vm {
+        let image = Assembler::new().assemble(source).unwrap();
+        Vm::from_image(image).unwrap()
+    }
+
+    #[test]
+    fn test_spawn_and_join() {
+        // Two workers count down from their own start values, main adds up their statuses
+        let source = ".data\n.code\nload $0 #3\nspawn $1 @worker\nload $0 #5\nspawn $2 @worker\njoin $1\njoin $2\nadd $1 $2 $3\nhlt $3\nworker: load $4 #0\nload $5 #0\nload $6 @loop\nloop: inc $4\ndec $0\nyield\nneq $0 $5\njeq $6\nhlt $4\n";
+        let mut vm = vm(source);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 8 });
+        assert_eq!(vm.thread(), MAIN_THREAD);
+        assert_eq!(vm.threads.finished.len(), 2);
+    }
+
+    #[test]
+    fn test_threads_have_their_own_heap() {
+        let source = ".data\n.code\nload $0 #16\nspawn $1 @worker\naloc $0\njoin $1\nhlt $1\nworker: aloc $0\naloc $0\nhlt $0\n";
+        let mut vm = vm(source);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 16 });
+        assert_eq!(vm.heap().len(), 16);
+    }
+
+    #[test]
+    fn test_time_slices_preempt() {
+        // The worker never yields, main only gets to run again once its slice ends
+        let source = ".data\n.code\nspawn $0 @worker\nyield\nload $1 #1\nhlt $1\nworker: load $2 @worker\njmp $2\n";
+        let mut vm = vm(source);
+        vm.time_slice = Some(10);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 1 });
+        assert_eq!(vm.instructions_executed(), 14);
+
+        // Without preemption the worker keeps running until a limit stops it
+        let mut vm = self::vm(source);
+        vm.time_slice = None;
+        vm.limits.instructions = Some(100);
+        assert!(matches!(vm.run(), RunOutcome::LimitExceeded { .. }));
+        assert_eq!(vm.thread(), 1);
+    }
+
+    #[test]
+    fn test_heap_limit_counts_every_thread() {
+        // Each worker allocates 16 bytes, which only goes over the limit together
+        let source = ".data\n.code\nload $0 #16\nspawn $1 @worker\nspawn $2 @worker\njoin $1\njoin $2\nhlt\nworker: aloc $0\nyield\nhlt\n";
+        let mut vm = vm(source);
+        vm.limits.heap = Some(24);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::LimitExceeded {
+                limit: LimitExceeded::Heap {
+                    limit: 24,
+                    requested: 32
+                }
+            }
+        );
+        assert_eq!(vm.heap_in_use(), 16);
+        vm.limits.heap = Some(32);
+        assert_eq!(vm.run(), RunOutcome::Exited { status: 0 });
+    }
+
+    #[test]
+    fn test_thread_limit() {
+        // Keeps spawning workers that never finish
+        let source = ".data\n.code\nload $2 @main\nload $4 @done\nmain: spawn $1 @worker\neq $1 $9\njeq $4\njmp $2\ndone: hlt\nworker: yield\nload $3 @worker\njmp $3\n";
+        let mut vm = vm(source);
+        vm.limits.threads = Some(3);
+        vm.limits.instructions = Some(1000);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::LimitExceeded {
+                limit: LimitExceeded::Threads { limit: 3 }
+            }
+        );
+        assert_eq!(vm.live_threads(), 3);
+        // The spawn runs again once the limit allows it
+        vm.limits.threads = Some(4);
+        assert!(matches!(
+            vm.run(),
+            RunOutcome::LimitExceeded {
+                limit: LimitExceeded::Threads { limit: 4 }
+            }
+        ));
+        assert_eq!(vm.live_threads(), 4);
+    }
+
+    #[test]
+    fn test_join_faults() {
+        let mut vm = vm(".data\n.code\nload $0 #7\njoin $0\nhlt\n");
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Faulted {
+                error: VmError::UnknownThread { pc: 68, thread: 7 }
+            }
+        );
+
+        // The worker waits for main, which waits for the worker
+        let mut vm = self::vm(
+            ".data\n.code\nspawn $1 @worker\njoin $1\nhlt\nworker: load $0 #0\njoin $0\nhlt\n",
+        );
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Faulted {
+                error: VmError::Deadlock {
+                    pc: 80,
+                    waits: vec![(1, Wait::Join { thread: 0 }), (0, Wait::Join { thread: 1 })]
+                }
+            }
+        );
+    }
+}
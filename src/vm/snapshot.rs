@@ -0,0 +1,623 @@
+use super::channel::Message;
+use super::host::Bindings;
+use super::scheduler::{Context, Thread, Wait};
+use super::{Vm, VmError};
+use crate::assembler::debug_info::DebugInfo;
+use crate::assembler::{pie_ro_length, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
+use crate::codec::{write_long_bytes, write_u32, write_u64, CodecError, Reader};
+use std::collections::VecDeque;
+use std::fmt;
+
+// Constants
+pub const SNAPSHOT_PREFIX: [u8; 4] = [73, 82, 83, 78]; // "IRSN"
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    NotASnapshot,
+    UnsupportedVersion { version: u32 },
+    Truncated,
+    Invalid { field: &'static str },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not an Iridation VM snapshot"),
+            SnapshotError::UnsupportedVersion { version } => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid { field } => write!(f, "snapshot has an invalid {}", field),
+        }
+    }
+}
+
+impl From<CodecError> for SnapshotError {
+    fn from(error: CodecError) -> SnapshotError {
+        match error {
+            CodecError::Truncated => SnapshotError::Truncated,
+            CodecError::InvalidString => SnapshotError::Invalid { field: "string" },
+        }
+    }
+}
+
+impl Vm {
+    // Everything needed to carry on running later: registers, pc, heap, program, read-only
+    // data, remainder, equal flag, how the program ended if it did, the names of the host
+    // functions it calls, its green threads and its debug info. Limits, the time slice, host
+    // function bindings and the input belong to whoever runs the VM and are not saved.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = SNAPSHOT_PREFIX.to_vec();
+        write_u32(&mut out, SNAPSHOT_VERSION);
+        for register in self.registers {
+            out.extend_from_slice(&register.to_le_bytes());
+        }
+        write_u64(&mut out, self.pc as u64);
+        write_u64(&mut out, self.instruction_pc as u64);
+        write_long_bytes(&mut out, &self.heap);
+        write_long_bytes(&mut out, &self.program);
+        write_long_bytes(&mut out, &self.ro_data);
+        write_u32(&mut out, self.remainder);
+        out.push(self.equal_flag as u8);
+        write_u64(&mut out, self.instructions_executed);
+
+        match self.exit_status {
+            Some(status) => {
+                out.push(1);
+                out.extend_from_slice(&status.to_le_bytes());
+            }
+            None => out.push(0),
+        }
+        match &self.error {
+            None => out.push(0),
+            Some(VmError::IllegalOpcode { pc, opcode }) => {
+                out.push(1);
+                write_u64(&mut out, *pc as u64);
+                out.push(*opcode);
+            }
+            Some(VmError::DivisionByZero { pc }) => {
+                out.push(2);
+                write_u64(&mut out, *pc as u64);
+            }
+            Some(VmError::InvalidString { pc, offset }) => {
+                out.push(3);
+                write_u64(&mut out, *pc as u64);
+                write_u64(&mut out, *offset as u64);
+            }
+            Some(VmError::HeapOutOfBounds { pc, offset, length }) => {
+                out.push(4);
+                write_u64(&mut out, *pc as u64);
+                write_u64(&mut out, *offset as u64);
+                write_u64(&mut out, *length as u64);
+            }
+            Some(VmError::UnboundHostFunction { pc, name }) => {
+                out.push(5);
+                write_u64(&mut out, *pc as u64);
+                write_long_bytes(&mut out, name.as_bytes());
+            }
+            Some(VmError::HostError { pc, message }) => {
+                out.push(6);
+                write_u64(&mut out, *pc as u64);
+                write_long_bytes(&mut out, message.as_bytes());
+            }
+            Some(VmError::UnknownThread { pc, thread }) => {
+                out.push(7);
+                write_u64(&mut out, *pc as u64);
+                out.extend_from_slice(&thread.to_le_bytes());
+            }
+            Some(VmError::Deadlock { pc, waits }) => {
+                out.push(8);
+                write_u64(&mut out, *pc as u64);
+                write_u64(&mut out, waits.len() as u64);
+                for (thread, wait) in waits {
+                    write_u64(&mut out, *thread as u64);
+                    write_wait(&mut out, Some(*wait));
+                }
+            }
+            Some(VmError::UnknownChannel { pc, channel }) => {
+                out.push(9);
+                write_u64(&mut out, *pc as u64);
+                out.extend_from_slice(&channel.to_le_bytes());
+            }
+            Some(VmError::InvalidInteger { pc, line }) => {
+                out.push(10);
+                write_u64(&mut out, *pc as u64);
+                write_long_bytes(&mut out, line.as_bytes());
+            }
+            Some(VmError::InvalidAllocation { pc, heap, change }) => {
+                out.push(11);
+                write_u64(&mut out, *pc as u64);
+                write_u64(&mut out, *heap as u64);
+                out.extend_from_slice(&change.to_le_bytes());
+            }
+        }
+        write_u64(&mut out, self.imports.len() as u64);
+        for name in &self.imports {
+            write_long_bytes(&mut out, name.as_bytes());
+        }
+
+        // The running thread's context is the VM's own, saved above
+        let threads = &self.threads;
+        write_u64(&mut out, threads.current as u64);
+        write_wait(&mut out, threads.wait);
+        write_u64(&mut out, threads.next_id as u64);
+        write_u64(&mut out, threads.slice_used);
+        out.push(threads.switch as u8);
+        write_u64(&mut out, threads.waiting.len() as u64);
+        for thread in &threads.waiting {
+            write_u64(&mut out, thread.id as u64);
+            write_wait(&mut out, thread.wait);
+            write_context(&mut out, &thread.context);
+        }
+        write_u64(&mut out, threads.finished.len() as u64);
+        for (id, status) in &threads.finished {
+            write_u64(&mut out, *id as u64);
+            out.extend_from_slice(&status.to_le_bytes());
+        }
+        write_u64(&mut out, threads.channels.len() as u64);
+        for channel in &threads.channels {
+            write_u64(&mut out, channel.len() as u64);
+            for message in channel {
+                match message {
+                    Message::Integer(integer) => {
+                        out.push(0);
+                        out.extend_from_slice(&integer.to_le_bytes());
+                    }
+                    Message::Buffer(bytes) => {
+                        out.push(1);
+                        write_long_bytes(&mut out, bytes);
+                    }
+                }
+            }
+        }
+
+        match &self.debug_info {
+            Some(debug_info) => {
+                out.push(1);
+                write_long_bytes(&mut out, &debug_info.to_bytes());
+            }
+            None => out.push(0),
+        }
+        out
+    }
+
+    pub fn restore(bytes: &[u8]) -> Result<Vm, SnapshotError> {
+        if bytes.len() < 4 || bytes[0..4] != SNAPSHOT_PREFIX {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let mut reader = Reader::new(&bytes[SNAPSHOT_PREFIX.len()..]);
+        let version = reader.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        let mut vm = Vm::new();
+        for register in vm.registers.iter_mut() {
+            *register = reader.u32()? as i32;
+        }
+        vm.pc = reader.u64()? as usize;
+        vm.instruction_pc = reader.u64()? as usize;
+        vm.heap = reader.long_bytes()?.to_vec();
+        vm.program = reader.long_bytes()?.to_vec();
+        vm.ro_data = reader.long_bytes()?.to_vec();
+        vm.remainder = reader.u32()?;
+        vm.equal_flag = read_flag(&mut reader, "equal flag")?;
+        vm.instructions_executed = reader.u64()?;
+
+        if read_flag(&mut reader, "exit status")? {
+            vm.exit_status = Some(reader.u32()? as i32);
+        }
+        vm.error = match reader.u8()? {
+            0 => None,
+            1 => Some(VmError::IllegalOpcode {
+                pc: reader.u64()? as usize,
+                opcode: reader.u8()?,
+            }),
+            2 => Some(VmError::DivisionByZero {
+                pc: reader.u64()? as usize,
+            }),
+            3 => Some(VmError::InvalidString {
+                pc: reader.u64()? as usize,
+                offset: reader.u64()? as usize,
+            }),
+            4 => Some(VmError::HeapOutOfBounds {
+                pc: reader.u64()? as usize,
+                offset: reader.u64()? as usize,
+                length: reader.u64()? as usize,
+            }),
+            5 => Some(VmError::UnboundHostFunction {
+                pc: reader.u64()? as usize,
+                name: read_string(&mut reader, "fault")?,
+            }),
+            6 => Some(VmError::HostError {
+                pc: reader.u64()? as usize,
+                message: read_string(&mut reader, "fault")?,
+            }),
+            7 => Some(VmError::UnknownThread {
+                pc: reader.u64()? as usize,
+                thread: reader.u32()? as i32,
+            }),
+            8 => {
+                let pc = reader.u64()? as usize;
+                let mut waits = vec![];
+                for _ in 0..reader.u64()? {
+                    let thread = reader.u64()? as usize;
+                    let wait =
+                        read_wait(&mut reader)?.ok_or(SnapshotError::Invalid { field: "fault" })?;
+                    waits.push((thread, wait));
+                }
+                Some(VmError::Deadlock { pc, waits })
+            }
+            9 => Some(VmError::UnknownChannel {
+                pc: reader.u64()? as usize,
+                channel: reader.u32()? as i32,
+            }),
+            10 => Some(VmError::InvalidInteger {
+                pc: reader.u64()? as usize,
+                line: read_string(&mut reader, "fault")?,
+            }),
+            11 => Some(VmError::InvalidAllocation {
+                pc: reader.u64()? as usize,
+                heap: reader.u64()? as usize,
+                change: reader.u32()? as i32,
+            }),
+            _ => return Err(SnapshotError::Invalid { field: "fault" }),
+        };
+        for _ in 0..reader.u64()? {
+            let name = read_string(&mut reader, "host import")?;
+            vm.imports.push(name);
+        }
+        vm.host_bindings = Bindings(vec![None; vm.imports.len()]);
+
+        vm.threads.current = reader.u64()? as usize;
+        vm.threads.wait = read_wait(&mut reader)?;
+        vm.threads.next_id = reader.u64()? as usize;
+        vm.threads.slice_used = reader.u64()?;
+        vm.threads.switch = read_flag(&mut reader, "thread switch")?;
+        for _ in 0..reader.u64()? {
+            let id = reader.u64()? as usize;
+            let wait = read_wait(&mut reader)?;
+            let context = read_context(&mut reader)?;
+            vm.threads.waiting.push_back(Thread { id, wait, context });
+        }
+        for _ in 0..reader.u64()? {
+            let id = reader.u64()? as usize;
+            vm.threads.finished.insert(id, reader.u32()? as i32);
+        }
+        for _ in 0..reader.u64()? {
+            let mut channel = VecDeque::new();
+            for _ in 0..reader.u64()? {
+                channel.push_back(match reader.u8()? {
+                    0 => Message::Integer(reader.u32()? as i32),
+                    1 => Message::Buffer(reader.long_bytes()?.to_vec()),
+                    _ => return Err(SnapshotError::Invalid { field: "message" }),
+                });
+            }
+            vm.threads.channels.push(channel);
+        }
+        // A thread can only wait on a channel that exists
+        let channels = vm.threads.channels.len();
+        let waits = vm.threads.waiting.iter().map(|t| t.wait);
+        let invalid_wait = waits
+            .chain([vm.threads.wait])
+            .any(|wait| matches!(wait, Some(Wait::Receive { channel }) if channel >= channels));
+        if invalid_wait {
+            return Err(SnapshotError::Invalid { field: "thread" });
+        }
+        // Every thread has to resume on an instruction the verifier can see
+        let code_start = code_start(&vm)?;
+        let contexts = vm.threads.waiting.iter().map(|t| &t.context);
+        let pcs = contexts
+            .flat_map(|context| [context.pc, context.instruction_pc])
+            .chain([vm.pc, vm.instruction_pc]);
+        for pc in pcs {
+            let boundary = pc >= code_start && (pc - code_start).is_multiple_of(4);
+            if !boundary && pc < vm.program.len() {
+                return Err(SnapshotError::Invalid { field: "pc" });
+            }
+        }
+        if read_flag(&mut reader, "debug info")? {
+            let debug_info = DebugInfo::from_bytes(reader.long_bytes()?).map_err(|_| {
+                SnapshotError::Invalid {
+                    field: "debug info",
+                }
+            })?;
+            vm.debug_info = Some(debug_info);
+        }
+        Ok(vm)
+    }
+}
+
+// Where instructions start: after the header and read-only data of an image, or at 0 for the
+// bare bytecode the REPL builds. The read-only data must be the image's own, since predecode
+// starts decoding after it.
+fn code_start(vm: &Vm) -> Result<usize, SnapshotError> {
+    if !vm.program.starts_with(&PIE_HEADER_PREFIX) {
+        return Ok(0);
+    }
+    let start = PIE_HEADER_LENGTH + vm.ro_data.len();
+    match pie_ro_length(&vm.program) {
+        Some(length)
+            if length == vm.ro_data.len() && vm.program[PIE_HEADER_LENGTH..start] == vm.ro_data =>
+        {
+            Ok(start)
+        }
+        _ => Err(SnapshotError::Invalid {
+            field: "read-only data",
+        }),
+    }
+}
+
+fn write_wait(out: &mut Vec<u8>, wait: Option<Wait>) {
+    match wait {
+        None => out.push(0),
+        Some(Wait::Join { thread }) => {
+            out.push(1);
+            write_u64(out, thread as u64);
+        }
+        Some(Wait::Receive { channel }) => {
+            out.push(2);
+            write_u64(out, channel as u64);
+        }
+    }
+}
+
+// A thread that is not running, its jump history is not saved
+fn write_context(out: &mut Vec<u8>, context: &Context) {
+    for register in context.registers {
+        out.extend_from_slice(&register.to_le_bytes());
+    }
+    write_u64(out, context.pc as u64);
+    write_u64(out, context.instruction_pc as u64);
+    write_u32(out, context.remainder);
+    out.push(context.equal_flag as u8);
+    write_long_bytes(out, &context.heap);
+}
+
+fn read_flag(reader: &mut Reader, field: &'static str) -> Result<bool, SnapshotError> {
+    match reader.u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(SnapshotError::Invalid { field }),
+    }
+}
+
+fn read_wait(reader: &mut Reader) -> Result<Option<Wait>, SnapshotError> {
+    match reader.u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(Wait::Join {
+            thread: reader.u64()? as usize,
+        })),
+        2 => Ok(Some(Wait::Receive {
+            channel: reader.u64()? as usize,
+        })),
+        _ => Err(SnapshotError::Invalid { field: "thread" }),
+    }
+}
+
+fn read_context(reader: &mut Reader) -> Result<Context, SnapshotError> {
+    let mut context = Context::default();
+    for register in context.registers.iter_mut() {
+        *register = reader.u32()? as i32;
+    }
+    context.pc = reader.u64()? as usize;
+    context.instruction_pc = reader.u64()? as usize;
+    context.remainder = reader.u32()?;
+    context.equal_flag = read_flag(reader, "equal flag")?;
+    context.heap = reader.long_bytes()?.to_vec();
+    Ok(context)
+}
+
+fn read_string(reader: &mut Reader, field: &'static str) -> Result<String, SnapshotError> {
+    reader.long_string().map_err(|error| match error {
+        CodecError::InvalidString => SnapshotError::Invalid { field },
+        error => error.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::base_assembler::Assembler;
+    use crate::vm::{LimitExceeded, RunOutcome};
+
+    #[test]
+    fn test_snapshot_resumes_where_it_stopped() {
+        let mut assembler = Assembler::new();
+        assembler.enable_debug_info();
+        let source = ".data\n.code\nload $0 #10\nload $1 #0\nload $2 @loop\nloop: dec $0\nadd $3 $0 $3\nneq $0 $1\njeq $2\nhlt $3\n";
+        let image = assembler.assemble(source).unwrap();
+
+        let mut vm = Vm::new();
+        vm.load_image(image);
+        vm.registers[9] = -5;
+        vm.limits.instructions = Some(12);
+        assert_eq!(
+            vm.run(),
+            RunOutcome::LimitExceeded {
+                limit: LimitExceeded::Instructions { limit: 12 }
+            }
+        );
+
+        let mut restored = Vm::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored, vm_without_limits(vm));
+        assert_eq!(restored.registers[9], -5);
+        assert!(restored.debug_info().is_some());
+        assert_eq!(restored.run(), RunOutcome::Exited { status: 45 });
+    }
+
+    fn vm_without_limits(mut vm: Vm) -> Vm {
+        vm.limits = Default::default();
+        vm.limit_exceeded = None;
+        // Jump history is only used for backtraces and is not saved
+        vm.jumps.clear();
+        vm
+    }
+
+    #[test]
+    fn test_snapshot_keeps_faults() {
+        let mut vm = Vm::new();
+        vm.program = crate::vm::prepend_header(vec![4, 0, 1, 2]);
+        vm.run();
+        let restored = Vm::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.error(), Some(&VmError::DivisionByZero { pc: 64 }));
+
+        let mut vm = Vm::new();
+        vm.registers[0] = -1;
+        vm.program = crate::vm::prepend_header(vec![17, 0, 0, 0]);
+        vm.run();
+        let restored = Vm::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.error(), vm.error());
+        assert!(matches!(
+            restored.error(),
+            Some(VmError::InvalidAllocation { change: -1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_host_imports() {
+        let image = Assembler::new()
+            .assemble(".data\n.code\ncall_host @tick\ncall_host @tick\nhlt $0\n")
+            .unwrap();
+        let mut hosts = crate::vm::host::HostFunctions::new();
+        hosts.register("tick", |_, args| Ok(args[0] + 1));
+        let mut vm = Vm::from_image_with_hosts(image, &hosts).unwrap();
+        vm.limits.instructions = Some(1);
+        vm.run();
+        let snapshot = vm.snapshot();
+
+        // Host functions must be bound again after restoring
+        let mut restored = Vm::restore(&snapshot).unwrap();
+        assert_eq!(restored.imports(), &["tick".to_string()]);
+        assert_eq!(
+            restored.run(),
+            RunOutcome::Faulted {
+                error: VmError::UnboundHostFunction {
+                    pc: 68,
+                    name: "tick".to_string()
+                }
+            }
+        );
+        let restored_fault = Vm::restore(&restored.snapshot()).unwrap();
+        assert_eq!(restored_fault.error(), restored.error());
+
+        let mut restored = Vm::restore(&snapshot).unwrap();
+        restored.bind_host_functions(&hosts).unwrap();
+        assert_eq!(restored.run(), RunOutcome::Exited { status: 2 });
+    }
+
+    #[test]
+    fn test_snapshot_keeps_threads() {
+        let source = ".data\n.code\nload $0 #4\nspawn $1 @worker\naloc $0\njoin $1\nhlt $1\nworker: aloc $0\ndec $0\nyield\nhlt $0\n";
+        let image = Assembler::new().assemble(source).unwrap();
+        let mut vm = Vm::new();
+        vm.load_image(image);
+        vm.limits.instructions = Some(6);
+        vm.run();
+        assert_eq!(vm.thread(), 1);
+
+        let mut restored = Vm::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored, vm_without_limits(vm));
+        assert_eq!(restored.run(), RunOutcome::Exited { status: 3 });
+        assert_eq!(restored.heap().len(), 4);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_channels() {
+        let source = ".data\n.code\nchan $0\nload $1 #2\naloc $1\nsend $0 $1\nsend_buf $0 $2 $1\nrecv $0 $3 $4\nrecv $0 $3 $4\nrecv $0 $3 $4\nhlt\n";
+        let image = Assembler::new().assemble(source).unwrap();
+        let mut vm = Vm::new();
+        vm.load_image(image);
+        vm.limits.instructions = Some(5);
+        vm.run();
+
+        let mut restored = Vm::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored, vm_without_limits(vm));
+        let outcome = restored.run();
+        assert_eq!(restored.heap(), &[0, 0, 0, 0]);
+        assert_eq!(
+            Vm::restore(&restored.snapshot()).unwrap().error(),
+            restored.error()
+        );
+        assert!(matches!(
+            outcome,
+            RunOutcome::Faulted {
+                error: VmError::Deadlock { .. }
+            }
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_pcs_between_instructions() {
+        let image = Assembler::new()
+            .assemble(".data\n.code\nload $0 #51200\nhlt\n")
+            .unwrap();
+        let loaded = || {
+            let mut vm = Vm::new();
+            vm.load_image(image.clone());
+            vm
+        };
+        assert!(Vm::restore(&loaded().snapshot()).is_ok());
+
+        let mut tampered = loaded();
+        tampered.pc += 1;
+        assert_eq!(
+            Vm::restore(&tampered.snapshot()),
+            Err(SnapshotError::Invalid { field: "pc" })
+        );
+        let mut tampered = loaded();
+        tampered.instruction_pc = 0;
+        assert_eq!(
+            Vm::restore(&tampered.snapshot()),
+            Err(SnapshotError::Invalid { field: "pc" })
+        );
+        // Decoding would start in the middle of the first instruction
+        let mut tampered = loaded();
+        tampered.ro_data.push(0);
+        assert_eq!(
+            Vm::restore(&tampered.snapshot()),
+            Err(SnapshotError::Invalid {
+                field: "read-only data"
+            })
+        );
+        // Running off the end stops the program, so pcs there are kept
+        let mut ended = loaded();
+        ended.pc = ended.program().len() + 3;
+        assert!(Vm::restore(&ended.snapshot()).is_ok());
+    }
+
+    #[test]
+    fn test_snapshot_rejects_thread_pcs_between_instructions() {
+        let source =
+            ".data\n.code\nload $0 #4\nspawn $1 @worker\njoin $1\nhlt $1\nworker: yield\nhlt $0\n";
+        let image = Assembler::new().assemble(source).unwrap();
+        let mut vm = Vm::new();
+        vm.load_image(image);
+        vm.limits.instructions = Some(3);
+        vm.run();
+        assert_eq!(vm.threads.waiting.len(), 1);
+
+        vm.threads.waiting[0].context.pc += 2;
+        assert_eq!(
+            Vm::restore(&vm.snapshot()),
+            Err(SnapshotError::Invalid { field: "pc" })
+        );
+    }
+
+    #[test]
+    fn test_invalid_snapshots() {
+        assert_eq!(Vm::restore(b"-PI-"), Err(SnapshotError::NotASnapshot));
+        let bytes = Vm::new().snapshot();
+        assert_eq!(
+            Vm::restore(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+        let mut bytes = Vm::new().snapshot();
+        bytes[4] = 7;
+        assert_eq!(
+            Vm::restore(&bytes),
+            Err(SnapshotError::UnsupportedVersion { version: 7 })
+        );
+    }
+}
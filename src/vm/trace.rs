@@ -0,0 +1,228 @@
+use super::Vm;
+use crate::instruction::Opcode;
+use nom::types::CompleteStr;
+use std::ops::Range;
+
+// Log target of the trace, so RUST_LOG can select it on its own
+pub const TRACE_TARGET: &str = "iridation::trace";
+
+// Which instructions are traced, an empty filter traces everything
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TraceFilter {
+    pub range: Option<Range<usize>>,
+    pub opcodes: Vec<Opcode>,
+}
+
+impl TraceFilter {
+    pub fn new() -> TraceFilter {
+        TraceFilter::default()
+    }
+
+    pub fn matches(&self, pc: usize, opcode: Opcode) -> bool {
+        let in_range = match &self.range {
+            Some(range) => range.contains(&pc),
+            None => true,
+        };
+        in_range && (self.opcodes.is_empty() || self.opcodes.contains(&opcode))
+    }
+}
+
+// An address range such as `64..128` or `0x40..0x80`, the end is exclusive
+pub fn parse_range(text: &str) -> Result<Range<usize>, String> {
+    let (start, end) = text
+        .split_once("..")
+        .ok_or(format!("`{}` is not a range like 64..128", text))?;
+    let start = parse_address(start)?;
+    let end = parse_address(end)?;
+    match start < end {
+        true => Ok(start..end),
+        false => Err(format!("`{}` is an empty range", text)),
+    }
+}
+
+fn parse_address(text: &str) -> Result<usize, String> {
+    let text = text.trim();
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse::<usize>(),
+    };
+    parsed.map_err(|_| format!("`{}` is not an address", text))
+}
+
+// Comma separated mnemonics such as `load,jmp`
+pub fn parse_opcodes(text: &str) -> Result<Vec<Opcode>, String> {
+    text.split(',')
+        .map(|name| match Opcode::from(CompleteStr(name.trim())) {
+            Opcode::IGL => Err(format!("`{}` is not an opcode", name.trim())),
+            opcode => Ok(opcode),
+        })
+        .collect()
+}
+
+// The instruction as it would be written in assembly, with operands decoded by opcode
+pub fn disassemble(bytes: &[u8]) -> String {
+    let opcode = Opcode::from(bytes[0]);
+    let mnemonic = opcode.mnemonic().to_string();
+    let register = |i: usize| format!("${}", bytes[i]);
+    let number = |i: usize| ((bytes[i] as u16) << 8) | bytes[i + 1] as u16;
+    let operands = match opcode {
+        Opcode::LOAD | Opcode::SPAWN => vec![register(1), format!("#{}", number(2))],
+        Opcode::ADD
+        | Opcode::SUB
+        | Opcode::MUL
+        | Opcode::DIV
+        | Opcode::SENDB
+        | Opcode::RECV
+        | Opcode::TRYRECV => vec![register(1), register(2), register(3)],
+        Opcode::SEND | Opcode::READL => vec![register(1), register(2)],
+        Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => {
+            vec![register(1), register(2)]
+        }
+        Opcode::JMP
+        | Opcode::JMPF
+        | Opcode::JMPB
+        | Opcode::JEQ
+        | Opcode::JNEQ
+        | Opcode::AlOC
+        | Opcode::INC
+        | Opcode::DEC
+        | Opcode::JOIN
+        | Opcode::CHAN
+        | Opcode::READB
+        | Opcode::READI => vec![register(1)],
+        Opcode::HLT if bytes[2] != 0 => vec![register(1)],
+        Opcode::PTRS => vec![format!("@{}", number(1))],
+        // The operand is an index into the image's host imports
+        Opcode::CALLH => vec![format!("#{}", number(1))],
+        Opcode::IGL => vec![format!("{}", bytes[0])],
+        Opcode::HLT | Opcode::NOP | Opcode::YIELD => vec![],
+    };
+    match operands.is_empty() {
+        true => mnemonic,
+        false => format!("{} {}", mnemonic, operands.join(" ")),
+    }
+}
+
+// VM state an instruction may change, taken before it runs
+pub(super) struct Before {
+    pc: usize,
+    registers: [i32; 32],
+    equal_flag: bool,
+    heap: usize,
+}
+
+impl Vm {
+    // Some when this instruction should be traced
+    pub(super) fn trace_before(&self) -> Option<Before> {
+        let filter = self.trace.as_ref()?;
+        let opcode = Opcode::from(*self.program.get(self.pc)?);
+        if !filter.matches(self.pc, opcode)
+            || !log_enabled!(target: TRACE_TARGET, log::Level::Trace)
+        {
+            return None;
+        }
+        Some(Before {
+            pc: self.pc,
+            registers: self.registers,
+            equal_flag: self.equal_flag,
+            heap: self.heap.len(),
+        })
+    }
+
+    pub(super) fn trace_after(&self, before: Before) {
+        // An instruction stopped by a limit did not run
+        if self.limit_exceeded.is_some() {
+            return;
+        }
+        trace!(target: TRACE_TARGET, "{}", self.trace_line(&before));
+    }
+
+    // Such as `0048 add $0 $1 $2 | $2 = 7`
+    fn trace_line(&self, before: &Before) -> String {
+        let end = (before.pc + 4).min(self.program.len());
+        let mut bytes = self.program[before.pc..end].to_vec();
+        bytes.resize(4, 0);
+        let mut line = format!("{:04x} {}", before.pc, disassemble(&bytes));
+
+        let mut changes = vec![];
+        for (register, (old, new)) in before.registers.iter().zip(self.registers).enumerate() {
+            if *old != new {
+                changes.push(format!("${} = {}", register, new));
+            }
+        }
+        if before.equal_flag != self.equal_flag {
+            changes.push(format!("flag = {}", self.equal_flag));
+        }
+        if before.heap != self.heap.len() {
+            changes.push(format!("heap = {} bytes", self.heap.len()));
+        }
+        if self.pc != before.pc + 4 && self.error.is_none() && !self.halted() {
+            changes.push(format!("pc = {:04x}", self.pc));
+        }
+        if !changes.is_empty() {
+            line.push_str(" | ");
+            line.push_str(&changes.join(", "));
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::prepend_header;
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(&[0, 1, 1, 244]), "load $1 #500");
+        assert_eq!(disassemble(&[1, 0, 1, 2]), "add $0 $1 $2");
+        assert_eq!(disassemble(&[14, 3, 0, 0]), "jeq $3");
+        assert_eq!(disassemble(&[254, 2, 1, 0]), "hlt $2");
+        assert_eq!(disassemble(&[254, 0, 0, 0]), "hlt");
+        assert_eq!(disassemble(&[20, 0, 8, 0]), "prts @8");
+        assert_eq!(disassemble(&[200, 0, 0, 0]), "igl 200");
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        let mut filter = TraceFilter::new();
+        assert!(filter.matches(64, Opcode::LOAD));
+        filter.range = Some(parse_range("0x44..72").unwrap());
+        filter.opcodes = parse_opcodes("load, jmp").unwrap();
+        assert!(filter.matches(68, Opcode::JMP));
+        assert!(!filter.matches(64, Opcode::LOAD));
+        assert!(!filter.matches(68, Opcode::ADD));
+        assert!(parse_range("72..64").is_err());
+        assert!(parse_range("64").is_err());
+        assert_eq!(
+            parse_opcodes("load,bogus"),
+            Err("`bogus` is not an opcode".to_string())
+        );
+    }
+
+    #[test]
+    fn test_trace_line() {
+        let mut vm = Vm::new();
+        vm.program = prepend_header(vec![0, 0, 0, 3, 0, 1, 0, 3, 8, 0, 1, 0, 0, 2, 0, 64]);
+        let mut lines = vec![];
+        for _ in 0..4 {
+            let before = Before {
+                pc: vm.pc,
+                registers: vm.registers,
+                equal_flag: vm.equal_flag,
+                heap: vm.heap.len(),
+            };
+            vm.run_once();
+            lines.push(vm.trace_line(&before));
+        }
+        assert_eq!(
+            lines,
+            vec![
+                "0040 load $0 #3 | $0 = 3",
+                "0044 load $1 #3 | $1 = 3",
+                "0048 eq $0 $1 | flag = true",
+                "004c load $2 #64 | $2 = 64",
+            ]
+        );
+    }
+}
@@ -0,0 +1,26 @@
+// Runs the binary with its stdin piped, as a shell pipeline would
+
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_program_reads_piped_stdin() {
+    // Prompts without a newline, then adds up the two integers it reads
+    let source = ".data\nprompt: .asciiz 'numbers? '\n.code\nprts @prompt\nread_int $0\nread_int $1\nadd $0 $1 $2\nhlt $2\n";
+    let path = std::env::temp_dir().join(format!("iridation-stdin-{}.iasm", std::process::id()));
+    fs::write(&path, source).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_Iridation"))
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"30\n12\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(42));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "numbers? ");
+}